@@ -0,0 +1,302 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+use crate::{Base, BaseLike};
+
+// A compact DNA file: a little-endian header
+//   magic, version: u32, flags: u8, length: u64
+// then the bases packed four to a byte with the first base in the low
+// bits (as in PackedBases), then crc: u32 if the CRC flag is set.  The
+// checksum comes last so that it can be computed while writing.
+
+pub const PACKED_MAGIC: &[u8; 8] = b"ENDOPACK";
+const PACKED_VERSION: u32 = 1;
+const HAS_CRC: u8 = 1;
+const BUF_LEN: usize = 1 << 16;
+
+fn invalid<U>(msg: &str) -> io::Result<U> {
+  Err(io::Error::new(io::ErrorKind::InvalidData, msg.to_string()))
+}
+
+// CRC-32 of the bases, taking each one's u2 value as a byte.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc(u32);
+
+const CRC_TABLE: [u32; 256] = make_crc_table();
+// For taking four bytes at once ("slicing by 4"): CRC_TABLES[k] is the
+// effect of a byte followed by k zero bytes.
+const CRC_TABLES: [[u32; 256]; 4] = make_crc_tables();
+// A packed byte's four bases, each spread out to a byte.
+const SPREAD: [u32; 256] = make_spread();
+
+const fn make_crc_table() -> [u32; 256] {
+  let mut table = [0; 256];
+  let mut i = 0;
+  while i < 256 {
+    let mut c = i as u32;
+    let mut k = 0;
+    while k < 8 {
+      c = if (c & 1) != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+      k += 1;
+    }
+    table[i] = c;
+    i += 1;
+  }
+  table
+}
+
+const fn make_crc_tables() -> [[u32; 256]; 4] {
+  let mut tables = [CRC_TABLE; 4];
+  let mut k = 1;
+  while k < 4 {
+    let mut i = 0;
+    while i < 256 {
+      let c = tables[k - 1][i];
+      tables[k][i] = (c >> 8) ^ CRC_TABLE[(c & 0xff) as usize];
+      i += 1;
+    }
+    k += 1;
+  }
+  tables
+}
+
+const fn make_spread() -> [u32; 256] {
+  let mut table = [0; 256];
+  let mut i = 0;
+  while i < 256 {
+    let b = i as u32;
+    table[i] = (b & 3) | (b >> 2 & 3) << 8 | (b >> 4 & 3) << 16 | (b >> 6 & 3) << 24;
+    i += 1;
+  }
+  table
+}
+
+impl Default for Crc {
+  fn default() -> Self { Crc::new() }
+}
+
+impl Crc {
+  pub fn new() -> Self {
+    Crc(0xffffffff)
+  }
+
+  #[inline]
+  pub fn update(&mut self, base: Base) {
+    self.0 = (self.0 >> 8) ^ CRC_TABLE[((self.0 ^ base as u32) & 0xff) as usize];
+  }
+
+  // Takes the bases in `range` of packed `bytes`, four at a time where
+  // they fill a byte.
+  pub fn update_packed(&mut self, bytes: &[u8], range: Range<usize>) {
+    let (mut i, end) = (range.start, range.end);
+    while i < end && i & 3 != 0 {
+      self.update(Base::from_u8(bytes[i >> 2] >> ((i & 3) * 2)));
+      i += 1;
+    }
+    if i < end {
+      for b in &bytes[i >> 2 .. end >> 2] {
+        let c = self.0 ^ SPREAD[*b as usize];
+        self.0 = CRC_TABLES[3][(c & 0xff) as usize] ^ CRC_TABLES[2][(c >> 8 & 0xff) as usize]
+            ^ CRC_TABLES[1][(c >> 16 & 0xff) as usize] ^ CRC_TABLES[0][(c >> 24) as usize];
+      }
+      i = cmp::max(i, end & !3);
+    }
+    while i < end {
+      self.update(Base::from_u8(bytes[i >> 2] >> ((i & 3) * 2)));
+      i += 1;
+    }
+  }
+
+  pub fn sum(&self) -> u32 {
+    self.0 ^ 0xffffffff
+  }
+}
+
+pub struct PackedWriter<W: Write> {
+  out: W,
+  len: u64,
+  count: u64,
+  byte: u8,
+  buf: Vec<u8>,
+  crc: Option<Crc>,
+}
+
+impl<W: Write> PackedWriter<W> {
+  // Writes the header for `len` bases, which must then all be pushed
+  // before finishing.
+  pub fn new(mut out: W, len: u64, crc: bool) -> io::Result<Self> {
+    out.write_all(PACKED_MAGIC)?;
+    out.write_all(&PACKED_VERSION.to_le_bytes())?;
+    out.write_all(&[if crc { HAS_CRC } else { 0 }])?;
+    out.write_all(&len.to_le_bytes())?;
+    Ok(PackedWriter{out, len, count: 0, byte: 0, buf: Vec::with_capacity(BUF_LEN),
+                    crc: crc.then(Crc::new)})
+  }
+
+  pub fn push(&mut self, base: Base) -> io::Result<()> {
+    if self.count == self.len { return invalid("More bases than the header says"); }
+    self.byte |= (base as u8) << ((self.count & 3) * 2);
+    self.count += 1;
+    if let Some(crc) = self.crc.as_mut() { crc.update(base); }
+    if self.count & 3 == 0 {
+      self.buf.push(self.byte);
+      self.byte = 0;
+      if self.buf.len() == BUF_LEN {
+        self.out.write_all(&self.buf)?;
+        self.buf.clear();
+      }
+    }
+    Ok(())
+  }
+
+  pub fn finish(mut self) -> io::Result<W> {
+    if self.count != self.len { return invalid("Fewer bases than the header says"); }
+    if self.count & 3 != 0 { self.buf.push(self.byte); }
+    self.out.write_all(&self.buf)?;
+    if let Some(crc) = self.crc {
+      self.out.write_all(&crc.sum().to_le_bytes())?;
+    }
+    Ok(self.out)
+  }
+}
+
+pub struct PackedReader<R: Read> {
+  input: R,
+  len: u64,
+  crc: bool,
+}
+
+impl<R: Read> PackedReader<R> {
+  // Reads the header, checking the magic.
+  pub fn new(mut input: R) -> io::Result<Self> {
+    let mut header = [0u8; 21];
+    input.read_exact(&mut header)?;
+    if &header[.. 8] != PACKED_MAGIC { return invalid("Not a packed DNA file"); }
+    if u32::from_le_bytes(header[8 .. 12].try_into().unwrap()) != PACKED_VERSION {
+      return invalid("Unsupported packed DNA version");
+    }
+    let flags = header[12];
+    if flags & !HAS_CRC != 0 { return invalid("Unknown packed DNA flags"); }
+    let len = u64::from_le_bytes(header[13 ..].try_into().unwrap());
+    Ok(PackedReader{input, len, crc: flags & HAS_CRC != 0})
+  }
+
+  // Number of bases, from the header.
+  pub fn len(&self) -> u64 {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  pub fn has_crc(&self) -> bool {
+    self.crc
+  }
+
+  // Reads all the bases, handing each one to `out`, and checks the CRC
+  // if there is one.
+  pub fn read_into<T: BaseLike, E: Extend<T>>(mut self, out: &mut E) -> io::Result<()> {
+    let mut crc = Crc::new();
+    let mut buf = vec![0u8; BUF_LEN];
+    let mut pos = 0;
+    while pos < self.len {
+      let count = cmp::min(BUF_LEN as u64, (self.len - pos + 3) >> 2) as usize;
+      self.input.read_exact(&mut buf[.. count])?;
+      crc.update_packed(&buf, 0 .. cmp::min(count as u64 * 4, self.len - pos) as usize);
+      for byte in &buf[.. count] {
+        for k in 0 .. cmp::min(4, self.len - pos) {
+          let base = Base::from_u8(byte >> (k * 2));
+          out.extend(Some(T::from_base_pos(base, pos as usize)));
+          pos += 1;
+        }
+      }
+    }
+    if self.crc {
+      let mut sum = [0u8; 4];
+      self.input.read_exact(&mut sum)?;
+      if u32::from_le_bytes(sum) != crc.sum() { return invalid("Packed DNA fails its CRC"); }
+    }
+    Ok(())
+  }
+}
+
+
+#[cfg(test)]
+mod file_tests {
+  use super::*;
+  use crate::PackedBases;
+  use quickcheck_macros::quickcheck;
+  use rope::Leaf;
+
+  fn write(bases: &[Base], crc: bool) -> Vec<u8> {
+    let mut w = PackedWriter::new(vec![], bases.len() as u64, crc).unwrap();
+    for b in bases {
+      w.push(*b).unwrap();
+    }
+    w.finish().unwrap()
+  }
+
+  fn read(bytes: &[u8]) -> io::Result<Vec<Base>> {
+    let mut out = vec![];
+    PackedReader::new(bytes)?.read_into::<Base, _>(&mut out)?;
+    Ok(out)
+  }
+
+  #[quickcheck]
+  fn roundtrip(xs: Vec<u8>, crc: bool) {
+    let bases = xs.iter().map(|x| Base::from_u8(*x)).collect::<Vec<_>>();
+    let bytes = write(&bases, crc);
+    assert_eq!(bytes.len(), 21 + bases.len().div_ceil(4) + if crc { 4 } else { 0 });
+    assert_eq!(read(&bytes).unwrap(), bases);
+  }
+
+  #[test]
+  fn layout() {
+    let bases = Base::collect_from::<Vec<_>>("CFPIP");
+    let bytes = write(&bases, false);
+    assert_eq!(&bytes[.. 8], PACKED_MAGIC);
+    assert_eq!(&bytes[13 .. 21], &5u64.to_le_bytes());
+    assert_eq!(&bytes[21 ..], &[0b00_11_10_01, 0b11]);
+  }
+
+  #[test]
+  fn crc() {
+    // The CRC-32 of the bytes 0, 1, 2, 3.
+    let mut crc = Crc::new();
+    for b in Base::collect_from::<Vec<_>>("ICFP") {
+      crc.update(b);
+    }
+    assert_eq!(crc.sum(), 0x8bb98613);
+  }
+
+  #[quickcheck]
+  fn crc_packed(xs: Vec<u8>, start: usize, end: usize) {
+    let bases = xs.iter().map(|x| Base::from_u8(*x)).collect::<Vec<_>>();
+    let (mut start, mut end) = (start % (bases.len() + 1), end % (bases.len() + 1));
+    if start > end { std::mem::swap(&mut start, &mut end); }
+    let mut crc = Crc::new();
+    for b in &bases[start .. end] {
+      crc.update(*b);
+    }
+    let mut packed = Crc::new();
+    packed.update_packed(PackedBases::from_slice(&bases).bytes(), start .. end);
+    assert_eq!(packed.sum(), crc.sum());
+  }
+
+  #[test]
+  fn corrupt() {
+    let bases = Base::collect_from::<Vec<_>>(&"ICFP".repeat(100));
+    let mut bytes = write(&bases, true);
+    bytes[30] ^= 1;
+    assert!(read(&bytes).is_err());
+    // Without a CRC, only truncation is detected.
+    let bytes = write(&bases, false);
+    assert!(read(&bytes[.. bytes.len() - 1]).is_err());
+    assert!(read(b"ENDOCKPT").is_err());
+    let mut w = PackedWriter::new(vec![], 1, false).unwrap();
+    w.push(Base::I).unwrap();
+    assert!(w.push(Base::I).is_err());
+  }
+}
@@ -3,7 +3,17 @@ use std::fmt;
 use std::mem;
 use std::marker::PhantomData;
 
-pub trait BaseLike: Copy + PartialEq + fmt::Display + fmt::Debug {
+use rope::Element;
+
+mod file;
+mod packed;
+mod runs;
+pub use file::{Crc, PackedReader, PackedWriter, PACKED_MAGIC};
+pub use packed::{pack_word, packed_word, PackedBases};
+pub use runs::{Runs, Tagged};
+
+// Ropes of bases are the main use, so every BaseLike is a rope Element.
+pub trait BaseLike: Element + PartialEq + fmt::Display + fmt::Debug {
   const HAS_SOURCE: bool = false;
 
   fn to_base(self) -> Base;
@@ -15,10 +25,23 @@ pub trait BaseLike: Copy + PartialEq + fmt::Display + fmt::Debug {
   }
 
   fn addr(self) -> Option<u32> { None }
-  fn level(self) -> Option<i8> { None }
-  fn from_parts(base: Base, _addr: u32, _level: i8) -> Self {
+  fn level(self) -> Option<i32> { None }
+  fn from_parts(base: Base, _addr: u32, _level: i32) -> Self {
+    Self::from_base(base)
+  }
+
+  // Bases made up by the engine (such as the lengths written by |n|
+  // template items) rather than copied from the DNA.  The address is
+  // whatever produced them, and they have no level of their own.
+  fn synthesized(base: Base, _addr: u32) -> Self {
     Self::from_base(base)
   }
+  fn is_synthesized(self) -> bool { false }
+
+  // The iteration whose splice created the base, for bases that keep
+  // track (0 for the original DNA).
+  fn stamp(self) -> Option<u32> { None }
+  fn stamped(self, _iter: u32) -> Self { self }
 
   // TODO - how to make this private?
   fn push(i: u8, out: &mut Vec<Self>) {
@@ -33,9 +56,15 @@ pub trait BaseLike: Copy + PartialEq + fmt::Display + fmt::Debug {
     // NOTE: for IC -> P, unprotect the I, not the C.
     BaseLike::from_base(Base::from_u8(self.to_base() as u8 + 3))
   }
+  // Panics on anything but bases and whitespace; use try_collect_from
+  // for input that isn't known to be good.
   fn collect_from<C: FromIterator<Self>>(s: &str) -> C {
-    BaseLikeIterator{s: s.as_bytes(), i: 0, pos: 0, phantom: PhantomData}
-        .collect::<C>()
+    BaseLikeIterator::new(s.as_bytes()).collect::<C>()
+  }
+  fn try_collect_from<C, S>(s: &S) -> Result<C, ParseError>
+  where C: FromIterator<Self>, S: AsRef<[u8]> + ?Sized {
+    let mut iter = BaseLikeIterator::new(s.as_ref());
+    std::iter::from_fn(|| iter.next_result()).collect()
   }
 }
 
@@ -73,6 +102,10 @@ impl Base {
   pub fn char(&self) -> char { BASE_CHARS[*self as usize] }
 }
 
+impl Element for Base {
+  type Leaf = PackedBases;
+}
+
 impl BaseLike for Base {
   #[inline]
   fn to_base(self) -> Base { self }
@@ -83,6 +116,10 @@ impl BaseLike for Base {
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct SourceBase(u32);
 
+impl Element for SourceBase {
+  type Leaf = Runs<SourceBase>;
+}
+
 impl BaseLike for SourceBase {
   const HAS_SOURCE: bool = true;
 
@@ -97,17 +134,12 @@ impl BaseLike for SourceBase {
     SourceBase(base as u32 | ((pos & 0xffffff) as u32) << 2)
   }
   fn protect(self, level: u8, out: &mut Vec<Self>) {
-    let mut esc = self.0 as i32 >> 26;
-    if esc > -31 {
-      esc = cmp::min(31, esc + level as i32);
-    }
-    let mask = (esc << 26) as u32 | (self.0 & ADDR_MASK);
-    SourceBase::push(self.to_base() as u8 + level, mask, out);
+    SourceBase::push(self.to_base() as u8 + level, self.protected_mask(level), out);
   }
   fn unprotect(self) -> Self {
     let mut esc = self.0 as i32 >> 26;
     if esc < 31 && esc > -31 {
-      esc = cmp::max(-31, esc - 1 as i32);
+      esc = cmp::max(-31, esc - 1);
     }
     let mask = (esc << 26) as u32 | (self.0 & ADDR_MASK);
     let base = ((self.0 & 3) + 3) & 3;
@@ -118,20 +150,38 @@ impl BaseLike for SourceBase {
     Some((self.0 & ADDR_MASK) >> 2)
   }
   #[inline]
-  fn level(self) -> Option<i8> {
-    Some(((self.0 as i32) >> 26) as i8)
+  fn level(self) -> Option<i32> {
+    Some((self.0 as i32) >> 26)
   }
+  // Levels saturate at 31 and -31, and -32 marks synthesized bases.
   #[inline]
-  fn from_parts(base: Base, addr: u32, level: i8) -> Self {
+  fn from_parts(base: Base, addr: u32, level: i32) -> Self {
     SourceBase(base as u8 as u32
                | (addr << 2) & ADDR_MASK
-               | ((level as i32) << 26) as u32)
+               | (level.clamp(-32, 31) << 26) as u32)
+  }
+  fn synthesized(base: Base, addr: u32) -> Self {
+    SourceBase::from_parts(base, addr, -32)
+  }
+  #[inline]
+  fn is_synthesized(self) -> bool {
+    self.level() == Some(-32)
   }
 }
 
 const ADDR_MASK: u32 = 0xffffff << 2;
 
 impl SourceBase {
+  // Everything but the base, for copies escaped by `level`.
+  #[inline]
+  fn protected_mask(self, level: u8) -> u32 {
+    let mut esc = self.0 as i32 >> 26;
+    if esc > -31 {
+      esc = cmp::min(31, esc + level as i32);
+    }
+    (esc << 26) as u32 | (self.0 & ADDR_MASK)
+  }
+
   fn push(i: u8, mask: u32, out: &mut Vec<Self>) {
     if i < 4 {
       out.push(SourceBase(mask | (i as u32)));
@@ -142,38 +192,275 @@ impl SourceBase {
   }
 }
 
+// Like SourceBase, but wide enough to keep exact provenance: a 32-bit
+// address, a 16-bit level and an explicit synthesized flag.
+//   bits 0-1: base, bit 2: synthesized, bits 16-31: level, bits 32-63: address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WideBase(u64);
+
+const WIDE_SYNTHESIZED: u64 = 4;
+const WIDE_LEVEL_MASK: u64 = 0xffff << 16;
+
+impl Element for WideBase {
+  type Leaf = Runs<WideBase>;
+}
+
+impl BaseLike for WideBase {
+  const HAS_SOURCE: bool = true;
+
+  #[inline]
+  fn to_base(self) -> Base { Base::from_u8(self.0 as u8) }
+  #[inline]
+  fn to_u2(self) -> u8 { (self.0 & 3) as u8 }
+  #[inline]
+  fn from_base(base: Base) -> Self { WideBase(base as u64) }
+  #[inline]
+  fn from_base_pos(base: Base, pos: usize) -> Self {
+    WideBase(base as u64 | (pos as u32 as u64) << 32)
+  }
+  fn protect(self, level: u8, out: &mut Vec<Self>) {
+    let tag = if self.is_synthesized() {
+      self.0 & !3
+    } else {
+      self.with_level(self.level().unwrap() + level as i32).0 & !3
+    };
+    WideBase::push(self.to_u2() + level, tag, out);
+  }
+  fn unprotect(self) -> Self {
+    let base = WideBase(self.0 & !3 | ((self.0 & 3) + 3) & 3);
+    if self.is_synthesized() { return base; }
+    base.with_level(self.level().unwrap() - 1)
+  }
+  #[inline]
+  fn addr(self) -> Option<u32> {
+    Some((self.0 >> 32) as u32)
+  }
+  #[inline]
+  fn level(self) -> Option<i32> {
+    Some((self.0 >> 16) as u16 as i16 as i32)
+  }
+  #[inline]
+  fn from_parts(base: Base, addr: u32, level: i32) -> Self {
+    WideBase(base as u64 | (addr as u64) << 32).with_level(level)
+  }
+  fn synthesized(base: Base, addr: u32) -> Self {
+    WideBase(base as u64 | WIDE_SYNTHESIZED | (addr as u64) << 32)
+  }
+  #[inline]
+  fn is_synthesized(self) -> bool {
+    self.0 & WIDE_SYNTHESIZED != 0
+  }
+}
+
+impl WideBase {
+  // Levels saturate at the i16 range, which no real gene gets near.
+  #[inline]
+  fn with_level(self, level: i32) -> Self {
+    let level = level.clamp(i16::MIN as i32, i16::MAX as i32) as i16 as u16 as u64;
+    WideBase(self.0 & !WIDE_LEVEL_MASK | level << 16)
+  }
+
+  fn push(i: u8, tag: u64, out: &mut Vec<Self>) {
+    if i < 4 {
+      out.push(WideBase(tag | i as u64));
+    } else {
+      WideBase::push(i - 4, tag, out);
+      WideBase::push(i - 3, tag, out);
+    }
+  }
+}
+
+impl fmt::Display for WideBase {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.to_base().char())
+  }
+}
+
+// A SourceBase that also records the iteration whose splice created it.
+//   bits 0-31: as SourceBase, bits 32-63: stamp
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StampedBase(u64);
+
+const SOURCE_MASK: u64 = 0xffffffff;
+
+impl Element for StampedBase {
+  type Leaf = Runs<StampedBase>;
+}
+
+impl BaseLike for StampedBase {
+  const HAS_SOURCE: bool = true;
+
+  #[inline]
+  fn to_base(self) -> Base { Base::from_u8(self.0 as u8) }
+  #[inline]
+  fn to_u2(self) -> u8 { (self.0 & 3) as u8 }
+  #[inline]
+  fn from_base(base: Base) -> Self { StampedBase(base as u64) }
+  #[inline]
+  fn from_base_pos(base: Base, pos: usize) -> Self {
+    StampedBase::from_source(SourceBase::from_base_pos(base, pos))
+  }
+  fn protect(self, level: u8, out: &mut Vec<Self>) {
+    let tag = self.0 & !SOURCE_MASK | self.source().protected_mask(level) as u64;
+    StampedBase::push(self.to_u2() + level, tag, out);
+  }
+  fn unprotect(self) -> Self {
+    StampedBase(self.0 & !SOURCE_MASK | self.source().unprotect().0 as u64)
+  }
+  #[inline]
+  fn addr(self) -> Option<u32> { self.source().addr() }
+  #[inline]
+  fn level(self) -> Option<i32> { self.source().level() }
+  #[inline]
+  fn from_parts(base: Base, addr: u32, level: i32) -> Self {
+    StampedBase::from_source(SourceBase::from_parts(base, addr, level))
+  }
+  fn synthesized(base: Base, addr: u32) -> Self {
+    StampedBase::from_source(SourceBase::synthesized(base, addr))
+  }
+  #[inline]
+  fn is_synthesized(self) -> bool { self.source().is_synthesized() }
+  #[inline]
+  fn stamp(self) -> Option<u32> { Some((self.0 >> 32) as u32) }
+  #[inline]
+  fn stamped(self, iter: u32) -> Self {
+    StampedBase(self.0 & SOURCE_MASK | (iter as u64) << 32)
+  }
+}
+
+impl StampedBase {
+  #[inline]
+  fn source(self) -> SourceBase { SourceBase(self.0 as u32) }
+  #[inline]
+  fn from_source(source: SourceBase) -> Self { StampedBase(source.0 as u64) }
+
+  fn push(i: u8, tag: u64, out: &mut Vec<Self>) {
+    if i < 4 {
+      out.push(StampedBase(tag | i as u64));
+    } else {
+      StampedBase::push(i - 4, tag, out);
+      StampedBase::push(i - 3, tag, out);
+    }
+  }
+}
+
+impl fmt::Display for StampedBase {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.to_base().char())
+  }
+}
+
+// Where parsing DNA text failed.  Lines and columns count from 1, and
+// columns count bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseError {
+  pub byte: u8,
+  pub offset: usize,
+  pub line: usize,
+  pub column: usize,
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.byte.is_ascii_graphic() {
+      write!(f, "Bad character '{}'", self.byte as char)?;
+    } else {
+      write!(f, "Bad byte 0x{:02x}", self.byte)?;
+    }
+    write!(f, " at line {}, column {} (offset {})", self.line, self.column, self.offset)
+  }
+}
+
+impl std::error::Error for ParseError {}
+
+// Parses DNA text a byte at a time, so that it can be fed from a stream.
+// Whitespace (including tabs and CRLF line endings) is skipped, and
+// doesn't count towards the base positions.
+#[derive(Clone, Debug)]
+pub struct BaseParser {
+  offset: usize,
+  pos: usize,
+  line: usize,
+  line_start: usize,
+}
+
+impl Default for BaseParser {
+  fn default() -> Self { BaseParser::new() }
+}
+
+impl BaseParser {
+  pub fn new() -> Self {
+    BaseParser{offset: 0, pos: 0, line: 1, line_start: 0}
+  }
+
+  // Number of bases parsed so far.
+  pub fn pos(&self) -> usize {
+    self.pos
+  }
+
+  #[inline]
+  pub fn parse<T: BaseLike>(&mut self, b: u8) -> Result<Option<T>, ParseError> {
+    let offset = self.offset;
+    self.offset += 1;
+    let base = match b {
+      b'I' => Base::I,
+      b'C' => Base::C,
+      b'F' => Base::F,
+      b'P' => Base::P,
+      b' '|b'\t'|b'\r' => return Ok(None),
+      b'\n' => {
+        self.line += 1;
+        self.line_start = self.offset;
+        return Ok(None);
+      }
+      _ => {
+        return Err(ParseError{byte: b, offset, line: self.line,
+                              column: offset - self.line_start + 1});
+      }
+    };
+    let pos = self.pos;
+    self.pos += 1;
+    Ok(Some(T::from_base_pos(base, pos)))
+  }
+
+  // Parses a whole buffer, handing each base to `out`.
+  pub fn parse_into<T: BaseLike, E: Extend<T>>(&mut self, bytes: &[u8], out: &mut E)
+                                               -> Result<(), ParseError> {
+    for b in bytes {
+      if let Some(base) = self.parse(*b)? {
+        out.extend(Some(base));
+      }
+    }
+    Ok(())
+  }
+}
+
 pub struct BaseLikeIterator<'a, T: BaseLike> {
   s: &'a [u8],
-  i: usize,
-  pos: usize,
+  parser: BaseParser,
   phantom: PhantomData<T>,
 }
 
+impl<'a, T: BaseLike> BaseLikeIterator<'a, T> {
+  fn new(s: &'a [u8]) -> Self {
+    BaseLikeIterator{s, parser: BaseParser::new(), phantom: PhantomData}
+  }
+
+  fn next_result(&mut self) -> Option<Result<T, ParseError>> {
+    while let Some((b, rest)) = self.s.split_first() {
+      self.s = rest;
+      if let Some(result) = self.parser.parse(*b).transpose() {
+        return Some(result);
+      }
+    }
+    None
+  }
+}
+
 impl<'a, T: BaseLike> Iterator for BaseLikeIterator<'a, T> {
   type Item = T;
   fn next(&mut self) -> Option<T> {
-    loop {
-      if self.i < self.s.len() {
-        let b = self.s[self.i];
-        let base = match b {
-          b'I' => Some(Base::I),
-          b'C' => Some(Base::C),
-          b'F' => Some(Base::F),
-          b'P' => Some(Base::P),
-          b' ' => None,
-          b'\n' => None,
-          _ => panic!("Bad character: {}", b),
-        };
-        self.i += 1;
-        if let Some(base) = base {
-          let pos = self.pos;
-          self.pos += 1;
-          return Some(T::from_base_pos(base, pos));
-        }
-      } else {
-        return None;
-      }
-    }
+    self.next_result().map(|r| r.unwrap_or_else(|e| panic!("{}", e)))
   }
 }
 
@@ -182,7 +469,7 @@ impl<'a, T: BaseLike> Iterator for BaseLikeIterator<'a, T> {
 pub struct Join<'a, T>(pub &'a [T], pub &'a str);
 impl<'a, T: fmt::Display> fmt::Display for Join<'a, T> {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    if self.0.len() == 0 { return Ok(()); }
+    if self.0.is_empty() { return Ok(()); }
     write!(f, "{}", self.0[0])?;
     for i in 1 .. self.0.len() {
       write!(f, "{}{}", self.1, self.0[i])?;
@@ -229,6 +516,7 @@ mod base_tests {
   }
 
   #[test]
+  #[allow(clippy::identity_op)]
   fn from_str_sourcebase() {
     let s = "ICFPIIC";
     let v: Vec<SourceBase> = SourceBase::collect_from(s);
@@ -240,6 +528,23 @@ mod base_tests {
     ]);
   }
 
+  #[test]
+  fn try_collect_whitespace() {
+    let v: Vec<SourceBase> = SourceBase::try_collect_from("IC\tF\r\nP I\n").unwrap();
+    assert_eq!(v, SourceBase::collect_from::<Vec<_>>("ICFPI"));
+    assert_eq!(v[4].addr(), Some(4));
+  }
+
+  #[test]
+  fn try_collect_error_position() {
+    let err = Base::try_collect_from::<Vec<_>, _>("ICFP\r\nIIX").unwrap_err();
+    assert_eq!(err, ParseError{byte: b'X', offset: 8, line: 2, column: 3});
+    assert_eq!(err.to_string(), "Bad character 'X' at line 2, column 3 (offset 8)");
+    let err = Base::try_collect_from::<Vec<_>, _>(b"\n\tI\xff").unwrap_err();
+    assert_eq!((err.line, err.column), (2, 3));
+    assert_eq!(err.to_string(), "Bad byte 0xff at line 2, column 3 (offset 3)");
+  }
+
   #[quickcheck]
   fn to_base_sourcebase(x: u32) {
     assert_eq!(SourceBase(x).to_base(), Base::from_u8(x as u8));
@@ -336,9 +641,9 @@ mod base_tests {
   fn protect_sourcebase_roundtrip(x: u32, mut i: u8) {
     i &= 63;
     let orig = SourceBase(x);
-    let orig_level: i8 = orig.level().unwrap();
+    let orig_level: i32 = orig.level().unwrap();
     let protected = protect(orig, i);
-    let escaped_level = cmp::min(orig_level + i as i8, 31);
+    let escaped_level = cmp::min(orig_level + i as i32, 31);
     let expected_level = match (orig_level, escaped_level) {
       (-32, _) => -32,
       (-31, _) => -31,
@@ -356,4 +661,57 @@ mod base_tests {
 
     assert_eq!(unprotected, vec![expected_base]);
   }
+
+  #[quickcheck]
+  fn widebase_from_parts(b: u8, addr: u32, level: i16) {
+    let base = WideBase::from_parts(Base::from_u8(b), addr, level as i32);
+    assert_eq!(base.to_base(), Base::from_u8(b));
+    assert_eq!(base.addr(), Some(addr));
+    assert_eq!(base.level(), Some(level as i32));
+    assert!(!base.is_synthesized());
+  }
+
+  #[quickcheck]
+  fn protect_widebase_roundtrip(b: u8, addr: u32, level: i8, mut i: u8) {
+    // Levels don't saturate, so escaping is exactly undone.
+    i &= 63;
+    let orig = WideBase::from_parts(Base::from_u8(b), addr, level as i32 * 4);
+    let mut unprotected = protect(orig, i);
+    assert!(unprotected.iter().all(|x| x.level() == Some(level as i32 * 4 + i as i32)));
+    for _ in 0..i {
+      unprotected = unprotect(&unprotected);
+    }
+    assert_eq!(unprotected, vec![orig]);
+  }
+
+  #[test]
+  fn protect_synthesized() {
+    let nat = WideBase::synthesized(Base::C, 77);
+    assert!(nat.is_synthesized());
+    assert_eq!(protect(nat, 1), vec![WideBase::synthesized(Base::F, 77)]);
+    assert_eq!(nat.unprotect(), WideBase::synthesized(Base::I, 77));
+    assert!(SourceBase::synthesized(Base::C, 77).is_synthesized());
+  }
+
+  #[quickcheck]
+  fn stamped_matches_source(b: u8, addr: u32, level: i8, stamp: u32, i: u8) {
+    // Apart from the stamp, which is carried along, StampedBase behaves
+    // exactly like SourceBase.
+    let i = i & 15;
+    let source = SourceBase::from_parts(Base::from_u8(b), addr, level as i32);
+    let stamped = StampedBase::from_parts(Base::from_u8(b), addr, level as i32)
+        .stamped(stamp);
+    assert_eq!(stamped.stamp(), Some(stamp));
+    let parts = |v: Vec<SourceBase>| {
+      v.iter().map(|x| (x.to_base(), x.addr(), x.level())).collect::<Vec<_>>()
+    };
+    let protected = protect(stamped, i);
+    assert!(protected.iter().all(|x| x.stamp() == Some(stamp)));
+    assert_eq!(protected.iter().map(|x| (x.to_base(), x.addr(), x.level()))
+                 .collect::<Vec<_>>(),
+               parts(protect(source, i)));
+    assert_eq!(stamped.unprotect().level(), source.unprotect().level());
+    assert_eq!(stamped.stamped(0), StampedBase::from_parts(Base::from_u8(b), addr,
+                                                           level as i32));
+  }
 }
@@ -0,0 +1,279 @@
+use std::cmp;
+use std::ops::Range;
+
+use rope::Leaf;
+
+use crate::{Base, BaseLike};
+
+// Rope leaf storage for plain bases, packed four to a byte with the
+// first base in the low bits.  Unused bits of the last byte are always
+// zero, so the derived equality compares contents.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PackedBases {
+  bytes: Vec<u8>,
+  len: usize,
+}
+
+impl PackedBases {
+  pub fn new() -> Self {
+    PackedBases::default()
+  }
+
+  pub fn with_capacity(capacity: usize) -> Self {
+    PackedBases{bytes: Vec::with_capacity((capacity + 3) >> 2), len: 0}
+  }
+
+  #[inline]
+  pub fn push(&mut self, base: Base) {
+    let shift = (self.len & 3) * 2;
+    if shift == 0 {
+      self.bytes.push(base as u8);
+    } else {
+      *self.bytes.last_mut().unwrap() |= (base as u8) << shift;
+    }
+    self.len += 1;
+  }
+
+  // The packed representation, four bases per byte.
+  pub fn bytes(&self) -> &[u8] {
+    &self.bytes
+  }
+
+  // Copies out a range.  Leaves are split often and can be long, so
+  // this shifts a whole word (32 bases) at a time.
+  fn extract(&self, range: Range<usize>) -> PackedBases {
+    let len = range.len();
+    let count = (len + 3) >> 2;
+    let src = &self.bytes[range.start >> 2 ..];
+    let shift = (range.start & 3) * 2;
+    let mut bytes = Vec::with_capacity(count);
+    if shift == 0 {
+      bytes.extend_from_slice(&src[.. count]);
+    } else {
+      let mut k = 0;
+      while k + 8 < src.len() && k + 8 <= count {
+        let word = u64::from_le_bytes(src[k .. k + 8].try_into().unwrap());
+        let word = word >> shift | (src[k + 8] as u64) << (64 - shift);
+        bytes.extend_from_slice(&word.to_le_bytes());
+        k += 8;
+      }
+      for k in k .. count {
+        let next = if k + 1 < src.len() { src[k + 1] << (8 - shift) } else { 0 };
+        bytes.push(src[k] >> shift | next);
+      }
+    }
+    let mut out = PackedBases{bytes, len};
+    out.clear_tail();
+    out
+  }
+
+  fn clear_tail(&mut self) {
+    let rem = self.len & 3;
+    if rem != 0 {
+      *self.bytes.last_mut().unwrap() &= (1 << (rem * 2)) - 1;
+    }
+  }
+}
+
+// Up to 32 bases from `index` of packed `bytes`, as a word with the
+// first base in the low bits.  Words compare bases 32 at a time, and the
+// highest bit set in the xor of two gives the last base that differs.
+#[inline]
+pub fn packed_word(bytes: &[u8], index: usize, len: usize) -> u64 {
+  debug_assert!(len <= 32 && index + len <= bytes.len() * 4);
+  let first = index >> 2;
+  let wide = match bytes.get(first .. first + 16) {
+    Some(wide) => u128::from_le_bytes(wide.try_into().unwrap()),
+    None => {
+      // 32 bases at an odd offset span nine bytes.
+      let n = cmp::min(bytes.len() - first, 9);
+      let mut buf = [0u8; 16];
+      buf[.. n].copy_from_slice(&bytes[first .. first + n]);
+      u128::from_le_bytes(buf)
+    }
+  };
+  let word = (wide >> ((index & 3) * 2)) as u64;
+  if len == 32 { word } else { word & ((1 << (len * 2)) - 1) }
+}
+
+// Packs up to 32 bases into a word, as packed_word would read them.
+#[inline]
+pub fn pack_word<T: BaseLike>(bases: &[T]) -> u64 {
+  debug_assert!(bases.len() <= 32);
+  bases.iter().rev().fold(0, |word, b| word << 2 | b.to_u2() as u64)
+}
+
+impl Leaf<Base> for PackedBases {
+  fn from_vec(vec: Vec<Base>) -> Self {
+    PackedBases::from_slice(&vec)
+  }
+
+  fn from_slice(slice: &[Base]) -> Self {
+    let mut bytes = Vec::with_capacity((slice.len() + 3) >> 2);
+    let quads = slice.chunks_exact(4);
+    let rest = quads.remainder();
+    for q in quads {
+      bytes.push(q[0] as u8 | (q[1] as u8) << 2 | (q[2] as u8) << 4 | (q[3] as u8) << 6);
+    }
+    if !rest.is_empty() {
+      bytes.push(rest.iter().rev().fold(0, |acc, b| acc << 2 | *b as u8));
+    }
+    PackedBases{bytes, len: slice.len()}
+  }
+
+  #[inline]
+  fn len(&self) -> usize {
+    self.len
+  }
+
+  #[inline]
+  fn at(&self, index: usize) -> Base {
+    debug_assert!(index < self.len);
+    Base::from_u8(self.bytes[index >> 2] >> ((index & 3) * 2))
+  }
+
+  fn split_off(&mut self, at: usize) -> Self {
+    assert!(at <= self.len, "split_off index {} out of bounds {}", at, self.len);
+    let rest = if at & 3 == 0 {
+      PackedBases{bytes: self.bytes.split_off(at >> 2), len: self.len - at}
+    } else {
+      self.extract(at .. self.len)
+    };
+    self.truncate(at);
+    rest
+  }
+
+  fn truncate(&mut self, len: usize) {
+    if len >= self.len { return; }
+    self.bytes.truncate((len + 3) >> 2);
+    self.len = len;
+    self.clear_tail();
+  }
+
+  fn append(&mut self, other: &Self) {
+    let shift = (self.len & 3) * 2;
+    if shift == 0 {
+      self.bytes.extend_from_slice(&other.bytes);
+    } else {
+      // Each of the other's bytes straddles two of ours.
+      self.bytes.reserve(other.bytes.len());
+      for b in &other.bytes {
+        *self.bytes.last_mut().unwrap() |= b << shift;
+        self.bytes.push(b >> (8 - shift));
+      }
+    }
+    self.len += other.len;
+    self.bytes.truncate((self.len + 3) >> 2);
+  }
+
+  fn heap_bytes(&self) -> usize {
+    self.bytes.capacity()
+  }
+
+  #[inline]
+  fn packed(&self) -> Option<&[u8]> {
+    Some(&self.bytes)
+  }
+
+  fn copy_range(&self, range: Range<usize>, out: &mut Vec<Base>) {
+    out.reserve(range.len());
+    let (mut i, end) = (range.start, range.end);
+    while i < end && i & 3 != 0 {
+      out.push(self.at(i));
+      i += 1;
+    }
+    for b in &self.bytes[i >> 2 .. end >> 2] {
+      out.extend_from_slice(&[Base::from_u8(*b), Base::from_u8(b >> 2),
+                              Base::from_u8(b >> 4), Base::from_u8(b >> 6)]);
+    }
+    for j in cmp::max(i, end & !3) .. end {
+      out.push(self.at(j));
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod packed_tests {
+  use super::*;
+  use quickcheck_macros::quickcheck;
+
+  fn bases(xs: &[u8]) -> Vec<Base> {
+    xs.iter().map(|x| Base::from_u8(*x)).collect()
+  }
+
+  fn unpack(p: &PackedBases) -> Vec<Base> {
+    let mut out = vec![];
+    p.copy_range(0 .. p.len(), &mut out);
+    out
+  }
+
+  #[test]
+  fn packs_low_bits_first() {
+    let p = PackedBases::from_slice(&[Base::C, Base::F, Base::P, Base::I, Base::P]);
+    assert_eq!(p.bytes(), &[0b00_11_10_01, 0b11]);
+    assert_eq!(p.at(2), Base::P);
+  }
+
+  #[quickcheck]
+  fn split_off_parity(xs: Vec<u8>, at: usize) {
+    let mut v = bases(&xs);
+    let at = if v.is_empty() { 0 } else { at % (v.len() + 1) };
+    let mut p = PackedBases::from_slice(&v);
+    let rest = p.split_off(at);
+    let vrest = v.split_off(at);
+    assert_eq!(unpack(&p), v);
+    assert_eq!(unpack(&rest), vrest);
+    // Equality only depends on the contents.
+    assert_eq!(p, PackedBases::from_slice(&v));
+    assert_eq!(rest, PackedBases::from_slice(&vrest));
+  }
+
+  #[test]
+  fn split_off_long() {
+    let v = bases(&(0 .. 1000).map(|i| (i * 7 / 3) as u8).collect::<Vec<_>>());
+    for at in 1 .. 9 {
+      let mut p = PackedBases::from_slice(&v);
+      assert_eq!(unpack(&p.split_off(at)), &v[at ..]);
+      assert_eq!(unpack(&p), &v[.. at]);
+    }
+  }
+
+  #[quickcheck]
+  fn copy_range_parity(xs: Vec<u8>, start: usize, end: usize) {
+    let v = bases(&xs);
+    let (mut start, mut end) = (start % (v.len() + 1), end % (v.len() + 1));
+    if start > end { std::mem::swap(&mut start, &mut end); }
+    let mut out = vec![];
+    PackedBases::from_slice(&v).copy_range(start .. end, &mut out);
+    assert_eq!(out, &v[start .. end]);
+  }
+
+  #[quickcheck]
+  fn truncate_parity(xs: Vec<u8>, len: usize) {
+    let mut v = bases(&xs);
+    let len = len % (v.len() + 1);
+    let mut p = PackedBases::from_slice(&v);
+    p.truncate(len);
+    v.truncate(len);
+    assert_eq!(p, PackedBases::from_slice(&v));
+  }
+
+  #[quickcheck]
+  fn append_parity(xs: Vec<u8>, ys: Vec<u8>) {
+    let (mut v, w) = (bases(&xs), bases(&ys));
+    let mut p = PackedBases::from_slice(&v);
+    p.append(&PackedBases::from_slice(&w));
+    v.extend(w);
+    assert_eq!(p, PackedBases::from_slice(&v));
+  }
+
+  #[quickcheck]
+  fn packed_word_parity(xs: Vec<u8>, index: usize, len: usize) {
+    let v = bases(&xs);
+    let index = index % (v.len() + 1);
+    let len = len % (v.len() - index + 1).min(33);
+    let p = PackedBases::from_slice(&v);
+    assert_eq!(packed_word(p.bytes(), index, len), pack_word(&v[index .. index + len]));
+  }
+}
@@ -0,0 +1,438 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Range;
+
+use rope::Leaf;
+
+use crate::{Base, BaseLike, PackedBases, SourceBase, StampedBase, WideBase, ADDR_MASK,
+            SOURCE_MASK};
+
+// Provenance that can be stored as runs: everything but the base itself
+// is a tag, and the tag of the next address is a fixed step away.  Tags
+// are as wide as the base, so runs of narrow bases stay small.
+pub trait Tagged: BaseLike {
+  type Tag: TagBits;
+  fn tag(self) -> Self::Tag;
+  fn from_tag(tag: Self::Tag, base: Base) -> Self;
+  // None if the address can't be incremented without overflowing.
+  fn next_tag(tag: Self::Tag) -> Option<Self::Tag>;
+}
+
+pub trait TagBits: Copy + Default + Eq + fmt::Debug {
+  fn wrapping_sub(self, other: Self) -> Self;
+  // The tag `n` steps of `step` on from this one.
+  fn advance(self, step: Self, n: u32) -> Self;
+}
+
+macro_rules! tag_bits {
+  ($t:ty) => {
+    impl TagBits for $t {
+      #[inline]
+      fn wrapping_sub(self, other: Self) -> Self { <$t>::wrapping_sub(self, other) }
+      #[inline]
+      fn advance(self, step: Self, n: u32) -> Self {
+        self.wrapping_add(step.wrapping_mul(n as $t))
+      }
+    }
+  };
+}
+
+tag_bits!(u32);
+tag_bits!(u64);
+
+impl Tagged for SourceBase {
+  type Tag = u32;
+  #[inline]
+  fn tag(self) -> u32 { self.0 & !3 }
+  #[inline]
+  fn from_tag(tag: u32, base: Base) -> Self { SourceBase(tag | base as u32) }
+  #[inline]
+  fn next_tag(tag: u32) -> Option<u32> {
+    // Don't let the address carry into the level.
+    if tag & ADDR_MASK != ADDR_MASK { Some(tag + 4) } else { None }
+  }
+}
+
+impl Tagged for WideBase {
+  type Tag = u64;
+  #[inline]
+  fn tag(self) -> u64 { self.0 & !3 }
+  #[inline]
+  fn from_tag(tag: u64, base: Base) -> Self { WideBase(tag | base as u64) }
+  #[inline]
+  fn next_tag(tag: u64) -> Option<u64> { tag.checked_add(1 << 32) }
+}
+
+impl Tagged for StampedBase {
+  type Tag = u64;
+  #[inline]
+  fn tag(self) -> u64 { self.0 & !3 }
+  #[inline]
+  fn from_tag(tag: u64, base: Base) -> Self { StampedBase(tag | base as u64) }
+  #[inline]
+  fn next_tag(tag: u64) -> Option<u64> {
+    // The stamp stays the same; only the source part moves on.
+    let source = SourceBase::next_tag((tag & SOURCE_MASK) as u32)?;
+    Some(source as u64 | tag & !SOURCE_MASK)
+  }
+}
+
+// Rope leaf storage for bases with provenance.  Neighbouring bases
+// nearly always come from consecutive addresses at the same level (or,
+// for escaped and synthesized bases, repeat a single address), so rather
+// than spending a word on each base this packs the bases and keeps one
+// Run per stretch.  A SourceBase Run is 12 bytes (24 with 64-bit tags),
+// so only a leaf that averages 3 or fewer bases a run takes more than
+// the 4 bytes per base of a Vec<SourceBase>.  Finding a base's run is a binary
+// search, so sequential readers should use at_hint.
+#[derive(Clone, Debug)]
+pub struct Runs<T: Tagged> {
+  bases: PackedBases,
+  runs: Vec<Run<T::Tag>>,
+  phantom: PhantomData<T>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Run<G> {
+  // Index of the run's first base within the leaf, which is far shorter
+  // than 2^32 bases.
+  start: u32,
+  // Tag of the first base.
+  tag: G,
+  // Added to the tag for each following base: either the step to the
+  // next address, or 0.
+  step: G,
+}
+
+impl<G: TagBits> Run<G> {
+  #[inline]
+  fn tag_at(&self, index: usize) -> G {
+    self.tag.advance(self.step, index as u32 - self.start)
+  }
+}
+
+#[inline]
+fn start(index: usize) -> u32 {
+  u32::try_from(index).expect("Leaf too long for runs")
+}
+
+impl<T: Tagged> Default for Runs<T> {
+  fn default() -> Self {
+    Runs{bases: PackedBases::new(), runs: vec![], phantom: PhantomData}
+  }
+}
+
+impl<T: Tagged> Runs<T> {
+  pub fn new() -> Self {
+    Runs::default()
+  }
+
+  pub fn push(&mut self, base: T) {
+    let tag = base.tag();
+    let index = self.bases.len();
+    let extends = match self.runs.last_mut() {
+      None => false,
+      Some(run) => {
+        let prev = run.tag_at(index - 1);
+        let next = T::next_tag(prev);
+        if index - run.start as usize == 1 {
+          // The second base decides the step.
+          if tag == prev || Some(tag) == next {
+            run.step = tag.wrapping_sub(prev);
+            true
+          } else {
+            false
+          }
+        } else if run.step == T::Tag::default() {
+          tag == prev
+        } else {
+          Some(tag) == next
+        }
+      }
+    };
+    if !extends {
+      self.runs.push(Run{start: start(index), tag, step: T::Tag::default()});
+    }
+    self.bases.push(base.to_base());
+  }
+
+  // How many runs the leaf holds, i.e. how well it compressed.
+  pub fn runs(&self) -> usize {
+    self.runs.len()
+  }
+
+  #[inline]
+  fn run_index(&self, index: usize) -> usize {
+    self.runs.partition_point(|r| r.start as usize <= index) - 1
+  }
+
+  #[inline]
+  fn in_run(&self, r: usize, index: usize) -> bool {
+    r < self.runs.len() && self.runs[r].start as usize <= index
+        && self.runs.get(r + 1).is_none_or(|next| index < next.start as usize)
+  }
+}
+
+// Runs may be split differently depending on how the leaf was built, so
+// compare the contents.
+impl<T: Tagged> PartialEq for Runs<T> {
+  fn eq(&self, other: &Runs<T>) -> bool {
+    let (mut a, mut b) = (0, 0);
+    self.len() == other.len() && self.bases == other.bases
+        && (0 .. self.len()).all(|i| self.at_hint(i, &mut a) == other.at_hint(i, &mut b))
+  }
+}
+
+impl<T: Tagged> Eq for Runs<T> {}
+
+impl<T: Tagged> Leaf<T> for Runs<T> {
+  fn from_vec(vec: Vec<T>) -> Self {
+    Runs::from_slice(&vec)
+  }
+
+  fn from_slice(slice: &[T]) -> Self {
+    let mut out = Runs{bases: PackedBases::with_capacity(slice.len()), runs: vec![],
+                       phantom: PhantomData};
+    for b in slice {
+      out.push(*b);
+    }
+    out.runs.shrink_to_fit();
+    out
+  }
+
+  #[inline]
+  fn len(&self) -> usize {
+    self.bases.len()
+  }
+
+  #[inline]
+  fn at(&self, index: usize) -> T {
+    let run = &self.runs[self.run_index(index)];
+    T::from_tag(run.tag_at(index), self.bases.at(index))
+  }
+
+  #[inline]
+  fn run_end(&self, index: usize) -> usize {
+    self.runs.get(self.run_index(index) + 1).map_or(self.len(), |next| next.start as usize)
+  }
+
+  #[inline]
+  fn at_hint(&self, index: usize, hint: &mut usize) -> T {
+    // Reading forwards, the base is nearly always in the same run as the
+    // last one or the next.
+    if !self.in_run(*hint, index) {
+      *hint = if self.in_run(*hint + 1, index) { *hint + 1 } else { self.run_index(index) };
+    }
+    let run = &self.runs[*hint];
+    T::from_tag(run.tag_at(index), self.bases.at(index))
+  }
+
+  fn split_off(&mut self, at: usize) -> Self {
+    assert!(at <= self.len(), "split_off index {} out of bounds {}", at, self.len());
+    if at == self.len() { return Runs::new(); }
+    let mut runs = self.runs.split_off(self.run_index(at));
+    let at32 = start(at);
+    if runs[0].start < at32 {
+      // The run straddles the split, so both halves get a copy.
+      self.runs.push(runs[0]);
+      runs[0].tag = runs[0].tag_at(at);
+      runs[0].start = at32;
+    }
+    for run in runs.iter_mut() {
+      run.start -= at32;
+    }
+    Runs{bases: self.bases.split_off(at), runs, phantom: PhantomData}
+  }
+
+  fn append(&mut self, other: &Self) {
+    // Both halves' starts must still fit once joined.
+    start(self.len() + other.len());
+    let len = start(self.len());
+    self.runs.extend(other.runs.iter().map(|run| Run{start: run.start + len, ..*run}));
+    self.bases.append(&other.bases);
+  }
+
+  fn truncate(&mut self, len: usize) {
+    if len >= self.len() { return; }
+    let r = self.run_index(len);
+    self.runs.truncate(if (self.runs[r].start as usize) < len { r + 1 } else { r });
+    self.bases.truncate(len);
+  }
+
+  fn heap_bytes(&self) -> usize {
+    self.bases.heap_bytes() + self.runs.capacity() * std::mem::size_of::<Run<T::Tag>>()
+  }
+
+  #[inline]
+  fn packed(&self) -> Option<&[u8]> {
+    Some(self.bases.bytes())
+  }
+
+  fn copy_range(&self, range: Range<usize>, out: &mut Vec<T>) {
+    out.reserve(range.len());
+    if range.is_empty() { return; }
+    let mut r = self.run_index(range.start);
+    let mut i = range.start;
+    while i < range.end {
+      let run = self.runs[r];
+      let end = self.runs.get(r + 1)
+          .map_or(range.end, |next| (next.start as usize).min(range.end));
+      for j in i .. end {
+        out.push(T::from_tag(run.tag_at(j), self.bases.at(j)));
+      }
+      i = end;
+      r += 1;
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod runs_tests {
+  use super::*;
+  use quickcheck_macros::quickcheck;
+
+  // Builds bases whose provenance mostly runs forward, with occasional
+  // jumps and repeats, like a spliced DNA leaf.
+  fn source_bases<T: BaseLike>(xs: &[u8]) -> Vec<T> {
+    let mut addr = 100;
+    let mut level = 0;
+    xs.iter().map(|x| {
+      match x >> 4 {
+        0 => { addr = (*x as u32) * 1000; }
+        1 => { level = (x & 7) as i32 - 3; }
+        2 => { return T::synthesized(Base::from_u8(*x), addr); }
+        3 => {}
+        _ => { addr += 1; }
+      }
+      T::from_parts(Base::from_u8(*x), addr, level)
+    }).collect()
+  }
+
+  fn unpack<T: Tagged>(r: &Runs<T>) -> Vec<T> {
+    let mut out = vec![];
+    r.copy_range(0 .. r.len(), &mut out);
+    out
+  }
+
+  #[test]
+  fn compresses_consecutive_addresses() {
+    let v = SourceBase::collect_from::<Vec<_>>("ICFPICFPICFP");
+    let r = Runs::from_slice(&v);
+    assert_eq!(r.runs(), 1);
+    assert_eq!(unpack(&r), v);
+    let nats = vec![SourceBase::from_parts(Base::C, 0, -32); 5];
+    assert_eq!(Runs::from_slice(&nats).runs(), 1);
+  }
+
+  #[test]
+  fn address_does_not_carry_into_level() {
+    let v = vec![SourceBase::from_parts(Base::I, 0xffffff, 0),
+                 SourceBase::from_parts(Base::I, 0, 1)];
+    let r = Runs::from_slice(&v);
+    assert_eq!(r.runs(), 2);
+    assert_eq!(unpack(&r), v);
+  }
+
+  #[test]
+  fn wide_addresses_and_levels() {
+    let v = vec![WideBase::from_parts(Base::I, 0xfffffffe, 100),
+                 WideBase::from_parts(Base::C, 0xffffffff, 100),
+                 WideBase::from_parts(Base::F, 0, 100),
+                 WideBase::synthesized(Base::P, 0),
+                 WideBase::synthesized(Base::P, 0)];
+    let r = Runs::from_slice(&v);
+    assert_eq!(r.runs(), 3);
+    assert_eq!(unpack(&r), v);
+  }
+
+  fn check_at<T: Tagged>(xs: &[u8]) {
+    let v = source_bases::<T>(xs);
+    let r = Runs::from_slice(&v);
+    for (i, b) in v.iter().enumerate() {
+      assert_eq!(r.at(i), *b);
+    }
+    // Forwards, backwards and striding, the hint is only a hint.
+    let mut hint = 0;
+    for (i, b) in v.iter().enumerate().chain(v.iter().enumerate().rev()).step_by(3) {
+      assert_eq!(r.at_hint(i, &mut hint), *b);
+    }
+  }
+
+  #[test]
+  fn fragmented_heap_bytes() {
+    assert_eq!(std::mem::size_of::<Run<u32>>(), 12);
+    assert_eq!(std::mem::size_of::<Run<u64>>(), 24);
+    // Every base from a different address, so every base is a run.
+    let v = (0 .. 4096).map(|i| SourceBase::from_parts(Base::from_u8(i as u8), i * 2, 0))
+        .collect::<Vec<_>>();
+    let r = Runs::from_slice(&v);
+    assert_eq!(r.runs(), v.len());
+    assert!(r.heap_bytes() <= v.len() / 4 + v.len() * 12, "{}", r.heap_bytes());
+    // Runs of 4 already beat a Vec<SourceBase>.
+    let v = (0 .. 4096).map(|i| SourceBase::from_parts(Base::I, i + i / 4, 0))
+        .collect::<Vec<_>>();
+    let r = Runs::from_slice(&v);
+    assert!(r.heap_bytes() <= v.len() * 4, "{}", r.heap_bytes());
+  }
+
+  #[quickcheck]
+  fn at_parity(xs: Vec<u8>) {
+    check_at::<SourceBase>(&xs);
+    check_at::<WideBase>(&xs);
+    check_at::<StampedBase>(&xs);
+  }
+
+  #[test]
+  fn stamps_break_runs() {
+    let mut v = StampedBase::collect_from::<Vec<_>>("ICFPICFP");
+    for b in &mut v[4 ..] {
+      *b = b.stamped(3);
+    }
+    let r = Runs::from_slice(&v);
+    assert_eq!(r.runs(), 2);
+    assert_eq!(unpack(&r), v);
+  }
+
+  #[quickcheck]
+  fn split_off_parity(xs: Vec<u8>, at: usize) {
+    let mut v = source_bases::<SourceBase>(&xs);
+    let at = at % (v.len() + 1);
+    let mut r = Runs::from_slice(&v);
+    let rest = r.split_off(at);
+    let vrest = v.split_off(at);
+    assert_eq!(unpack(&r), v);
+    assert_eq!(unpack(&rest), vrest);
+    // Both halves can still be extended.
+    let mut rest = rest;
+    rest.push(SourceBase::from_parts(Base::F, 7, 0));
+    assert_eq!(rest.at(vrest.len()), SourceBase::from_parts(Base::F, 7, 0));
+  }
+
+  #[quickcheck]
+  fn copy_range_parity(xs: Vec<u8>, start: usize, end: usize, len: usize) {
+    let mut v = source_bases::<WideBase>(&xs);
+    let (mut start, mut end) = (start % (v.len() + 1), end % (v.len() + 1));
+    if start > end { std::mem::swap(&mut start, &mut end); }
+    let mut r = Runs::from_slice(&v);
+    let mut out = vec![];
+    r.copy_range(start .. end, &mut out);
+    assert_eq!(out, &v[start .. end]);
+    let len = len % (v.len() + 1);
+    r.truncate(len);
+    v.truncate(len);
+    assert_eq!(unpack(&r), v);
+  }
+
+  #[quickcheck]
+  fn append_parity(xs: Vec<u8>, ys: Vec<u8>) {
+    let mut v = source_bases::<StampedBase>(&xs);
+    let w = source_bases::<StampedBase>(&ys);
+    let mut r = Runs::from_slice(&v);
+    r.append(&Runs::from_slice(&w));
+    v.extend(w);
+    assert_eq!(unpack(&r), v);
+    r.push(StampedBase::from_parts(Base::F, 7, 0));
+    assert_eq!(r.at(v.len()), StampedBase::from_parts(Base::F, 7, 0));
+  }
+}
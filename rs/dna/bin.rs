@@ -1,118 +1,516 @@
+use base::{Base, BaseLike, PackedWriter, SourceBase, StampedBase, WideBase};
+use dna::{lockstep, Checkpoint, Debugger, DnaState, Naive, State, Storage};
+use rope::{BTreeRope, Rope};
 
-use base::BaseLike;
-use dna::{DnaState, State};
-use rope::Rope;
-
-use flate2::read::GzDecoder;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::collections::{BTreeSet, HashMap};
-use std::env;
-use std::fs::File;
-use std::io::{BufReader, Read};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(about = "Runs Endo's DNA and renders the resulting RNA")]
+struct Cli {
+  #[command(subcommand)]
+  command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// Executes DNA (with an optional prefix), writing RNA and/or an image.
+  Run(RunArgs),
+  /// Renders an RNA file (as written by `run --rna`) to a PNG.
+  Render(RenderArgs),
+  /// Renders many prefix variants, forking each one from a shared
+  /// checkpoint rather than re-running the common iterations.
+  ///
+  /// Each variant is prepended after --at iterations, which only matches
+  /// running it as a prefix if those iterations don't touch the part of
+  /// the DNA that the variant rewrites.
+  Sweep(SweepArgs),
+  /// Converts DNA to the packed 2-bit format, which loads faster.
+  Pack(PackArgs),
+  /// Runs DNA on the engine and on a naive interpreter that follows the
+  /// specification literally, stopping where they first differ.
+  Diff(DiffArgs),
+  /// Steps through DNA interactively.
+  Debug(DebugArgs),
+}
+
+#[derive(Args)]
+struct RunArgs {
+  /// DNA to execute: plain text, packed, or either one gzipped.
+  #[arg(short, long, default_value = "endo.dna.gz")]
+  dna: PathBuf,
+  /// Prefix to prepend to the DNA.
+  #[arg(short, long, conflicts_with = "prefix_file")]
+  prefix: Option<String>,
+  /// File containing a prefix to prepend to the DNA.
+  #[arg(short = 'f', long)]
+  prefix_file: Option<PathBuf>,
+  /// Where to write the RNA, one per line ("-" for stdout).
+  #[arg(short, long)]
+  rna: Option<PathBuf>,
+  /// Annotate each RNA with its iteration and source address.
+  #[arg(short, long)]
+  verbose: bool,
+  /// Don't print progress to stderr.
+  #[arg(short, long)]
+  quiet: bool,
+  /// Where to write the rendered image.
+  #[arg(short = 'o', long)]
+  png: Option<PathBuf>,
+  /// Track the source address of every base and record which ones are
+  /// used, and how.  This uses a slower engine.
+  #[arg(long)]
+  provenance: bool,
+  /// Use 64-bit provenance, with exact escape levels and 32-bit
+  /// addresses.  Slower still, but needed for DNA over 16M bases.
+  #[arg(long, requires = "provenance")]
+  wide: bool,
+  /// Also stamp each base with the iteration that created it, shown in
+  /// verbose RNA output.
+  #[arg(long, requires = "provenance", conflicts_with = "wide")]
+  stamps: bool,
+  /// Where to write the coverage dump ("-" for stdout).
+  #[arg(long, requires = "provenance")]
+  coverage: Option<PathBuf>,
+  /// Stop after this many iterations.
+  #[arg(short = 'n', long)]
+  limit: Option<u32>,
+  /// Resume from a saved checkpoint instead of starting from the DNA
+  /// and prefix.
+  #[arg(long, conflicts_with_all = ["prefix", "prefix_file"])]
+  resume: Option<PathBuf>,
+  /// Save a checkpoint of the final state (e.g. after --limit).
+  #[arg(long)]
+  save: Option<PathBuf>,
+  /// Print the shape of the final DNA rope to stderr.
+  #[arg(long)]
+  stats: bool,
+  /// Write the final DNA rope as a Graphviz graph.
+  #[arg(long)]
+  dot: Option<PathBuf>,
+  /// What to keep the DNA in while running.
+  #[arg(long, value_enum, default_value_t = StorageKind::Rope)]
+  storage: StorageKind,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum StorageKind {
+  /// An AVL tree of leaves, which splices cheaply.
+  Rope,
+  /// A B-tree of leaves, which seeks faster.
+  Btree,
+  /// A flat vector; slow, but simple enough to check the others with.
+  Vec,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+  /// DNA to execute: plain text, packed, or either one gzipped.
+  #[arg(short, long, default_value = "endo.dna.gz")]
+  dna: PathBuf,
+  /// Prefix to prepend to the DNA.
+  #[arg(short, long, conflicts_with = "prefix_file")]
+  prefix: Option<String>,
+  /// File containing a prefix to prepend to the DNA.
+  #[arg(short = 'f', long)]
+  prefix_file: Option<PathBuf>,
+  /// Stop after this many iterations.  The naive interpreter copies
+  /// the whole DNA every iteration, so a full run takes a long time.
+  #[arg(short = 'n', long)]
+  limit: Option<u32>,
+  /// Compare the DNA every this many iterations.  The RNA is always
+  /// compared.
+  #[arg(long, default_value_t = 1)]
+  every: u32,
+  /// What the engine keeps the DNA in.
+  #[arg(long, value_enum, default_value_t = StorageKind::Rope)]
+  storage: StorageKind,
+  /// Don't print progress to stderr.
+  #[arg(short, long)]
+  quiet: bool,
+}
 
-type B = base::SourceBase;
+#[derive(Args)]
+struct DebugArgs {
+  /// DNA to execute: plain text, packed, or either one gzipped.
+  #[arg(short, long, default_value = "endo.dna.gz")]
+  dna: PathBuf,
+  /// Prefix to prepend to the DNA.
+  #[arg(short, long, conflicts_with = "prefix_file")]
+  prefix: Option<String>,
+  /// File containing a prefix to prepend to the DNA.
+  #[arg(short = 'f', long)]
+  prefix_file: Option<PathBuf>,
+  /// Track where each base came from, to show with the DNA.
+  #[arg(long)]
+  provenance: bool,
+}
+
+#[derive(Args)]
+struct PackArgs {
+  /// DNA to convert: plain text, gzipped or already packed.
+  input: PathBuf,
+  /// Where to write the packed DNA.
+  #[arg(short = 'o', long)]
+  output: PathBuf,
+  /// Leave out the checksum.
+  #[arg(long)]
+  no_crc: bool,
+}
+
+#[derive(Args)]
+struct RenderArgs {
+  /// RNA file to render (defaults to stdin).  Comments after '#' are ignored.
+  input: Option<PathBuf>,
+  /// Where to write the rendered image.
+  #[arg(short = 'o', long, default_value = "rna.png")]
+  png: PathBuf,
+}
+
+#[derive(Args)]
+struct SweepArgs {
+  /// DNA to execute: plain text, packed, or either one gzipped.
+  #[arg(short, long, default_value = "endo.dna.gz")]
+  dna: PathBuf,
+  /// Prefix shared by all variants, prepended as PREFIX then VARIANT.
+  #[arg(short, long, default_value = "")]
+  prefix: String,
+  /// How many iterations to run before taking the checkpoint, and so
+  /// before each variant is prepended.
+  #[arg(long, default_value_t = 0)]
+  at: u32,
+  /// File with one variant per line: a title, then the DNA to prepend
+  /// to the checkpointed DNA.
+  variants: PathBuf,
+  /// Directory to write TITLE.png for each variant.
+  #[arg(short = 'o', long, default_value = ".")]
+  out_dir: PathBuf,
+  /// Don't print progress to stderr.
+  #[arg(short, long)]
+  quiet: bool,
+}
 
 fn main() {
-  let file = File::open("endo.dna.gz").unwrap();
-  let reader = BufReader::new(file);
-  let mut decoder = GzDecoder::new(reader);
-  let mut endo_dna: String = String::new();
-  decoder.read_to_string(&mut endo_dna).unwrap();
+  let cli = Cli::parse();
+  let result = match cli.command {
+    Command::Run(args) => run(args),
+    Command::Render(args) => render(args),
+    Command::Sweep(args) => sweep(args),
+    Command::Pack(args) => pack(args),
+    Command::Diff(args) => diff(args),
+    Command::Debug(args) => debug(args),
+  };
+  if let Err(e) = result {
+    eprintln!("Error: {}", e);
+    std::process::exit(1);
+  }
+}
 
-  // TODO: can we use a #define for this that can be set
-  // at build time?  Or just clone the function and call
-  // the appropriate one to keep optimizations?
-  let mut dna = B::collect_from::<Rope<_>>(&endo_dna);
+fn create(path: &PathBuf) -> io::Result<Box<dyn Write>> {
+  Ok(if path.as_os_str() == "-" {
+    Box::new(BufWriter::new(io::stdout()))
+  } else {
+    Box::new(BufWriter::new(File::create(path)?))
+  })
+}
 
-  let args = env::args().collect::<Vec<String>>();
-  if args.len() > 1 {
-    dna.splice(0, 0, Some(B::collect_from::<Vec<_>>(&args[1])));
+// Loads a DNA file: plain text, packed, or either one gzipped.
+fn load_dna<B: BaseLike>(path: &PathBuf) -> io::Result<Rope<B>> {
+  File::open(path).and_then(dna::load).map_err(|e| {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+  })
+}
+
+// Loads DNA and puts the prefix (given directly or as a file) in front.
+fn load_with_prefix<B: BaseLike>(path: &PathBuf, prefix: &Option<String>,
+                                 prefix_file: &Option<PathBuf>) -> io::Result<Rope<B>> {
+  let mut dna = load_dna::<B>(path)?;
+  let prefix = match (prefix, prefix_file) {
+    (Some(p), _) => Rope::from_vec(parse_dna("prefix", p)?),
+    (_, Some(f)) => load_dna(f)?,
+    _ => Rope::new(),
+  };
+  dna.splice_rope(0, 0, prefix);
+  Ok(dna)
+}
+
+// Parses DNA text, saying where it came from if it's bad.
+fn parse_dna<B: BaseLike>(what: &str, text: &str) -> io::Result<Vec<B>> {
+  B::try_collect_from(text).map_err(|e| {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", what, e))
+  })
+}
+
+fn run(args: RunArgs) -> io::Result<()> {
+  // Both engines are compiled in so that the common case doesn't pay
+  // for provenance tracking.
+  if args.wide {
+    execute::<WideBase>(&args)
+  } else if args.stamps {
+    execute::<StampedBase>(&args)
+  } else if args.provenance {
+    execute::<SourceBase>(&args)
+  } else {
+    execute::<Base>(&args)
   }
+}
 
-  let mut state = DnaState::<B>::new();
-  state.print = true;
-  state.print_verbose = true;
-  let mut i = 0;
-  while !state.finished() {
-    i += 1;
-//eprintln!("\nIteration {}: {} bases, depth {} CRC {}", i, dna.len(), dna.dep(), dna::crc(&dna));
-    // if i % 50000 == 0 {
-    //   eprintln!("Iteration {}: {} bases, depth {}", i, dna.len(), dna.dep());
-    // }
-//eprintln!("{}", Join(&dna.iter().take(320).collect::<Vec<_>>(), ""));
+fn execute<B: BaseLike>(args: &RunArgs) -> io::Result<()> {
+  let (mut state, dna) = if let Some(path) = &args.resume {
+    let checkpoint = Checkpoint::<B>::load(path)?;
+    if !args.quiet {
+      eprintln!("Resuming at {} iterations, {} RNA",
+                checkpoint.iters, checkpoint.rna.len());
+    }
+    checkpoint.fork()
+  } else {
+    let dna = load_with_prefix(&args.dna, &args.prefix, &args.prefix_file)?;
+    (DnaState::<B>::new(), dna)
+  };
+
+  state.out = args.rna.as_ref().map(create).transpose()?;
+  state.print_verbose = args.verbose;
+  state.provenance = args.provenance;
+  // Checkpoints and the rope tools below work on a Rope, so other
+  // storage is only used for the iterations themselves.
+  let dna = match args.storage {
+    StorageKind::Rope => iterate_on::<B, Rope<B>>(args, &mut state, dna),
+    StorageKind::Btree => iterate_on::<B, BTreeRope<B>>(args, &mut state, dna),
+    StorageKind::Vec => iterate_on::<B, Vec<B>>(args, &mut state, dna),
+  };
+  if let Some(e) = state.error.take() { return Err(e); }
+  if let Some(out) = state.out.as_mut() { out.flush()?; }
+  if !args.quiet {
+    eprintln!("Finished {} iterations, {} RNA", state.iters, state.rna().len());
+  }
+
+  if args.stats {
+    eprintln!("{}", dna.stats());
+  }
+  if let Some(path) = &args.dot {
+    let mut out = create(path)?;
+    dna.write_dot(&mut out)?;
+    out.flush()?;
+  }
+
+  if let Some(path) = &args.save {
+    state.checkpoint(&dna).save(path)?;
+    if !args.quiet { eprintln!("Saved {}", path.display()); }
+  }
+
+  if let Some(path) = &args.png {
+    let mut canvas = rna::Canvas::new();
+    canvas.process_all(state.rna());
+    rna::png::Image::from(&canvas.finish()).save(path)?;
+    if !args.quiet { eprintln!("Wrote {}", path.display()); }
+  }
+
+  if let Some(path) = &args.coverage {
+    let mut out = create(path)?;
+    write_coverage(&mut out, &state, &load_dna(&args.dna)?)?;
+    out.flush()?;
+  }
+  Ok(())
+}
+
+fn iterate_on<B: BaseLike, D: Storage<B>>(args: &RunArgs, state: &mut DnaState<B>,
+                                          dna: Rope<B>) -> Rope<B> {
+  let mut dna = D::from_rope(dna);
+  while !state.finished() && state.error.is_none() {
+    if args.limit.is_some_and(|n| state.iters >= n) { break; }
     state.iterate(&mut dna);
-    if state.finished() { break; }
   }
-  eprintln!("Finished {} iterations, {} RNA", i, state.rna().len());
+  dna.to_rope()
+}
 
-  // Potentially we want some sort of serialization format
-  // for the coverage stats?
-  if B::HAS_SOURCE {
+fn diff(args: DiffArgs) -> io::Result<()> {
+  let dna = load_with_prefix::<Base>(&args.dna, &args.prefix, &args.prefix_file)?;
+  match args.storage {
+    StorageKind::Rope => diff_on::<Rope<Base>>(&args, dna),
+    StorageKind::Btree => diff_on::<BTreeRope<Base>>(&args, dna),
+    StorageKind::Vec => diff_on::<Vec<Base>>(&args, dna),
+  }
+}
 
-    let mut covered: HashMap<usize, BTreeSet<i8>> = HashMap::new();
-    let mut splices: HashMap<usize, BTreeSet<i8>> = HashMap::new();
-    for ((addr, lvl), stat) in state.coverage.iter() {
-      covered.entry(*addr).or_insert_with(BTreeSet::new).insert(*lvl);
-      if stat.splice {
-        splices.entry(*addr).or_insert_with(BTreeSet::new).insert(*lvl);
-      }
+fn diff_on<D: Storage<Base>>(args: &DiffArgs, dna: Rope<Base>) -> io::Result<()> {
+  let mut naive = Naive::new(dna.iter().collect());
+  let mut state = DnaState::<Base>::new();
+  let mut dna = D::from_rope(dna);
+  while !naive.finished {
+    if args.limit.is_some_and(|n| naive.iters >= n) { break; }
+    // Always check the last iteration, so a run that agrees all the
+    // way ends with the same DNA.
+    let last = args.limit.is_some_and(|n| naive.iters + 1 >= n);
+    let check_dna = last || (naive.iters + 1).is_multiple_of(args.every.max(1));
+    lockstep(&mut naive, &mut state, &mut dna, check_dna)
+        .map_err(|d| io::Error::other(d.to_string()))?;
+    if !args.quiet && naive.iters.is_multiple_of(10000) {
+      eprintln!("Agreed for {} iterations, {} RNA", naive.iters, naive.rna.len());
+    }
+  }
+  if !args.quiet {
+    eprintln!("Agreed for {} iterations, {} RNA", naive.iters, state.rna().len());
+  }
+  Ok(())
+}
+
+fn debug(args: DebugArgs) -> io::Result<()> {
+  if args.provenance {
+    debug_with::<SourceBase>(&args)
+  } else {
+    debug_with::<Base>(&args)
+  }
+}
+
+fn debug_with<B: BaseLike>(args: &DebugArgs) -> io::Result<()> {
+  let dna = load_with_prefix::<B>(&args.dna, &args.prefix, &args.prefix_file)?;
+  let mut debugger = Debugger::new(DnaState::new(), dna);
+  let mut out = io::stdout();
+  debugger.write_status(&mut out)?;
+  writeln!(out, "Type help for the commands.")?;
+  let mut line = String::new();
+  loop {
+    write!(out, "(dna) ")?;
+    out.flush()?;
+    line.clear();
+    if io::stdin().read_line(&mut line)? == 0 { break; }
+    if !debugger.command(&line, &mut out)? { break; }
+  }
+  Ok(())
+}
+
+fn pack(args: PackArgs) -> io::Result<()> {
+  let dna = load_dna::<Base>(&args.input)?;
+  let mut out = PackedWriter::new(create(&args.output)?, dna.len() as u64, !args.no_crc)?;
+  for chunk in dna.chunks() {
+    for b in chunk.iter() {
+      out.push(b)?;
+    }
+  }
+  out.finish()?.flush()?;
+  eprintln!("Wrote {} bases to {}", dna.len(), args.output.display());
+  Ok(())
+}
+
+fn render(args: RenderArgs) -> io::Result<()> {
+  let text = match &args.input {
+    Some(path) => fs::read_to_string(path)?,
+    None => {
+      let mut s = String::new();
+      io::stdin().read_to_string(&mut s)?;
+      s
+    }
+  };
+  let mut canvas = rna::Canvas::new();
+  let mut offset = 0;
+  for (n, line) in text.split('\n').enumerate() {
+    let rna = line.split('#').next().unwrap();
+    // Each line is parsed alone, so say where it is in the file.
+    let cmd = rna::Command::from_str_rna(rna).map_err(|e| {
+      let e = base::ParseError{line: n + 1, offset: offset + e.offset, ..e};
+      io::Error::new(io::ErrorKind::InvalidData, format!("RNA: {}", e))
+    })?;
+    if let Some(cmd) = cmd {
+      canvas.exec(cmd);
     }
-    let endo_bytes = endo_dna.as_bytes();
-    let chunk_start = std::cell::Cell::new(0 as usize);
-    let i = std::cell::Cell::new(0 as usize);
-
-    let print_chunk = || {
-      if chunk_start.get() == i.get() { return; }
-      println!("{:08} {}", chunk_start.get(),
-               std::str::from_utf8(&endo_bytes[chunk_start.get()..i.get()]).unwrap());
-      chunk_start.set(i.get());
+    offset += line.len() + 1;
+  }
+  rna::png::Image::from(&canvas.finish()).save(&args.png)?;
+  eprintln!("Wrote {}", args.png.display());
+  Ok(())
+}
+
+fn sweep(args: SweepArgs) -> io::Result<()> {
+  let mut dna = load_dna::<Base>(&args.dna)?;
+  let prefix = parse_dna::<Base>("prefix", &args.prefix)?;
+  let mut state = DnaState::<Base>::new();
+  while !state.finished() && state.iters < args.at {
+    state.iterate(&mut dna);
+  }
+  let checkpoint = state.checkpoint(&dna);
+  if !args.quiet {
+    eprintln!("Checkpoint at {} iterations, {} RNA",
+              checkpoint.iters, checkpoint.rna.len());
+  }
+
+  for line in fs::read_to_string(&args.variants)?.lines() {
+    let mut words = line.split_whitespace();
+    let title = match words.next() {
+      Some(t) => t,
+      None => continue,
     };
+    let variant = words.collect::<String>();
+    let (mut state, mut dna) = checkpoint.fork();
+    let mut front = prefix.clone();
+    front.extend(parse_dna::<Base>(title, &variant)?);
+    dna.splice(0, 0, Some(front));
+    while !state.finished() {
+      state.iterate(&mut dna);
+    }
+    let mut canvas = rna::Canvas::new();
+    canvas.process_all(state.rna());
+    let path = args.out_dir.join(format!("{}.png", title));
+    rna::png::Image::from(&canvas.finish()).save(&path)?;
+    if !args.quiet {
+      eprintln!("{}: {} iterations, {} RNA, wrote {}",
+                title, state.iters, state.rna().len(), path.display());
+    }
+  }
+  Ok(())
+}
 
-    while i.get() < endo_bytes.len() {
-      if let Some(levels) = splices.get(&i.get()) {
-        print_chunk();
-        println!("--- {} ---", levels.iter().map(|x| format!("{}", x)).collect::<Vec<_>>().join(", "));
-      } else if i.get() - chunk_start.get() >= 50 {
-        print_chunk();
-      }
-      if let Some(cov_lvls) = covered.get(&i.get()) {
-        // Start a new covered bit at the given levels.
-        let mut to_remove = vec![];
-        for lvl in cov_lvls {
-          // What do we have? Gather it and any continuations at same escape level?
-          let stat = state.coverage.get(&(i.get(), *lvl)).unwrap();
-          if stat.usage.is_some() {
-            let prefix = format!("{:08}@{} [{}..{} #{}]", i.get(), lvl, stat.first, stat.last, stat.count);
-            let (suffix, used) = state.source_dump(i.get(), *lvl);
-            to_remove.extend(used);
-            println!("{} {}", prefix, suffix);
-          }
-        }
-        for (a, l) in to_remove {
-          if let Some(set) = covered.get_mut(&a) { set.remove(&l); }
+// Potentially we want some sort of serialization format
+// for the coverage stats?
+fn write_coverage<O: Write, B: BaseLike>(out: &mut O, state: &DnaState<B>,
+                                         endo: &Rope<Base>) -> io::Result<()> {
+  let mut covered: HashMap<usize, BTreeSet<i32>> = HashMap::new();
+  let mut splices: HashMap<usize, BTreeSet<i32>> = HashMap::new();
+  for ((addr, lvl), stat) in state.coverage.iter() {
+    covered.entry(*addr).or_default().insert(*lvl);
+    if stat.splice {
+      splices.entry(*addr).or_default().insert(*lvl);
+    }
+  }
+  let mut chunk_start = 0;
+  let mut i = 0;
+
+  let print_chunk = |out: &mut O, chunk_start: &mut usize, i: usize| {
+    if *chunk_start == i { return Ok(()); }
+    let text = endo.cursor().chunks(*chunk_start .. i)
+        .flat_map(|c| c.iter()).map(|b| b.char()).collect::<String>();
+    let result = writeln!(out, "{:08} {}", chunk_start, text);
+    *chunk_start = i;
+    result
+  };
+
+  while i < endo.len() {
+    if let Some(levels) = splices.get(&i) {
+      print_chunk(out, &mut chunk_start, i)?;
+      writeln!(out, "--- {} ---", levels.iter().map(|x| format!("{}", x)).collect::<Vec<_>>().join(", "))?;
+    } else if i - chunk_start >= 50 {
+      print_chunk(out, &mut chunk_start, i)?;
+    }
+    if let Some(cov_lvls) = covered.get(&i) {
+      // Start a new covered bit at the given levels.
+      let mut to_remove = vec![];
+      for lvl in cov_lvls {
+        // What do we have? Gather it and any continuations at same escape level?
+        let stat = state.coverage.get(&(i, *lvl)).unwrap();
+        if stat.usage.is_some() {
+          let prefix = format!("{:08}@{} [{}..{} #{}]", i, lvl, stat.first, stat.last, stat.count);
+          let (suffix, used) = state.source_dump(i, *lvl);
+          to_remove.extend(used);
+          writeln!(out, "{} {}", prefix, suffix)?;
         }
       }
-      i.set(i.get() + 1);
+      for (a, l) in to_remove {
+        if let Some(set) = covered.get_mut(&a) { set.remove(&l); }
+      }
     }
-    //   // catchup missing bits
-    //   if i > *addr { continue; } // ignore for now
-    //   if i < *addr {
-    //     let mut s = String::new();
-    //     let mut start = i;
-    //     while i < *addr {
-    //       s.push(endo_bytes[i] as char);
-    //       i += 1;
-    //       if i % 50 == 0 {
-    //         println!("{:08} {}", start, s);
-    //         start = i;
-    //         s.clear();
-    //       }
-    //     }
-    //     if s.len() > 0 {
-    //       println!("{:08} {}", start, s);
-    //     }
-    //   }
-    //   // figure out what to print, how many bases, etc...
-      
-    // }
+    i += 1;
   }
+  Ok(())
 }
@@ -0,0 +1,387 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use base::{Base, BaseLike, PackedReader, PackedWriter};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use rope::{Rope, RopeBuilder};
+
+use crate::{Addr, DnaState, Rna, Stat, State, Usage};
+
+// A snapshot of the whole machine, which can be resumed any number of
+// times.  Taking one is cheap: the rope is persistent, so the DNA is
+// shared with the running state until one of them splices it.
+#[derive(Clone, Debug)]
+pub struct Checkpoint<T: BaseLike> {
+  pub dna: Rope<T>,
+  pub iters: u32,
+  pub finished: bool,
+  pub provenance: bool,
+  pub rna: Vec<Rna<T>>,
+  pub coverage: BTreeMap<Addr, Stat>,
+}
+
+impl<T: BaseLike> Checkpoint<T> {
+  // Starts an independent continuation from this checkpoint.  The new
+  // state doesn't write RNA anywhere until its `out` is set.
+  pub fn fork(&self) -> (DnaState<T>, Rope<T>) {
+    let mut state = DnaState::new();
+    state.iters = self.iters;
+    state.finished = self.finished;
+    state.provenance = self.provenance;
+    state.rna = self.rna.clone();
+    state.coverage = self.coverage.clone();
+    (state, self.dna.clone())
+  }
+}
+
+impl<T: BaseLike> DnaState<T> {
+  pub fn checkpoint(&self, dna: &Rope<T>) -> Checkpoint<T> {
+    Checkpoint{
+      dna: dna.clone(),
+      iters: self.iters,
+      finished: self.finished,
+      provenance: self.provenance,
+      rna: self.rna.clone(),
+      coverage: self.coverage.clone(),
+    }
+  }
+}
+
+////////////////////////////////////////////////////////////////
+// Serialization
+//
+// Checkpoints are written as a gzipped little-endian binary stream:
+//   magic, version: u32, has_source: u8
+//   iters: u32, finished: u8, provenance: u8
+//   dna: without has_source, a packed DNA stream (as base::PackedWriter
+//     writes it, with a CRC); otherwise dna length: u64, then each base
+//   rna count: u64, then 7 bases per RNA
+//   coverage count: u64, then (addr: u64, level: i32, splice: u8,
+//     usage: u8 (0xff for none), count: u32, first: u32, last: u32)
+// A base is its u2 value (plus 4 if it was synthesized, plus 8 if it has
+// a nonzero stamp), followed by (addr: u32, level: i32) if the checkpoint
+// has provenance, then the stamp: u32 if any.
+
+const MAGIC: &[u8; 8] = b"ENDOCKPT";
+const VERSION: u32 = 1;
+const SYNTHESIZED: u8 = 4;
+const STAMPED: u8 = 8;
+// The most to reserve up front for a length read from the file.
+const MAX_HINT: usize = 1 << 20;
+
+fn invalid<U>(msg: &str) -> io::Result<U> {
+  Err(io::Error::new(io::ErrorKind::InvalidData, msg.to_string()))
+}
+
+impl<T: BaseLike> Checkpoint<T> {
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+    let mut out = GzEncoder::new(BufWriter::new(File::create(path)?),
+                                 Compression::fast());
+    self.write(&mut out)?;
+    out.finish()?.flush()
+  }
+
+  pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    Checkpoint::read(&mut GzDecoder::new(BufReader::new(File::open(path)?)))
+  }
+
+  pub fn write<O: Write>(&self, out: &mut O) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&[T::HAS_SOURCE as u8])?;
+    out.write_all(&self.iters.to_le_bytes())?;
+    out.write_all(&[self.finished as u8, self.provenance as u8])?;
+
+    if T::HAS_SOURCE {
+      out.write_all(&(self.dna.len() as u64).to_le_bytes())?;
+      for base in self.dna.iter() {
+        write_base(out, base)?;
+      }
+    } else {
+      let mut w = PackedWriter::new(&mut *out, self.dna.len() as u64, true)?;
+      for base in self.dna.iter() {
+        w.push(base.to_base())?;
+      }
+      w.finish()?;
+    }
+
+    out.write_all(&(self.rna.len() as u64).to_le_bytes())?;
+    for rna in self.rna.iter() {
+      for base in rna {
+        write_base(out, *base)?;
+      }
+    }
+
+    out.write_all(&(self.coverage.len() as u64).to_le_bytes())?;
+    for ((addr, level), stat) in self.coverage.iter() {
+      out.write_all(&(*addr as u64).to_le_bytes())?;
+      out.write_all(&level.to_le_bytes())?;
+      out.write_all(&[stat.splice as u8, stat.usage.map_or(0xff, |u| u as u8)])?;
+      out.write_all(&stat.count.to_le_bytes())?;
+      out.write_all(&stat.first.to_le_bytes())?;
+      out.write_all(&stat.last.to_le_bytes())?;
+    }
+    Ok(())
+  }
+
+  // A checkpoint saved with provenance can be read into a plain engine
+  // (dropping the provenance), but not the other way around.
+  pub fn read<I: Read>(input: &mut I) -> io::Result<Self> {
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC { return invalid("Not a checkpoint file"); }
+    if read_u32(input)? != VERSION {
+      return invalid("Unsupported checkpoint version");
+    }
+    let has_source = read_u8(input)? != 0;
+    if T::HAS_SOURCE && !has_source {
+      return invalid("Checkpoint was saved without provenance");
+    }
+    let iters = read_u32(input)?;
+    let finished = read_u8(input)? != 0;
+    let provenance = read_u8(input)? != 0 && T::HAS_SOURCE;
+
+    // The lengths aren't trusted until that many bases have been read,
+    // so they only give a hint of how much room to make.  The DNA streams
+    // into a rope, which needs no hint.
+    let mut dna = RopeBuilder::new();
+    if has_source {
+      for _ in 0 .. read_u64(input)? {
+        dna.push(read_base(input, has_source)?);
+      }
+    } else {
+      PackedReader::new(&mut *input)?.read_into(&mut dna)?;
+    }
+
+    let rna_len = read_u64(input)? as usize;
+    let mut rna = Vec::with_capacity(rna_len.min(MAX_HINT));
+    for _ in 0 .. rna_len {
+      let mut r: Rna<T> = [T::from_base(Base::I); 7];
+      for base in r.iter_mut() {
+        *base = read_base(input, has_source)?;
+      }
+      rna.push(r);
+    }
+
+    let coverage_len = read_u64(input)? as usize;
+    let mut coverage = BTreeMap::new();
+    for _ in 0 .. coverage_len {
+      let addr = read_u64(input)? as usize;
+      let level = read_u32(input)? as i32;
+      let splice = read_u8(input)? != 0;
+      let usage = match read_u8(input)? {
+        0xff => None,
+        u => match Usage::from_u8(u) {
+          Some(u) => Some(u),
+          None => { return invalid("Bad usage in coverage"); }
+        },
+      };
+      let count = read_u32(input)?;
+      let first = read_u32(input)?;
+      let last = read_u32(input)?;
+      if provenance {
+        coverage.insert((addr, level), Stat{splice, usage, count, first, last});
+      }
+    }
+
+    Ok(Checkpoint{dna: dna.finish(), iters, finished, provenance,
+                  rna, coverage})
+  }
+}
+
+fn write_base<O: Write, T: BaseLike>(out: &mut O, base: T) -> io::Result<()> {
+  let stamp = base.stamp().unwrap_or(0);
+  let mut flags = if base.is_synthesized() { SYNTHESIZED } else { 0 };
+  if stamp != 0 { flags |= STAMPED; }
+  out.write_all(&[base.to_u2() | flags])?;
+  if T::HAS_SOURCE {
+    out.write_all(&base.addr().unwrap().to_le_bytes())?;
+    out.write_all(&base.level().unwrap().to_le_bytes())?;
+  }
+  if stamp != 0 {
+    out.write_all(&stamp.to_le_bytes())?;
+  }
+  Ok(())
+}
+
+fn read_base<I: Read, T: BaseLike>(input: &mut I, has_source: bool) -> io::Result<T> {
+  let byte = read_u8(input)?;
+  if byte & !(STAMPED | SYNTHESIZED | 3) != 0 { return invalid("Bad base"); }
+  let base = Base::from_u8(byte);
+  let base = if has_source {
+    let addr = read_u32(input)?;
+    let level = read_u32(input)? as i32;
+    if byte & SYNTHESIZED != 0 {
+      T::synthesized(base, addr)
+    } else {
+      T::from_parts(base, addr, level)
+    }
+  } else {
+    T::from_base(base)
+  };
+  if byte & STAMPED != 0 {
+    Ok(base.stamped(read_u32(input)?))
+  } else {
+    Ok(base)
+  }
+}
+
+fn read_u8<I: Read>(input: &mut I) -> io::Result<u8> {
+  let mut buf = [0u8; 1];
+  input.read_exact(&mut buf)?;
+  Ok(buf[0])
+}
+
+fn read_u32<I: Read>(input: &mut I) -> io::Result<u32> {
+  let mut buf = [0u8; 4];
+  input.read_exact(&mut buf)?;
+  Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<I: Read>(input: &mut I) -> io::Result<u64> {
+  let mut buf = [0u8; 8];
+  input.read_exact(&mut buf)?;
+  Ok(u64::from_le_bytes(buf))
+}
+
+
+#[cfg(test)]
+mod checkpoint_tests {
+  use super::*;
+  use base::{SourceBase, StampedBase, WideBase};
+
+  fn roundtrip<T: BaseLike, U: BaseLike>(c: &Checkpoint<T>) -> io::Result<Checkpoint<U>> {
+    let mut buf = Vec::new();
+    c.write(&mut buf)?;
+    Checkpoint::read(&mut &buf[..])
+  }
+
+  fn run<T: BaseLike>(dna: &str, iters: u32) -> Checkpoint<T> {
+    let mut dna = T::collect_from::<Rope<_>>(dna);
+    let mut state = DnaState::<T>::new();
+    while !state.finished() && state.iters < iters {
+      state.iterate(&mut dna);
+    }
+    state.checkpoint(&dna)
+  }
+
+  const DNA: &str = "IIIPIPIIICIICIICIIPIPICPIICICIIFICCIFPPIICCFPCIIIPIPIIIPIICIIC";
+
+  #[test]
+  fn roundtrip_base() {
+    let c = run::<Base>(DNA, 2);
+    let d = roundtrip::<Base, Base>(&c).unwrap();
+    assert_eq!(d.iters, 2);
+    assert_eq!(d.rna, c.rna);
+    assert_eq!(d.dna.iter().collect::<Vec<_>>(), c.dna.iter().collect::<Vec<_>>());
+    assert!(!d.provenance);
+  }
+
+  #[test]
+  fn roundtrip_source() {
+    let c = run::<SourceBase>(DNA, 2);
+    assert!(!c.coverage.is_empty());
+    let d = roundtrip::<SourceBase, SourceBase>(&c).unwrap();
+    assert_eq!(d.iters, c.iters);
+    assert_eq!(d.finished, c.finished);
+    assert_eq!(d.rna, c.rna);
+    assert_eq!(d.coverage, c.coverage);
+    assert_eq!(d.dna.iter().collect::<Vec<_>>(), c.dna.iter().collect::<Vec<_>>());
+
+    // Resuming gives the same result as never stopping.
+    let (mut state, mut dna) = d.fork();
+    while !state.finished() {
+      state.iterate(&mut dna);
+    }
+    let full = run::<SourceBase>(DNA, u32::MAX);
+    assert_eq!(state.rna(), &full.rna[..]);
+    assert_eq!(state.coverage, full.coverage);
+  }
+
+  // Synthesized bases have no level of their own, so leave it out.
+  fn parts<T: BaseLike>(dna: &Rope<T>) -> Vec<(Base, Option<u32>, Option<i32>)> {
+    dna.iter().map(|b| {
+      let level = if b.is_synthesized() { None } else { b.level() };
+      (b.to_base(), b.addr(), level)
+    }).collect()
+  }
+
+  #[test]
+  fn widen_provenance() {
+    let c = run::<SourceBase>(DNA, 2);
+    let d = roundtrip::<SourceBase, WideBase>(&c).unwrap();
+    assert_eq!(d.coverage, c.coverage);
+    assert_eq!(parts(&d.dna), parts(&c.dna));
+    let e = roundtrip::<WideBase, WideBase>(&d).unwrap();
+    assert_eq!(e.dna.iter().collect::<Vec<_>>(), d.dna.iter().collect::<Vec<_>>());
+    assert_eq!(e.rna, d.rna);
+  }
+
+  #[test]
+  fn roundtrip_stamps() {
+    let c = run::<StampedBase>(DNA, 2);
+    assert!(c.dna.iter().any(|b| b.stamp() != Some(0)));
+    let d = roundtrip::<StampedBase, StampedBase>(&c).unwrap();
+    assert_eq!(d.dna.iter().collect::<Vec<_>>(), c.dna.iter().collect::<Vec<_>>());
+    assert_eq!(d.rna, c.rna);
+    // Other engines drop the stamps.
+    let e = roundtrip::<StampedBase, SourceBase>(&c).unwrap();
+    assert_eq!(parts(&e.dna), parts(&c.dna));
+  }
+
+  #[test]
+  fn drop_provenance() {
+    let c = run::<SourceBase>(DNA, 2);
+    let d = roundtrip::<SourceBase, Base>(&c).unwrap();
+    assert!(d.coverage.is_empty());
+    assert_eq!(d.dna.iter().map(|b| b.to_base()).collect::<Vec<_>>(),
+               c.dna.iter().map(|b| b.to_base()).collect::<Vec<_>>());
+    assert!(roundtrip::<Base, SourceBase>(&d).is_err());
+  }
+
+  #[test]
+  fn bad_magic() {
+    let mut buf = Vec::new();
+    run::<Base>(DNA, 1).write(&mut buf).unwrap();
+    buf[0] = b'X';
+    assert!(Checkpoint::<Base>::read(&mut &buf[..]).is_err());
+  }
+
+  #[test]
+  fn bad_version() {
+    let mut buf = Vec::new();
+    run::<Base>(DNA, 1).write(&mut buf).unwrap();
+    buf[8 .. 12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+    let e = Checkpoint::<Base>::read(&mut &buf[..]).map(|_| ()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn truncated() {
+    let mut buf = Vec::new();
+    run::<SourceBase>(DNA, 1).write(&mut buf).unwrap();
+    // A DNA length far too big to allocate fails on reading, not
+    // before.
+    buf.truncate(27);
+    buf[19 .. 27].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+    let e = Checkpoint::<SourceBase>::read(&mut &buf[..]).map(|_| ()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+  }
+
+  #[test]
+  fn packed_dna() {
+    let c = run::<Base>(&DNA.repeat(100), 1);
+    let mut buf = Vec::new();
+    c.write(&mut buf).unwrap();
+    assert!(buf.len() < 19 + 21 + c.dna.len() / 4 + 4 + 100);
+    // The packed DNA's length is no more trusted than the others.
+    buf.truncate(40);
+    buf[32 .. 40].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+    let e = Checkpoint::<Base>::read(&mut &buf[..]).map(|_| ()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+  }
+}
@@ -0,0 +1,588 @@
+use std::cmp;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use base::{Base, BaseLike, Join};
+use rope::Rope;
+
+use crate::{source, Addr, DnaState, Env, PItem, Pattern, Rna, State, Storage, StorageChunk,
+            StorageCursor, TItem, Template, SYNTHESIZED_LEVEL};
+
+// The next iteration, decoded without running it.
+pub struct Preview<T: BaseLike> {
+  pub pattern: Vec<PItem<T>>,
+  pub template: Vec<TItem<T>>,
+  // RNA emitted while reading them.
+  pub rna: Vec<Rna<T>>,
+  pub pattern_end: usize,
+  pub template_end: usize,
+  // Where the match ends and the groups it finds, unless it fails.
+  pub matched: Option<(usize, Vec<(usize, usize)>)>,
+  // Whether the DNA runs out first, which ends the run.
+  pub finishes: bool,
+}
+
+impl<T: BaseLike> Preview<T> {
+  pub fn new<D: Storage<T>>(dna: &D) -> Self {
+    // Parse into a scratch state, so that nothing is recorded twice.
+    let mut scratch = DnaState::<T>::new();
+    let mut cursor = dna.cursor();
+    let pattern = PItem::parse(&mut cursor, &mut scratch);
+    let pattern_end = cursor.pos();
+    let template = if scratch.finished() {
+      vec![]
+    } else {
+      TItem::parse(&mut cursor, &mut scratch)
+    };
+    let template_end = cursor.pos();
+    let finishes = scratch.finished();
+    let matched = if finishes {
+      None
+    } else {
+      let mut env = Env{starts: vec![], groups: vec![]};
+      pattern.iter().all(|p| p.exec(&mut cursor, &mut env))
+          .then(|| (cursor.pos(), env.groups))
+    };
+    Preview{pattern, template, rna: scratch.rna, pattern_end, template_end, matched, finishes}
+  }
+}
+
+const HELP: &str = "\
+s, step [N]          run N iterations (default 1)
+r, run ITER          run until iteration ITER is done
+c, continue          run until the DNA runs out
+sh, show             decode the next iteration
+g, groups            show what the next pattern matches
+d, dna [POS [LEN]]   show DNA from POS (default 0), with provenance
+rna [N]              show the last N RNA (default 10)
+b, break ADDR        stop before a pattern or template starting at ADDR
+b, break RNA         stop before emitting RNA, e.g. PIPIIIC
+w, watch A[..B] [consumed|copied|emitted]...
+                     stop before a base from addresses A..B is read as
+                     code, copied by a template or emitted (default all)
+l, list              list breakpoints
+delete [N]           delete breakpoint N, or all of them
+h, help              show this
+q, quit
+Running stops before any iteration that would hit a breakpoint.  An
+empty line repeats the last command.";
+
+// How many bases `dna` and `groups` show by default.
+const SHOW_LEN: usize = 256;
+const LINE_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+  // Read as part of a pattern or template (or an RNA between them), or
+  // matched by the pattern and so replaced.
+  Consumed,
+  // In a group that the template refers to.
+  Copied,
+  Emitted,
+}
+
+impl fmt::Display for Access {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      Access::Consumed => "consumed",
+      Access::Copied => "copied",
+      Access::Emitted => "emitted",
+    })
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Breakpoint {
+  // A pattern or template whose first base comes from this address.
+  // Much of Endo's code is escaped in the source and then made by
+  // templates, so this is at any level, synthesized included.
+  Code(usize),
+  // Any base from these addresses, at any level.
+  Watch(Range<usize>, Vec<Access>),
+  Rna(Rna<Base>),
+}
+
+impl fmt::Display for Breakpoint {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Breakpoint::Code(addr) => write!(f, "pattern or template at @{}", addr),
+      Breakpoint::Watch(addrs, access) => {
+        write!(f, "@{}..{} {}", addrs.start, addrs.end, Join(access, ", "))
+      }
+      Breakpoint::Rna(rna) => write!(f, "emitting {}", Join(rna, "")),
+    }
+  }
+}
+
+// A line-at-a-time debugger for the DNA machine.
+pub struct Debugger<T: BaseLike, D: Storage<T> = Rope<T>> {
+  pub state: DnaState<T>,
+  pub dna: D,
+  breakpoints: Vec<(usize, Breakpoint)>,
+  next_id: usize,
+  // The iteration we last stopped before, which shouldn't stop us again.
+  stopped: Option<u32>,
+  last: String,
+}
+
+// The first base in `range` of the DNA that comes from one of `addrs`.
+// Groups can be most of the DNA, so this looks at the ends of each run
+// of provenance rather than every base: within a run the level stays
+// the same and the address either stays the same or counts up by one.
+fn find_addr<'a, T: BaseLike, C: StorageCursor<'a, T>>(cursor: &mut C, range: Range<usize>,
+                                                       addrs: &Range<usize>)
+                                                       -> Option<(usize, Addr)> {
+  if !T::HAS_SOURCE { return None; }
+  let mut pos = range.start;
+  while pos < range.end {
+    let chunk = cursor.chunk_at(pos)?;
+    let n = cmp::min(chunk.len(), range.end - pos);
+    let mut k = 0;
+    while k < n {
+      let end = cmp::min(chunk.run_end(k), n);
+      if let (Some((first, lvl)), Some((last, _))) =
+          (source(chunk.at(k)), source(chunk.at(end - 1))) {
+        let addr = if first == last { first } else { cmp::max(first, addrs.start) };
+        if addrs.contains(&addr) && addr <= last {
+          return Some((pos + k + addr - first, (addr, lvl)));
+        }
+      }
+      k = end;
+    }
+    pos += n;
+  }
+  None
+}
+
+fn level(lvl: i32) -> String {
+  match lvl {
+    0 => String::new(),
+    SYNTHESIZED_LEVEL => " (made)".to_string(),
+    lvl => format!(" \\{}", lvl),
+  }
+}
+
+fn parse_breakpoint(words: &[&str]) -> Result<Breakpoint, String> {
+  let arg = *words.get(1).ok_or("Break where?")?;
+  let num = |w: &str| w.parse::<usize>().map_err(|e| format!("Bad number: {}", e));
+  if words[0].starts_with('w') {
+    let addrs = match arg.split_once("..") {
+      Some((start, end)) => num(start)? .. num(end)?,
+      None => num(arg)? .. num(arg)? + 1,
+    };
+    if addrs.is_empty() { return Err("No addresses to watch".to_string()); }
+    let mut access = words[2 ..].iter().map(|w| match *w {
+      "consumed" => Ok(Access::Consumed),
+      "copied" => Ok(Access::Copied),
+      "emitted" => Ok(Access::Emitted),
+      _ => Err(format!("Can't watch for {:?}", w)),
+    }).collect::<Result<Vec<_>, _>>()?;
+    if access.is_empty() { access = vec![Access::Consumed, Access::Copied, Access::Emitted]; }
+    Ok(Breakpoint::Watch(addrs, access))
+  } else if let Ok(addr) = arg.parse::<usize>() {
+    Ok(Breakpoint::Code(addr))
+  } else {
+    let rna = Base::try_collect_from::<Vec<_>, _>(arg).map_err(|e| e.to_string())?;
+    Ok(Breakpoint::Rna(rna.try_into().map_err(|_| "An RNA is 7 bases".to_string())?))
+  }
+}
+
+impl<T: BaseLike, D: Storage<T>> Debugger<T, D> {
+  pub fn new(state: DnaState<T>, dna: D) -> Self {
+    Debugger{state, dna, breakpoints: vec![], next_id: 1, stopped: None, last: String::new()}
+  }
+
+  pub fn add_breakpoint(&mut self, bp: Breakpoint) -> usize {
+    let id = self.next_id;
+    self.next_id += 1;
+    self.breakpoints.push((id, bp));
+    id
+  }
+
+  // Runs up to n iterations, returning why it stopped early if it did.
+  pub fn step(&mut self, n: u32) -> Option<String> {
+    self.run_to(self.state.iters.saturating_add(n))
+  }
+
+  pub fn run_to(&mut self, iter: u32) -> Option<String> {
+    while self.state.iters < iter && !self.state.finished() {
+      if !self.breakpoints.is_empty() && self.stopped != Some(self.state.iters) {
+        if let Some(why) = self.check_breakpoints() {
+          self.stopped = Some(self.state.iters);
+          return Some(why);
+        }
+      }
+      self.state.iterate(&mut self.dna);
+    }
+    None
+  }
+
+  // Whether the next iteration hits a breakpoint, and which.
+  fn check_breakpoints(&self) -> Option<String> {
+    let p = Preview::new(&self.dna);
+    let mut cursor = self.dna.cursor();
+    let mut starts = vec![("pattern", 0)];
+    if !p.finishes { starts.push(("template", p.pattern_end)); }
+    // Copied groups can be most of the DNA, so each is only looked at
+    // once, however often the template refers to it.
+    let mut copied = p.template.iter().filter_map(|t| match t {
+      TItem::Ref{group, ..} => p.matched.as_ref()?.1.get(*group).copied(),
+      _ => None,
+    }).collect::<Vec<_>>();
+    copied.sort();
+    copied.dedup();
+    let consumed = p.matched.as_ref().map_or(p.template_end, |(end, _)| *end);
+    for (id, bp) in &self.breakpoints {
+      let why = match bp {
+        Breakpoint::Code(addr) => starts.iter().find_map(|(what, pos)| {
+          let (_, lvl) = cursor.try_at(*pos).and_then(source).filter(|(a, _)| a == addr)?;
+          Some(format!("the {} starts at @{}{}", what, addr, level(lvl)))
+        }),
+        Breakpoint::Watch(addrs, access) => access.iter().find_map(|a| {
+          let (pos, (addr, lvl)) = match a {
+            Access::Consumed => find_addr(&mut cursor, 0 .. consumed, addrs)
+                .map(|(i, s)| (Some(i), s)),
+            Access::Copied => copied.iter()
+                .find_map(|(start, end)| find_addr(&mut cursor, *start .. *end, addrs))
+                .map(|(i, s)| (Some(i), s)),
+            Access::Emitted => p.rna.iter().flatten().filter_map(|b| source(*b))
+                .find(|(addr, _)| addrs.contains(addr)).map(|s| (None, s)),
+          }?;
+          let at = pos.map_or(String::new(), |i| format!(" at {}", i));
+          Some(format!("@{}{}{} is {}", addr, level(lvl), at, a))
+        }),
+        Breakpoint::Rna(rna) => p.rna.iter().any(|r| r.map(BaseLike::to_base) == *rna)
+            .then(|| format!("emits {}", Join(rna, ""))),
+      };
+      if let Some(why) = why {
+        return Some(format!("Stopped before iteration {}: breakpoint {}, {}",
+                            self.state.iters + 1, id, why));
+      }
+    }
+    None
+  }
+
+  // Runs one command, returning false to quit.
+  pub fn command<W: Write>(&mut self, line: &str, out: &mut W) -> io::Result<bool> {
+    let line = match line.trim() {
+      "" => self.last.clone(),
+      line => line.to_string(),
+    };
+    self.last = line.clone();
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    match self.run_command(&words, out)? {
+      Ok(more) => Ok(more),
+      Err(msg) => {
+        writeln!(out, "{}", msg)?;
+        Ok(true)
+      }
+    }
+  }
+
+  // The inner result is a mistake in the command, to report.
+  fn run_command<W: Write>(&mut self, words: &[&str], out: &mut W)
+                           -> io::Result<Result<bool, String>> {
+    let num = |k: usize, default: usize| match words.get(k + 1) {
+      Some(w) => w.parse::<usize>().map_err(|e| format!("Bad number: {}", e)),
+      None => Ok(default),
+    };
+    macro_rules! num {
+      ($k:expr, $default:expr) => {
+        match num($k, $default) { Ok(n) => n, Err(e) => return Ok(Err(e)) }
+      };
+    }
+    let cmd = words.first().copied().unwrap_or("");
+    match cmd {
+      "" => {}
+      "s" | "step" => {
+        let why = self.step(num!(0, 1) as u32);
+        self.write_stop(out, why)?;
+      }
+      "r" | "run" => {
+        if words.len() < 2 { return Ok(Err("Run to which iteration?".to_string())); }
+        let why = self.run_to(num!(0, 0) as u32);
+        self.write_stop(out, why)?;
+      }
+      "c" | "continue" => {
+        let why = self.run_to(u32::MAX);
+        self.write_stop(out, why)?;
+      }
+      "sh" | "show" => self.write_preview(out)?,
+      "g" | "groups" => self.write_groups(out)?,
+      "d" | "dna" => self.write_dna(out, num!(0, 0), num!(1, SHOW_LEN))?,
+      "rna" => self.write_rna(out, num!(0, 10))?,
+      "b" | "break" | "w" | "watch" => {
+        let bp = match parse_breakpoint(words) {
+          Ok(bp) => bp,
+          Err(e) => return Ok(Err(e)),
+        };
+        if !T::HAS_SOURCE && !matches!(bp, Breakpoint::Rna(_)) {
+          return Ok(Err("Addresses need --provenance".to_string()));
+        }
+        let id = self.add_breakpoint(bp.clone());
+        writeln!(out, "Breakpoint {}: {}", id, bp)?;
+      }
+      "l" | "list" => {
+        if self.breakpoints.is_empty() { writeln!(out, "No breakpoints")?; }
+        for (id, bp) in &self.breakpoints {
+          writeln!(out, "{:>4}  {}", id, bp)?;
+        }
+      }
+      "delete" => {
+        if words.len() < 2 {
+          self.breakpoints.clear();
+          writeln!(out, "Deleted all breakpoints")?;
+        } else {
+          let id = num!(0, 0);
+          let before = self.breakpoints.len();
+          self.breakpoints.retain(|(k, _)| *k != id);
+          if self.breakpoints.len() == before {
+            return Ok(Err(format!("No breakpoint {}", id)));
+          }
+          writeln!(out, "Deleted breakpoint {}", id)?;
+        }
+      }
+      "h" | "help" => writeln!(out, "{}", HELP)?,
+      "q" | "quit" => return Ok(Ok(false)),
+      _ => return Ok(Err(format!("Unknown command {:?}; try help", cmd))),
+    }
+    Ok(Ok(true))
+  }
+
+  fn write_stop<W: Write>(&self, out: &mut W, why: Option<String>) -> io::Result<()> {
+    if let Some(why) = why { writeln!(out, "{}", why)?; }
+    self.write_status(out)
+  }
+
+  pub fn write_status<W: Write>(&self, out: &mut W) -> io::Result<()> {
+    writeln!(out, "Done {} iterations: {} bases, {} RNA{}",
+             self.state.iters, self.dna.len(), self.state.rna().len(),
+             if self.state.finished() { ", finished" } else { "" })
+  }
+
+  fn write_preview<W: Write>(&self, out: &mut W) -> io::Result<()> {
+    if self.state.finished() { return writeln!(out, "Finished"); }
+    let p = Preview::new(&self.dna);
+    writeln!(out, "Iteration {}", self.state.iters + 1)?;
+    writeln!(out, "  pattern  0..{}: {}", p.pattern_end, Join(&p.pattern, " "))?;
+    if !p.rna.is_empty() {
+      writeln!(out, "  emits {} RNA while reading", p.rna.len())?;
+    }
+    if p.finishes { return writeln!(out, "  then the DNA runs out"); }
+    writeln!(out, "  template {}..{}: {}", p.pattern_end, p.template_end,
+             Join(&p.template, " "))?;
+    match p.matched {
+      Some((end, groups)) => {
+        writeln!(out, "  matches {}..{} with {} groups", p.template_end, end, groups.len())
+      }
+      None => writeln!(out, "  doesn't match"),
+    }
+  }
+
+  fn write_groups<W: Write>(&self, out: &mut W) -> io::Result<()> {
+    if self.state.finished() { return writeln!(out, "Finished"); }
+    let groups = match Preview::new(&self.dna).matched {
+      Some((_, groups)) => groups,
+      None => return writeln!(out, "No match"),
+    };
+    let mut cursor = self.dna.cursor();
+    for (k, (start, end)) in groups.iter().enumerate() {
+      let shown = (*start .. (*end).min(start + LINE_LEN))
+          .map(|i| cursor.at(i).to_base().char()).collect::<String>();
+      let more = if end - start > LINE_LEN { "..." } else { "" };
+      writeln!(out, "  ${} {}..{} ({} bases): {}{}", k, start, end, end - start, shown, more)?;
+    }
+    Ok(())
+  }
+
+  // Prints the DNA a line at a time, starting a new line wherever the
+  // source address stops counting up.
+  fn write_dna<W: Write>(&self, out: &mut W, start: usize, len: usize) -> io::Result<()> {
+    let end = start.saturating_add(len).min(self.dna.len());
+    let mut cursor = self.dna.cursor();
+    let mut pos = start;
+    while pos < end {
+      let first = source(cursor.at(pos));
+      let mut n = 1;
+      while pos + n < end && n < LINE_LEN
+          && source(cursor.at(pos + n)) == first.map(|(addr, lvl)| (addr + n, lvl)) {
+        n += 1;
+      }
+      let bases = (pos .. pos + n).map(|i| cursor.at(i).to_base().char()).collect::<String>();
+      match first {
+        Some((addr, SYNTHESIZED_LEVEL)) => {
+          writeln!(out, "{:>9}  {:w$}  made by @{}", pos, bases, addr, w = LINE_LEN)?
+        }
+        Some((addr, 0)) => writeln!(out, "{:>9}  {:w$}  @{}", pos, bases, addr, w = LINE_LEN)?,
+        Some((addr, lvl)) => {
+          writeln!(out, "{:>9}  {:w$}  @{} \\{}", pos, bases, addr, lvl, w = LINE_LEN)?
+        }
+        None => writeln!(out, "{:>9}  {}", pos, bases)?,
+      }
+      pos += n;
+    }
+    Ok(())
+  }
+
+  fn write_rna<W: Write>(&self, out: &mut W, count: usize) -> io::Result<()> {
+    let rna = self.state.rna();
+    for (k, r) in rna.iter().enumerate().skip(rna.len().saturating_sub(count)) {
+      writeln!(out, "{:>9}  {}", k, r.iter().map(|b| b.to_base().char()).collect::<String>())?;
+    }
+    Ok(())
+  }
+}
+
+
+#[cfg(test)]
+mod debugger_tests {
+  use super::*;
+  use base::{Base, SourceBase};
+
+  fn run<T: BaseLike>(debugger: &mut Debugger<T>, line: &str) -> String {
+    let mut out = vec![];
+    assert!(debugger.command(line, &mut out).unwrap());
+    String::from_utf8(out).unwrap()
+  }
+
+  #[test]
+  fn preview() {
+    let dna = Base::collect_from::<Rope<_>>("IIPIPICPIICICIIFICCIFPPIICCFPC");
+    let p = Preview::new(&dna);
+    assert_eq!(Join(&p.pattern, " ").to_string(), "( !2 ) P");
+    assert_eq!(Join(&p.template, " ").to_string(), "PI $0");
+    assert_eq!((p.pattern_end, p.template_end), (16, 26));
+    assert_eq!(p.matched, Some((29, vec![(26, 28)])));
+    assert!(!p.finishes);
+    assert!(p.rna.is_empty());
+    // Nothing has run.
+    assert_eq!(dna.len(), 30);
+  }
+
+  #[test]
+  fn commands() {
+    // The spec's first example, then three iterations that each emit one
+    // RNA.
+    let dna = Base::collect_from::<Rope<_>>(
+        &("IIPIPICPIICICIIFICCIFPPIICCFPC".to_string() + &"IIIPIPIIICIICIIC".repeat(3)));
+    let mut d = Debugger::new(DnaState::new(), dna);
+    assert_eq!(run(&mut d, "show"),
+               "Iteration 1\n  pattern  0..16: ( !2 ) P\n  template 16..26: PI $0\n  \
+                matches 26..29 with 1 groups\n");
+    assert_eq!(run(&mut d, "groups"), "  $0 26..28 (2 bases): CF\n");
+    assert_eq!(run(&mut d, "step"), "Done 1 iterations: 53 bases, 0 RNA\n");
+    assert_eq!(run(&mut d, "sh"), "Iteration 2\n  pattern  0..18: FPCI\n  \
+                                   emits 1 RNA while reading\n  template 18..21: \n  \
+                                   doesn't match\n");
+    assert_eq!(run(&mut d, "g"), "No match\n");
+    assert_eq!(run(&mut d, "step 2"), "Done 3 iterations: 16 bases, 2 RNA\n");
+    // An empty line repeats the last command.
+    assert_eq!(run(&mut d, ""), "Done 5 iterations: 0 bases, 3 RNA, finished\n");
+    assert_eq!(run(&mut d, "c"), "Done 5 iterations: 0 bases, 3 RNA, finished\n");
+    assert_eq!(run(&mut d, "rna 2"), "        1  PIPIIIC\n        2  PIPIIIC\n");
+    assert_eq!(run(&mut d, "show"), "Finished\n");
+    assert_eq!(run(&mut d, "step x"), "Bad number: invalid digit found in string\n");
+    assert_eq!(run(&mut d, "frob"), "Unknown command \"frob\"; try help\n");
+    assert!(!d.command("quit", &mut vec![]).unwrap());
+  }
+
+  #[test]
+  fn dna_provenance() {
+    // (!2) -> $0 I: the group moves and the literal is new.
+    let program = "IIPIPICPIICIIC".to_string() + "IPPPCIIC";
+    let dna = SourceBase::collect_from::<Rope<_>>(&(program + "CFIC"));
+    let mut d = Debugger::new(DnaState::new(), dna);
+    run(&mut d, "step");
+    let pad = |s: &str| format!("{:64}", s);
+    assert_eq!(run(&mut d, "dna"),
+               format!("        0  {}  @22\n        2  {}  made by @18\n        3  {}  @24\n",
+                       pad("CF"), pad("I"), pad("IC")));
+    assert_eq!(run(&mut d, "dna 1 2"),
+               format!("        1  {}  @23\n        2  {}  made by @18\n", pad("F"), pad("I")));
+  }
+
+  // Each iteration of this DNA emits one RNA and consumes its 16 bases.
+  fn emitters(rnas: &[&str]) -> Rope<SourceBase> {
+    SourceBase::collect_from(&rnas.iter().map(|r| format!("III{}IICIIC", r)).collect::<String>())
+  }
+
+  #[test]
+  fn code_breakpoints() {
+    let mut d = Debugger::new(DnaState::new(), emitters(&["PIPIIIC"; 3]));
+    assert_eq!(run(&mut d, "break 16"), "Breakpoint 1: pattern or template at @16\n");
+    assert_eq!(run(&mut d, "c"), "Stopped before iteration 2: breakpoint 1, the pattern \
+                                  starts at @16\nDone 1 iterations: 32 bases, 1 RNA\n");
+    // Carrying on doesn't stop at the same place again.
+    assert_eq!(run(&mut d, "c"), "Done 4 iterations: 0 bases, 3 RNA, finished\n");
+
+    let mut d = Debugger::new(DnaState::new(), emitters(&["PIPIIIC"; 3]));
+    run(&mut d, "b 29");
+    assert_eq!(run(&mut d, "step 5"), "Stopped before iteration 2: breakpoint 1, the \
+                                       template starts at @29\nDone 1 iterations: \
+                                       32 bases, 1 RNA\n");
+  }
+
+  #[test]
+  fn rna_breakpoints() {
+    let mut d = Debugger::new(DnaState::new(), emitters(&["PIPIIIC", "PIPIIIC", "PIPIIIP"]));
+    assert_eq!(run(&mut d, "break PIPIIIP"), "Breakpoint 1: emitting PIPIIIP\n");
+    assert_eq!(run(&mut d, "run 10"), "Stopped before iteration 3: breakpoint 1, emits \
+                                       PIPIIIP\nDone 2 iterations: 16 bases, 2 RNA\n");
+    assert_eq!(run(&mut d, "break PIP"), "An RNA is 7 bases\n");
+    assert_eq!(run(&mut d, "break PIPX"),
+               "Bad character 'X' at line 1, column 4 (offset 3)\n");
+
+    // Addresses need bases that carry them.
+    let mut d = Debugger::new(DnaState::new(), Base::collect_from::<Rope<_>>("IIIPIPIIICIIC"));
+    assert_eq!(run(&mut d, "break 3"), "Addresses need --provenance\n");
+    assert_eq!(run(&mut d, "break PIPIIIC"), "Breakpoint 1: emitting PIPIIIC\n");
+    assert_eq!(run(&mut d, "c"), "Stopped before iteration 1: breakpoint 1, emits \
+                                  PIPIIIC\nDone 0 iterations: 13 bases, 0 RNA\n");
+  }
+
+  #[test]
+  fn watchpoints() {
+    let mut d = Debugger::new(DnaState::new(), emitters(&["PIPIIIC", "PIPIIIC", "PIPIIIP"]));
+    assert_eq!(run(&mut d, "watch 35..37 emitted"), "Breakpoint 1: @35..37 emitted\n");
+    assert_eq!(run(&mut d, "c"), "Stopped before iteration 3: breakpoint 1, @35 is \
+                                  emitted\nDone 2 iterations: 16 bases, 2 RNA\n");
+    assert_eq!(run(&mut d, "w 40 consumed"), "Breakpoint 2: @40..41 consumed\n");
+    assert_eq!(run(&mut d, "list"), "   1  @35..37 emitted\n   2  @40..41 consumed\n");
+    assert_eq!(run(&mut d, "delete 1"), "Deleted breakpoint 1\n");
+    assert_eq!(run(&mut d, "delete 1"), "No breakpoint 1\n");
+    assert_eq!(run(&mut d, "watch 5 eaten"), "Can't watch for \"eaten\"\n");
+    assert_eq!(run(&mut d, "watch 5..5"), "No addresses to watch\n");
+    assert_eq!(run(&mut d, "delete"), "Deleted all breakpoints\n");
+    assert_eq!(run(&mut d, "l"), "No breakpoints\n");
+
+    // (!300) -> $0 $0, with 100 copies of ICFP after it.
+    let program = "IIPIPIICCICIICPIICIIC".to_string() + "IPPPIPPPIIC";
+    let dna = SourceBase::collect_from::<Rope<_>>(&(program + &"ICFP".repeat(100)));
+    let mut d = Debugger::new(DnaState::new(), dna.clone());
+    // Outside the group.
+    run(&mut d, "watch 400..500 copied");
+    assert_eq!(run(&mut d, "step"), "Done 1 iterations: 700 bases, 0 RNA\n");
+    let mut d = Debugger::new(DnaState::new(), dna);
+    assert_eq!(run(&mut d, "watch 100..200 copied"), "Breakpoint 1: @100..200 copied\n");
+    assert_eq!(run(&mut d, "step"), "Stopped before iteration 1: breakpoint 1, @100 at 100 \
+                                     is copied\nDone 0 iterations: 432 bases, 0 RNA\n");
+    assert_eq!(run(&mut d, "step"), "Done 1 iterations: 700 bases, 0 RNA\n");
+    run(&mut d, "delete");
+    run(&mut d, "watch 100..200");
+    // Now the group is read again at the front as code.
+    assert_eq!(run(&mut d, "step"), "Stopped before iteration 2: breakpoint 2, @100 at 68 \
+                                     is consumed\nDone 1 iterations: 700 bases, 0 RNA\n");
+
+    // (!4) -> nothing: the matched bases are consumed, though no group
+    // holds them.
+    let dna = SourceBase::collect_from::<Rope<_>>("IPIICPIICIICICFPICFP");
+    let mut d = Debugger::new(DnaState::new(), dna.clone());
+    run(&mut d, "watch 13 copied emitted");
+    assert_eq!(run(&mut d, "step"), "Done 1 iterations: 4 bases, 0 RNA\n");
+    let mut d = Debugger::new(DnaState::new(), dna);
+    run(&mut d, "watch 13");
+    assert_eq!(run(&mut d, "step"), "Stopped before iteration 1: breakpoint 1, @13 at 13 \
+                                     is consumed\nDone 0 iterations: 20 bases, 0 RNA\n");
+  }
+}
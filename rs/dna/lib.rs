@@ -1,13 +1,22 @@
-#[macro_use]
-extern crate lazy_static;
-
-use std::cmp::max;
+use std::cmp::{self, max};
 use std::collections::BTreeMap;
 use std::fmt;
+use std::io::{self, Write};
 use std::mem;
 use std::str::FromStr;
 use rope::*;
-use base::{Base, BaseLike, Join};
+use base::{pack_word, packed_word, Base, BaseLike, Crc, Join, PackedBases};
+
+mod checkpoint;
+mod debugger;
+mod load;
+mod naive;
+mod storage;
+pub use checkpoint::Checkpoint;
+pub use debugger::{Access, Breakpoint, Debugger, Preview};
+pub use load::load;
+pub use naive::{lockstep, Divergence, Naive};
+pub use storage::{Storage, StorageChunk, StorageCursor, VecCursor};
 
 // SourceMap:
 //  - keep track of when a base is used as a PItem, a TItem, an Emit,
@@ -24,10 +33,10 @@ use base::{Base, BaseLike, Join};
 // We're gonna end up with mixed-and-matched numbers on different skip
 // bases, inserted from various places... how to represent this?
 
-type Rna<T> = [T;7];
+pub type Rna<T> = [T;7];
 
-pub fn str<T: BaseLike>(dna: &Rope<T>) -> String {
-  dna.iter().map(|b| format!("{}", b)).collect::<String>()
+pub fn str<T: BaseLike, D: Storage<T>>(dna: &D) -> String {
+  dna.cursor().map(|b| format!("{}", b)).collect::<String>()
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -74,7 +83,7 @@ impl<T: BaseLike> FromStr for PItem<T> {
 }
 
 impl<T: BaseLike> Pattern<T> for PItem<T> {
-  fn exec<S: BaseLike>(&self, cursor: &mut RopeCursor<S>, env: &mut Env) -> bool {
+  fn exec<'a, S: BaseLike, C: StorageCursor<'a, S>>(&self, cursor: &mut C, env: &mut Env) -> bool {
     match self {
       PItem::OpenGroup => {
         env.starts.push(cursor.pos());
@@ -83,18 +92,14 @@ impl<T: BaseLike> Pattern<T> for PItem<T> {
         env.groups.push((env.starts.pop().unwrap(), cursor.pos()));
       }
       PItem::Bases(bases) => {
-        for b in bases {
-          if cursor.next().map(BaseLike::to_base) != Some(b.to_base()) {
-            return false;
-          }
-        }
+        if !match_bases(cursor, bases) { return false; }
       }
       PItem::Skip(i) => {
         if cursor.pos() + i > cursor.full_len() { return false; }
         cursor.skip(*i as isize);
       }
       PItem::Search(bs, ..) => {
-        match find(cursor, &bs, cursor.pos()) {
+        match cursor.search(bs, cursor.pos()) {
           Some(index) => { cursor.seek(index + bs.len()); }
           None => { return false; }
         }
@@ -103,7 +108,7 @@ impl<T: BaseLike> Pattern<T> for PItem<T> {
     true
   }
 
-  fn make_bases<S: State<T>>(cursor: &mut RopeCursor<T>, state: &mut S) -> Self {
+  fn make_bases<'a, S: State<T>, C: StorageCursor<'a, T>>(cursor: &mut C, state: &mut S) -> Self {
     let bases: Vec<T> = Bases::parse(cursor);
     if T::HAS_SOURCE {
       for base in bases.iter() {
@@ -113,13 +118,14 @@ impl<T: BaseLike> Pattern<T> for PItem<T> {
     PItem::Bases(bases)
   }
 
-  fn make_skip<S: State<T>>(cursor: &mut RopeCursor<T>, state: &mut S) -> Option<Self> {
+  fn make_skip<'a, S: State<T>, C: StorageCursor<'a, T>>(cursor: &mut C, state: &mut S)
+                                                         -> Option<Self> {
     cursor.skip(2);
     state.record_num(cursor);
     usize::parse(cursor).map(PItem::Skip)
   }
 
-  fn make_search<S: State<T>>(cursor: &mut RopeCursor<T>, state: &mut S) -> Self {
+  fn make_search<'a, S: State<T>, C: StorageCursor<'a, T>>(cursor: &mut C, state: &mut S) -> Self {
     cursor.skip(3);
     let bases: Vec<T> = Bases::parse(cursor);
     if T::HAS_SOURCE {
@@ -130,12 +136,12 @@ impl<T: BaseLike> Pattern<T> for PItem<T> {
     PItem::Search(bases)
   }
 
-  fn make_open(cursor: &mut RopeCursor<T>) -> Self {
+  fn make_open<'a, C: StorageCursor<'a, T>>(cursor: &mut C) -> Self {
     cursor.skip(3);
     PItem::OpenGroup
   }
 
-  fn make_close(cursor: &mut RopeCursor<T>) -> Self {
+  fn make_close<'a, C: StorageCursor<'a, T>>(cursor: &mut C) -> Self {
     cursor.skip(3);
     PItem::CloseGroup
   }
@@ -144,20 +150,21 @@ impl<T: BaseLike> Pattern<T> for PItem<T> {
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum TItem<T: BaseLike> {
-  Bases(Vec<T>),
-  // Also stores the index where we found it, for debugging...?
-  Len(usize),
-  Ref{group: usize, level: usize},
+  // Each item also stores its own address, which the bases it creates
+  // inherit.
+  Bases{bases: Vec<T>, addr: u32},
+  Len{group: usize, addr: u32},
+  Ref{group: usize, level: usize, addr: u32},
 }
 
 impl<T: BaseLike> fmt::Display for TItem<T> {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     match self {
-      TItem::Bases(v) => write!(f, "{}", Join(v, "")),
-      TItem::Len(i) => write!(f, "|{}|", i),
-      TItem::Ref{group, level} => {
+      TItem::Bases{bases, ..} => write!(f, "{}", Join(bases, "")),
+      TItem::Len{group, ..} => write!(f, "|{}|", group),
+      TItem::Ref{group, level, ..} => {
         if *level < 5 {
-          write!(f, "${}{}", "\\".repeat(*level as usize), group)
+          write!(f, "${}{}", "\\".repeat(*level), group)
         } else {
           write!(f, "${}\\{}", level, group)
         }
@@ -172,22 +179,22 @@ impl<T: BaseLike> FromStr for TItem<T> {
     let v = s.as_bytes();
     match (v[0], v.len()) {
       (b'I', ..)|(b'C', ..)|(b'F', ..)|(b'P', ..) => {
-        Ok(TItem::Bases(T::collect_from(s)))
+        Ok(TItem::Bases{bases: T::collect_from(s), addr: 0})
       }
       (b'|', _) if v[v.len() - 1] == b'|' => {
         match s[1..v.len()-1].parse::<usize>() {
-          Ok(i) => Ok(TItem::Len(i)),
+          Ok(group) => Ok(TItem::Len{group, addr: 0}),
           Err(_) => Err(()),
         }
       },
       (b'$', _) => {
         // TODO - parse $6\0 format?
         let mut level: usize = 0;
-        while v[1 + level as usize] == b'\\' {
+        while v[1 + level] == b'\\' {
           level += 1;
         }
-        match s[level as usize..].parse::<usize>() {
-          Ok(group) => Ok(TItem::Ref{group, level}),
+        match s[level..].parse::<usize>() {
+          Ok(group) => Ok(TItem::Ref{group, level, addr: 0}),
           Err(_) => Err(()),
         }
       },
@@ -196,40 +203,142 @@ impl<T: BaseLike> FromStr for TItem<T> {
   }
 }
 
-fn as_nat<T: BaseLike>(mut i: usize) -> Vec<T> {
+fn as_nat<T: BaseLike>(mut i: usize, addr: u32) -> Vec<T> {
   let mut v: Vec<T> = vec![];
   while i > 0 {
-    // TODO - keep address from op, set level to -32
     v.push(match i & 1 {
-      0 => T::from_parts(Base::I, 0, -32),
-      1 => T::from_parts(Base::C, 0, -32),
+      0 => T::synthesized(Base::I, addr),
+      1 => T::synthesized(Base::C, addr),
       _ => unreachable!(),
     });
     i >>= 1;
   }
-  v.push(T::from_parts(Base::P, 0, -32));
+  v.push(T::synthesized(Base::P, addr));
   v
 }
 
+// Unescaped references shorter than this are copied, since a slice costs
+// a few nodes and fragments the leaves.
+const SHARE_THRESHOLD: usize = 256;
+
+// The result of expanding a template.  Literal and escaped bases are
+// gathered into a buffer, while long unescaped references are kept as
+// slices of the DNA so that they share its leaves rather than copying.
+// Bases that the template creates are stamped with the iteration.
+pub struct Expansion<T: BaseLike, D: Storage<T> = Rope<T>> {
+  rope: D,
+  buf: Vec<T>,
+  stamp: u32,
+}
+
+impl<T: BaseLike, D: Storage<T>> Expansion<T, D> {
+  pub fn new(stamp: u32) -> Self {
+    Expansion{rope: D::default(), buf: Vec::new(), stamp}
+  }
+
+  pub fn len(&self) -> usize {
+    self.rope.len() + self.buf.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn buf(&mut self) -> &mut Vec<T> {
+    &mut self.buf
+  }
+
+  // Attributes the bases buffered since `start` to the template item at
+  // `addr`, which created them.
+  pub fn synthesize_from(&mut self, start: usize, addr: u32) {
+    for b in &mut self.buf[start ..] {
+      *b = T::synthesized(b.to_base(), addr);
+    }
+  }
+
+  // Stamps the bases buffered since `start`, which the template created
+  // rather than moved.
+  pub fn stamp_from(&mut self, start: usize) {
+    let stamp = self.stamp;
+    for b in &mut self.buf[start ..] {
+      *b = b.stamped(stamp);
+    }
+  }
+
+  pub fn append_rope(&mut self, rope: D) {
+    self.flush();
+    self.rope.append(rope);
+  }
+
+  fn flush(&mut self) {
+    if !self.buf.is_empty() {
+      let buf = mem::take(&mut self.buf);
+      self.rope.append(D::from_vec(buf));
+    }
+  }
+
+  // Replaces `len` bases of `dna` at `start` with the expansion.  When
+  // nothing was shared, this is an ordinary splice.
+  pub fn splice_into(mut self, dna: &mut D, start: usize, len: usize) {
+    if self.rope.is_empty() {
+      let insert = if self.buf.is_empty() { None } else { Some(self.buf) };
+      dna.splice(start, len, insert);
+    } else {
+      self.flush();
+      dna.splice_from(start, len, self.rope);
+    }
+  }
+}
+
+impl<T: BaseLike, D: Storage<T>> Default for Expansion<T, D> {
+  fn default() -> Self { Expansion::new(0) }
+}
+
 impl<T: BaseLike> Template<T> for TItem<T> {
-  fn expand(&self, out: &mut Vec<T>, env: &[(usize, usize)],
-            cursor: &mut RopeCursor<T>) {
+  fn expand<'a, C: StorageCursor<'a, T>>(&self, out: &mut Expansion<T, C::Storage>,
+                                         env: &[(usize, usize)], cursor: &mut C) {
     match self {
-      TItem::Bases(v) => {
-        out.extend(v);
+      TItem::Bases{bases, addr} => {
+        let start = out.buf().len();
+        out.buf().extend(bases);
+        out.synthesize_from(start, *addr);
+        out.stamp_from(start);
       }
-      TItem::Len(i) => {
-        if *i < env.len() {
-          out.extend(as_nat::<T>(env[*i].1 - env[*i].0));
+      TItem::Len{group, addr} => {
+        let start = out.buf().len();
+        if *group < env.len() {
+          out.buf().extend(as_nat::<T>(env[*group].1 - env[*group].0, *addr));
         } else {
-          out.push(T::from_base(Base::P));
+          out.buf().push(T::synthesized(Base::P, *addr));
+        }
+        out.stamp_from(start);
+      }
+      TItem::Ref{group, level: 0, ..} => {
+        if *group < env.len() {
+          let (start, end) = env[*group];
+          if end - start >= SHARE_THRESHOLD {
+            out.append_rope(cursor.root().slice(start .. end));
+          } else {
+            cursor.copy_range(start .. end, out.buf());
+          }
         }
       }
-      TItem::Ref{group, level} => {
+      TItem::Ref{group, level, addr} => {
         if *group < env.len() {
-          for i in env[*group].0 .. env[*group].1 {
-            cursor.at(i).protect(*level as u8, out);
+          let (start, end) = env[*group];
+          let first = out.buf().len();
+          let mut pos = start;
+          while pos < end {
+            let chunk = cursor.chunk_at(pos).unwrap();
+            let n = cmp::min(chunk.len(), end - pos);
+            let mut hint = 0;
+            for k in 0 .. n {
+              chunk.at_hint(k, &mut hint).protect(*level as u8, out.buf());
+            }
+            pos += n;
           }
+          out.synthesize_from(first, *addr);
+          out.stamp_from(first);
         }
       }
     }
@@ -238,29 +347,34 @@ impl<T: BaseLike> Template<T> for TItem<T> {
   // This is necessary for finding splice points.
   fn as_unprotected_group(&self) -> Option<usize> {
     match self {
-      TItem::Ref{group, level} if *level == 0 => Some(*group as usize),
+      TItem::Ref{group, level: 0, ..} => Some(*group),
       _ => None,
     }
   }
 
 
-  fn make_bases(cursor: &mut RopeCursor<T>) -> Self {
-    TItem::Bases(Bases::parse(cursor))
+  fn make_bases<'a, C: StorageCursor<'a, T>>(cursor: &mut C) -> Self {
+    let addr = cursor.at(cursor.pos()).addr().unwrap_or(0);
+    TItem::Bases{bases: Bases::parse(cursor), addr}
   }
 
-  fn make_len<S: State<T>>(cursor: &mut RopeCursor<T>, state: &mut S) -> Option<Self> {
+  fn make_len<'a, S: State<T>, C: StorageCursor<'a, T>>(cursor: &mut C, state: &mut S)
+                                                        -> Option<Self> {
+    let addr = cursor.at(cursor.pos()).addr().unwrap_or(0);
     cursor.skip(3);
     state.record_num(cursor);
-    usize::parse(cursor).map(TItem::Len)
+    usize::parse(cursor).map(|group| TItem::Len{group, addr})
   }
 
-  fn make_ref<S: State<T>>(cursor: &mut RopeCursor<T>, state: &mut S) -> Option<Self> {
+  fn make_ref<'a, S: State<T>, C: StorageCursor<'a, T>>(cursor: &mut C, state: &mut S)
+                                                        -> Option<Self> {
+    let addr = cursor.at(cursor.pos()).addr().unwrap_or(0);
     cursor.skip(2);
     state.record_num(cursor);
     if let Some(level) = usize::parse(cursor) {
       state.record_num(cursor);
       if let Some(group) = usize::parse(cursor) {
-        return Some(TItem::Ref{group, level});
+        return Some(TItem::Ref{group, level, addr});
       }
     }
     None
@@ -268,6 +382,7 @@ impl<T: BaseLike> Template<T> for TItem<T> {
 }
 
 #[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
 enum OpCode {
   C,
   F,
@@ -282,7 +397,7 @@ enum OpCode {
   Invalid,
 }
 
-fn next_op<T: BaseLike>(cursor: &mut RopeCursor<T>) -> OpCode {
+fn next_op<'a, T: BaseLike, C: StorageCursor<'a, T>>(cursor: &mut C) -> OpCode {
   let i = cursor.pos();
   match cursor.try_at(i).map(BaseLike::to_base) {
     None => OpCode::Invalid,
@@ -315,17 +430,17 @@ pub struct Env {
 
 pub trait Num<T: BaseLike>: Sized {
   // None means finish
-  fn parse(cursor: &mut RopeCursor<T>) -> Option<Self>;
+  fn parse<'a, C: StorageCursor<'a, T>>(cursor: &mut C) -> Option<Self>;
 }
 pub trait Bases<T: BaseLike>: Sized {
-  fn parse(cursor: &mut RopeCursor<T>) -> Self;
+  fn parse<'a, C: StorageCursor<'a, T>>(cursor: &mut C) -> Self;
 }
 
 impl<T: BaseLike> Num<T> for usize {
-  fn parse(cursor: &mut RopeCursor<T>) -> Option<Self> {
+  fn parse<'a, C: StorageCursor<'a, T>>(cursor: &mut C) -> Option<Self> {
     let mut v: usize = 0;
     let mut mask: usize = 1;
-    while let Some(base) = cursor.next() {
+    for base in cursor.by_ref() {
       match base.to_base() {
         Base::C => { v |= mask; }
         Base::P => { return Some(v); }
@@ -338,30 +453,43 @@ impl<T: BaseLike> Num<T> for usize {
 }
 
 impl<T: BaseLike> Bases<T> for Vec<T> {
-  fn parse(cursor: &mut RopeCursor<T>) -> Self {
+  fn parse<'a, C: StorageCursor<'a, T>>(cursor: &mut C) -> Self {
     let mut v: Vec<T> = vec![];
-    loop {
-      let p = cursor.peek();
-      if p.is_none() { return v; }
-      if p.unwrap().to_base() == Base::I {
-        if cursor.try_at(cursor.pos() + 1).map(BaseLike::to_base) == Some(Base::C) {
-          v.push(p.unwrap().unprotect());
-          cursor.skip(2);
+    let mut pos = cursor.pos();
+    while let Some(chunk) = cursor.chunk_at(pos) {
+      let mut k = 0;
+      let mut hint = 0;
+      while k < chunk.len() {
+        let b = chunk.at_hint(k, &mut hint);
+        if b.to_base() == Base::I {
+          // IC may straddle two leaves.
+          let next = if k + 1 < chunk.len() {
+            Some(chunk.at_hint(k + 1, &mut hint))
+          } else {
+            cursor.try_at(pos + k + 1)
+          };
+          if next.map(BaseLike::to_base) != Some(Base::C) {
+            cursor.seek(pos + k);
+            return v;
+          }
+          v.push(b.unprotect());
+          k += 2;
         } else {
-          return v;
+          v.push(b.unprotect());
+          k += 1;
         }
-      } else {
-        v.push(p.unwrap().unprotect());
-        cursor.skip(1);
       }
-    }      
+      pos += k;
+    }
+    cursor.seek(pos);
+    v
   }
 }
 
 pub trait State<T: BaseLike> {
   fn new() -> Self;
 
-  fn emit(&mut self, cursor: &mut RopeCursor<T>);
+  fn emit<'a, C: StorageCursor<'a, T>>(&mut self, cursor: &mut C);
   fn finish(&mut self);
 
   fn finished(&self) -> bool;
@@ -373,15 +501,19 @@ pub trait State<T: BaseLike> {
     o
   }
 
-  fn iterate(&mut self, dna: &mut Rope<T>);
+  fn iterate<D: Storage<T>>(&mut self, dna: &mut D);
 
+  // What to stamp newly created bases with.
   #[inline]
-  fn record_splice(&mut self, _dna: &Rope<T>, _pos: u32) {}
+  fn stamp(&self) -> u32 { 0 }
+
   #[inline]
-  fn record_usage(&mut self, _cursor: &mut RopeCursor<T>,
-                  _pos: u32, _usage: Usage) {}
+  fn record_splice<D: Storage<T>>(&mut self, _dna: &D, _pos: u32) {}
   #[inline]
-  fn record_num(&mut self, _cursor: &mut RopeCursor<T>) {}
+  fn record_usage<'a, C: StorageCursor<'a, T>>(&mut self, _cursor: &mut C,
+                                          _pos: u32, _usage: Usage) {}
+  #[inline]
+  fn record_num<'a, C: StorageCursor<'a, T>>(&mut self, _cursor: &mut C) {}
   #[inline]
   fn record_pat_base(&mut self, _base: T) {}
   #[inline]
@@ -423,6 +555,12 @@ pub enum Usage {
 }
 
 impl Usage {
+  fn from_u8(i: u8) -> Option<Self> {
+    if i > Usage::RnaBaseP as u8 { return None; }
+    unsafe {
+      Some(mem::transmute::<u8, Usage>(i))
+    }
+  }
   fn rna_base<T: BaseLike>(base: T) -> Self {
     unsafe {
       mem::transmute::<u8, Usage>(base.to_u2() + Usage::RnaBaseI as u8)
@@ -441,11 +579,17 @@ impl Usage {
 }
 
 pub struct DnaState<T: BaseLike> {
-  pub print: bool,
+  // Where to write each RNA as it's emitted, if anywhere.
+  pub out: Option<Box<dyn Write>>,
+  // The first error writing to `out`, after which it is dropped.
+  pub error: Option<io::Error>,
   pub print_verbose: bool,
+  // Whether to record coverage stats (only meaningful for bases that
+  // carry a source address).
+  pub provenance: bool,
   pub iters: u32,
   //pub coverage: Option<BTreeMap<usize, CoverageStat>>,
-  pub coverage: BTreeMap<(usize, i8), Stat>,
+  pub coverage: BTreeMap<Addr, Stat>,
   finished: bool,
   rna: Vec<Rna<T>>,
 }
@@ -474,8 +618,20 @@ impl Stat {
   }
 }
 
-fn dump_num(coverage: &BTreeMap<(usize, i8), Stat>, addr: usize, lvl: i8)
-            -> Option<(usize, Vec<(usize, i8)>, usize)> {
+pub type Addr = (usize, i32);
+
+// Synthesized bases are covered at this level, under the address of the
+// template item that produced them.
+pub const SYNTHESIZED_LEVEL: i32 = -32;
+
+// Where a base came from, for coverage.
+fn source<T: BaseLike>(base: T) -> Option<Addr> {
+  let level = if base.is_synthesized() { SYNTHESIZED_LEVEL } else { base.level()? };
+  Some((base.addr()? as usize, level))
+}
+
+fn dump_num(coverage: &BTreeMap<Addr, Stat>, addr: usize, lvl: i32)
+            -> Option<(usize, Vec<Addr>, usize)> {
   let mut i = addr;
   let mut v = 0;
   let mut mask: usize = 1;
@@ -496,7 +652,13 @@ fn dump_num(coverage: &BTreeMap<(usize, i8), Stat>, addr: usize, lvl: i8)
 }
 
 impl<T: BaseLike> DnaState<T> {
-  pub fn source_dump(&self, addr: usize, lvl: i8) -> (String, Vec<(usize, i8)>) {
+  // How many iterations ago a base was created (counting the original
+  // DNA as created before the first), if the engine stamps bases.
+  pub fn age(&self, base: T) -> Option<u32> {
+    Some(self.iters - base.stamp()?)
+  }
+
+  pub fn source_dump(&self, addr: usize, lvl: i32) -> (String, Vec<Addr>) {
     let mut seen = vec![(addr, lvl)];
     let mut s = String::new();
     if let Some(stat) = self.coverage.get(&(addr, lvl)) {
@@ -546,7 +708,7 @@ impl<T: BaseLike> DnaState<T> {
               _ => { break; }
             }
           }
-          s.push_str(">");
+          s.push('>');
         }
         Usage::PatOpen => { s.push('('); }
         Usage::PatClose => { s.push(')'); }
@@ -646,29 +808,40 @@ impl<T: BaseLike> DnaState<T> {
 impl<T: BaseLike> State<T> for DnaState<T> {
   fn new() -> Self {
     DnaState{finished: false, rna: Vec::new(),
-             print: false, print_verbose: false, iters: 0,
+             out: None, error: None, print_verbose: false, iters: 0,
+             provenance: T::HAS_SOURCE,
              coverage: BTreeMap::new(),
     }
   }
-  fn emit(&mut self, c: &mut RopeCursor<T>) {
+  fn emit<'a, C: StorageCursor<'a, T>>(&mut self, c: &mut C) {
     let i = c.pos() + 3;
     c.skip(10);
-    if c.at_end() { return; }
+    // An RNA may end right at the end of the DNA, but one cut short is
+    // dropped.
+    if c.pos() > c.full_len() { return; }
     let rna = [c.at(i), c.at(i + 1), c.at(i + 2), c.at(i + 3),
                c.at(i + 4), c.at(i + 5), c.at(i + 6)];
     self.record_rna(rna);
     self.rna.push(rna);
-    let rna_str = &rna.map(|b| b.to_base().char()).iter().collect::<String>();
-    if self.print {
-      if self.print_verbose {
+    if let Some(out) = self.out.as_mut() {
+      let rna_str = &rna.map(|b| b.to_base().char()).iter().collect::<String>();
+      let result = if self.print_verbose {
         let addr = match (rna[0].addr(), rna[0].level()) {
-          (Some(a), Some(l)) if l == 0 => format!(" @{}", a),
+          (Some(a), Some(0)) => format!(" @{}", a),
           (Some(a), Some(l)) => format!(" @{} \\{}", a, l),
           _ => String::new(),
         };
-        println!("{} # iter {}{}", rna_str, self.iters, addr);
+        let stamp = match rna[0].stamp() {
+          Some(s) if s > 0 => format!(" from iter {}", s),
+          _ => String::new(),
+        };
+        writeln!(out, "{} # iter {}{}{}", rna_str, self.iters, addr, stamp)
       } else {
-        println!("{}", rna_str);
+        writeln!(out, "{}", rna_str)
+      };
+      if let Err(e) = result {
+        self.error = Some(e);
+        self.out = None;
       }
     }
   }
@@ -681,8 +854,11 @@ impl<T: BaseLike> State<T> for DnaState<T> {
   fn rna(&self) -> &[Rna<T>] {
     &self.rna
   }
+  fn stamp(&self) -> u32 {
+    self.iters
+  }
 
-  fn iterate(&mut self, dna: &mut Rope<T>) {
+  fn iterate<D: Storage<T>>(&mut self, dna: &mut D) {
     // TODO - find a way to parametrize on Pattern and Template.
     //eprintln!("Iterate: {}", str(&dna));
     self.iters += 1;
@@ -697,6 +873,7 @@ impl<T: BaseLike> State<T> for DnaState<T> {
     if self.finished() { return; }
     //eprintln!("Tpl: {}", Join(&tpl, " "));
     let template_end = cursor.pos();
+    drop(cursor);
 
     // if let Some(addr) = addr {
     //   let mut cmap = self.coverage.as_mut().unwrap();
@@ -716,38 +893,27 @@ impl<T: BaseLike> State<T> for DnaState<T> {
     match_replace(dna, &pat, &tpl, template_end, self);
   }
 
-  fn record_splice(&mut self, dna: &Rope<T>, pos: u32) {
-    if !T::HAS_SOURCE { return; }
+  fn record_splice<D: Storage<T>>(&mut self, dna: &D, pos: u32) {
+    if !self.tracking() { return; }
     let pos = pos as usize;
     if pos == 0 || pos >= dna.len() { return; }
     let mut c = dna.cursor();
-    let left = c.at(pos - 1);
-    let right = c.at(pos);
-    let left_addr = left.addr().unwrap() as usize;
-    let left_level = left.level().unwrap();
-    let right_addr = right.addr().unwrap() as usize;
-    let right_level = right.level().unwrap();
-    if left_level != -32 {
-      self.coverage.entry((left_addr, left_level))
-          .or_insert_with(Stat::new).record_splice();
-    }
-    if right_level != -32 {
-      self.coverage.entry((right_addr, right_level))
-          .or_insert_with(Stat::new).record_splice();
+    for base in [c.at(pos - 1), c.at(pos)] {
+      if let Some(addr) = source(base) {
+        self.coverage.entry(addr).or_insert_with(Stat::new).record_splice();
+      }
     }
   }
-  fn record_usage(&mut self, cursor: &mut RopeCursor<T>,
-                  pos: u32, usage: Usage) {
-    if !T::HAS_SOURCE { return; }
+  fn record_usage<'a, C: StorageCursor<'a, T>>(&mut self, cursor: &mut C,
+                                          pos: u32, usage: Usage) {
+    if !self.tracking() { return; }
     let pos = pos as usize;
     if pos >= cursor.full_len() { return; }
     let base = cursor.at(pos);
-    let addr = base.addr().unwrap();
-    let level = base.level().unwrap();
-    self.record(addr, level, usage);
+    self.record(base, usage);
   }
-  fn record_num(&mut self, cursor: &mut RopeCursor<T>) {
-    if !T::HAS_SOURCE { return; }
+  fn record_num<'a, C: StorageCursor<'a, T>>(&mut self, cursor: &mut C) {
+    if !self.tracking() { return; }
     for pos in cursor.pos() .. cursor.full_len() {
       let base = cursor.at(pos).to_u2();
       if base == 3 {
@@ -761,35 +927,34 @@ impl<T: BaseLike> State<T> for DnaState<T> {
   }
 
   fn record_pat_base(&mut self, base: T) {
-    if !T::HAS_SOURCE { return; }
-    let addr = base.addr().unwrap();
-    let level = base.level().unwrap();
-    self.record(addr, level, Usage::pat_base(base));
+    if !self.tracking() { return; }
+    self.record(base, Usage::pat_base(base));
   }
 
   fn record_search_base(&mut self, base: T) {
-    if !T::HAS_SOURCE { return; }
-    let addr = base.addr().unwrap();
-    let level = base.level().unwrap();
-    self.record(addr, level, Usage::search_base(base));
+    if !self.tracking() { return; }
+    self.record(base, Usage::search_base(base));
   }
 }
 
 impl<T: BaseLike> DnaState<T> {
-  fn record(&mut self, addr: u32, level: i8, usage: Usage) {
-    if level != -32 {
-      self.coverage.entry((addr as usize, level))
+  #[inline]
+  fn tracking(&self) -> bool {
+    T::HAS_SOURCE && self.provenance
+  }
+
+  fn record(&mut self, base: T, usage: Usage) {
+    if let Some(addr) = source(base) {
+      self.coverage.entry(addr)
           .or_insert_with(Stat::new)
           .record_usage(self.iters, usage);
     }
   }
 
   fn record_rna(&mut self, bases: [T;7]) {
-    if !T::HAS_SOURCE { return; }
+    if !self.tracking() { return; }
     for base in bases {
-      let addr = base.addr().unwrap();
-      let level = base.level().unwrap();
-      self.record(addr, level, Usage::rna_base(base));
+      self.record(base, Usage::rna_base(base));
     }
   }
 
@@ -807,14 +972,15 @@ impl<T: BaseLike> DnaState<T> {
 //         source_len: 
 }
 
-fn match_replace<T: BaseLike, S: State<T>>(dna: &mut Rope<T>, pat: &[PItem<T>],
-                                           tpl: &[TItem<T>], start: usize,
-                                           state: &mut S) {
+fn match_replace<T: BaseLike, D: Storage<T>, S: State<T>>(dna: &mut D, pat: &[PItem<T>],
+                                                         tpl: &[TItem<T>], start: usize,
+                                                         state: &mut S) {
   let mut cursor = dna.cursor();
   cursor.seek(start);
   let mut env = Env{starts: vec![], groups: vec![]};
   for p in pat {
     if !p.exec(&mut cursor, &mut env) {
+      drop(cursor);
       dna.splice(0, start, None);
 //eprintln!("No match: splicing to {}", str(&dna));
 //eprintln!("No match: splicing {}", start);
@@ -826,32 +992,32 @@ fn match_replace<T: BaseLike, S: State<T>>(dna: &mut Rope<T>, pat: &[PItem<T>],
   let splice_plan = find_splice(tpl, &env, (0, cursor.pos()));
   let splices = splice_plan.iter()
     .map(|(r, t)| {
-      let mut v: Vec<T> = Vec::new();
+      let mut expansion = Expansion::new(state.stamp());
       for item in *t {
-        item.expand(&mut v, &env, &mut cursor);
+        item.expand(&mut expansion, &env, &mut cursor);
       }
-      (r, v)
+      (r, expansion)
     }).collect::<Vec<_>>();
+  drop(cursor);
 // TODO - still need to verify that this is correct
-//eprintln!("Splices: {:?}", splices);
-  for ((start, end), bases) in splices {
-    let len = bases.len();
-    let insert = if len > 0 { Some(bases) } else { None };
-    dna.splice(*start, end - start, insert);
+  for ((start, end), expansion) in splices {
+    let len = expansion.len();
+    expansion.splice_into(dna, *start, end - start);
     state.record_splice(dna, *start as u32);
     state.record_splice(dna, (start + len) as u32);
   }
 }
 
 pub trait Pattern<T: BaseLike>: Sized {
-  fn exec<S: BaseLike>(&self, cursor: &mut RopeCursor<S>, env: &mut Env) -> bool;
-  fn make_bases<S: State<T>>(cursor: &mut RopeCursor<T>, state: &mut S) -> Self;
-  fn make_skip<S: State<T>>(cursor: &mut RopeCursor<T>, state: &mut S) -> Option<Self>;
-  fn make_search<S: State<T>>(cursor: &mut RopeCursor<T>, state: &mut S) -> Self;
-  fn make_open(cursor: &mut RopeCursor<T>) -> Self;
-  fn make_close(cursor: &mut RopeCursor<T>) -> Self;
-  fn parse_item<S: State<T>>(cursor: &mut RopeCursor<T>, depth: &mut usize,
-                        state: &mut S) -> Option<Self> {
+  fn exec<'a, S: BaseLike, C: StorageCursor<'a, S>>(&self, cursor: &mut C, env: &mut Env) -> bool;
+  fn make_bases<'a, S: State<T>, C: StorageCursor<'a, T>>(cursor: &mut C, state: &mut S) -> Self;
+  fn make_skip<'a, S: State<T>, C: StorageCursor<'a, T>>(cursor: &mut C, state: &mut S)
+                                                         -> Option<Self>;
+  fn make_search<'a, S: State<T>, C: StorageCursor<'a, T>>(cursor: &mut C, state: &mut S) -> Self;
+  fn make_open<'a, C: StorageCursor<'a, T>>(cursor: &mut C) -> Self;
+  fn make_close<'a, C: StorageCursor<'a, T>>(cursor: &mut C) -> Self;
+  fn parse_item<'a, S: State<T>, C: StorageCursor<'a, T>>(cursor: &mut C, depth: &mut usize,
+                                                          state: &mut S) -> Option<Self> {
     let next = next_op(cursor);
     match next {
       OpCode::Invalid => { state.finish(); None }
@@ -891,8 +1057,8 @@ pub trait Pattern<T: BaseLike>: Sized {
     }
   }
 
-  fn parse<S: State<T>>(cursor: &mut RopeCursor<T>,
-                        state: &mut S) -> Vec<Self> {
+  fn parse<'a, S: State<T>, C: StorageCursor<'a, T>>(cursor: &mut C,
+                                                     state: &mut S) -> Vec<Self> {
     let mut v: Vec<Self> = Vec::new();
     let mut depth: usize = 0;
     while let Some(item) = Self::parse_item(cursor, &mut depth, state) {
@@ -909,16 +1075,19 @@ pub trait Pattern<T: BaseLike>: Sized {
 // }
 
 pub trait Template<T: BaseLike>: Sized {
-  fn expand(&self, vec: &mut Vec<T>, env: &[(usize, usize)], cursor: &mut RopeCursor<T>);
+  fn expand<'a, C: StorageCursor<'a, T>>(&self, out: &mut Expansion<T, C::Storage>,
+                                         env: &[(usize, usize)], cursor: &mut C);
   // This is necessary for finding splice points.
   fn as_unprotected_group(&self) -> Option<usize>;
 
-  fn make_bases(cursor: &mut RopeCursor<T>) -> Self;
-  fn make_len<S: State<T>>(cursor: &mut RopeCursor<T>, state: &mut S) -> Option<Self>;
-  fn make_ref<S: State<T>>(cursor: &mut RopeCursor<T>, state: &mut S) -> Option<Self>;
+  fn make_bases<'a, C: StorageCursor<'a, T>>(cursor: &mut C) -> Self;
+  fn make_len<'a, S: State<T>, C: StorageCursor<'a, T>>(cursor: &mut C, state: &mut S)
+                                                        -> Option<Self>;
+  fn make_ref<'a, S: State<T>, C: StorageCursor<'a, T>>(cursor: &mut C, state: &mut S)
+                                                        -> Option<Self>;
 
-  fn parse_item<S: State<T>>(cursor: &mut RopeCursor<T>,
-                             state: &mut S) -> Option<Self> {
+  fn parse_item<'a, S: State<T>, C: StorageCursor<'a, T>>(cursor: &mut C,
+                                                          state: &mut S) -> Option<Self> {
     let next = next_op(cursor);
     match next {
       OpCode::Invalid => { state.finish(); None }
@@ -948,8 +1117,8 @@ pub trait Template<T: BaseLike>: Sized {
     }
   }
 
-  fn parse<S: State<T>>(cursor: &mut RopeCursor<T>,
-                        state: &mut S) -> Vec<Self> {
+  fn parse<'a, S: State<T>, C: StorageCursor<'a, T>>(cursor: &mut C,
+                                                     state: &mut S) -> Vec<Self> {
     let mut v: Vec<Self> = Vec::new();
     while let Some(item) = Self::parse_item(cursor, state) {
       v.push(item);
@@ -986,7 +1155,7 @@ fn find_splice<'a, T: BaseLike>(tpl: &'a [TItem<T>], env: &[Rng], range: Rng)
 //eprintln!("  internal: {:?} {:?} {:?}", range, rest, tpl_r);
     let rest: Vec<(usize, Rng)> =
         rest.iter().filter(|(_, r)| r.0 >= range.0 && r.1 <= range.1)
-        .map(|x| *x).collect();
+        .copied().collect();
     let option_i = rest.iter().enumerate().max_by_key(|(_, (_, r))| r.1 - r.0);
 
     if let Some((i1, (i2, r))) = option_i {
@@ -1010,31 +1179,58 @@ fn find_splice<'a, T: BaseLike>(tpl: &'a [TItem<T>], env: &[Rng], range: Rng)
   // splice everything RIGHT TO LEFT to keep indexes correct.
 }
 
-lazy_static!(
-  static ref CRC_TABLE: [u32; 256] = make_crc_table();
-);
-fn make_crc_table() -> [u32; 256] {
-  let mut table: [u32; 256] = [0; 256];
-  for i in 0..256 {
-    let mut c: u32 = i;
-    for _ in 0..8 {
-      c = if (c & 1) != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+// Checksum of the bases, as stored in packed DNA files.
+pub fn crc<T: BaseLike, D: Storage<T>>(dna: &D) -> u32 {
+  let mut crc = Crc::new();
+  let mut cursor = dna.cursor();
+  let mut pos = 0;
+  while let Some(chunk) = cursor.chunk_at(pos) {
+    match chunk.packed() {
+      Some((bytes, start)) => crc.update_packed(bytes, start .. start + chunk.len()),
+      None => {
+        for k in 0 .. chunk.len() {
+          crc.update(chunk.at(k).to_base());
+        }
+      }
     }
-    table[i as usize] = c;
+    pos += chunk.len();
   }
-  return table;
+  crc.sum()
 }
-pub fn crc<T: BaseLike>(rope: &Rope<T>) -> u32 {
-  let mut crc: u32 = 0xffffffff;
-  let mut cursor = rope.cursor();
-  while let Some(b) = cursor.next() {
-    crc = (crc >> 8) ^ (*CRC_TABLE)[((crc ^ b.to_u2() as u32) & 0xff) as usize];
+
+// Whether the DNA at the cursor starts with `bases`, moving past them if
+// so.  Packed leaves are compared a word at a time.
+fn match_bases<'a, T: BaseLike, S: BaseLike, C: StorageCursor<'a, S>>(cursor: &mut C,
+                                                                     bases: &[T]) -> bool {
+  let mut pos = cursor.pos();
+  let mut j = 0;
+  while j < bases.len() {
+    let chunk = match cursor.chunk_at(pos) {
+      Some(chunk) => chunk,
+      None => { return false; }
+    };
+    let n = cmp::min(chunk.len(), bases.len() - j);
+    if let Some((bytes, start)) = chunk.packed() {
+      for k in (0 .. n).step_by(32) {
+        let m = cmp::min(32, n - k);
+        if packed_word(bytes, start + k, m) != pack_word(&bases[j + k .. j + k + m]) {
+          return false;
+        }
+      }
+    } else if (0 .. n).any(|k| chunk.at(k).to_u2() != bases[j + k].to_u2()) {
+      return false;
+    }
+    pos += n;
+    j += n;
   }
-  crc ^ 0xffffffff
+  cursor.seek(pos);
+  true
 }
 
 
-fn find<T: BaseLike, S: BaseLike>(haystack: &mut RopeCursor<T>, needle: &[S], start: usize) -> Option<usize> {
+// Boyer-Moore search, the default for StorageCursor::search.
+fn find<'a, T: BaseLike, S: BaseLike, C: StorageCursor<'a, T>>(haystack: &mut C, needle: &[S],
+                                                               start: usize) -> Option<usize> {
 //eprintln!("find {} from {}", Join(needle, ""), start);
   let needle_len = needle.len();
   if needle_len == 0 { return Some(start); }
@@ -1043,11 +1239,46 @@ fn find<T: BaseLike, S: BaseLike>(haystack: &mut RopeCursor<T>, needle: &[S], st
 //eprintln!("char_table: {:?}", char_table);
   let offset_table = build_offset_table(needle);
 //eprintln!("offset_table: {:?}", offset_table);
+  let packed_needle = PackedBases::from_slice(&needle.iter().map(|b| b.to_base())
+                                              .collect::<Vec<_>>());
+  let packed_needle = packed_needle.bytes();
   let mut i = start + needle_len - 1;
+  if i >= haystack_len { return None; }
+  let (mut leaf_start, mut leaf) = haystack.leaf_at(i);
   while i < haystack_len {
-    let mut j = needle_len - 1 as usize;
+    if i < leaf_start || i >= leaf_start + leaf.len() {
+      (leaf_start, leaf) = haystack.leaf_at(i);
+    }
+    let mut j = needle_len - 1;
     loop {
-      let c = haystack.at(i).to_u2();
+      // In a packed leaf, compare up to 32 bases ending at i at once.
+      // The highest base that differs is the rightmost mismatch, just
+      // as comparing one at a time would find.
+      if let (true, Some((bytes, offset))) = (i >= leaf_start, leaf.packed()) {
+        let k = cmp::min(cmp::min(j + 1, i - leaf_start + 1), 32);
+        let diff = packed_word(bytes, offset + i - leaf_start + 1 - k, k)
+            ^ packed_word(packed_needle, j + 1 - k, k);
+        if diff == 0 {
+          if j + 1 == k { return Some(i + 1 - k); }
+          i -= k;
+          j -= k;
+          continue;
+        }
+        let back = k - 1 - (63 - diff.leading_zeros() as usize) / 2;
+        i -= back;
+        j -= back;
+        let index = offset + i - leaf_start;
+        let c = bytes[index >> 2] >> ((index & 3) * 2) & 3;
+        i += max(offset_table[needle_len - 1 - j], char_table[c as usize]);
+        break;
+      }
+      // Compare within the current leaf where possible, since stepping
+      // back out of it would otherwise restart the cursor from the root.
+      let c = if i >= leaf_start {
+        leaf.at(i - leaf_start)
+      } else {
+        haystack.at(i)
+      }.to_u2();
       if needle[j].to_u2() == c {
         if j == 0 { return Some(i); }
         i -= 1;
@@ -1073,10 +1304,7 @@ fn build_char_table<T: BaseLike>(needle: &[T]) -> [usize; 4] {
 
 fn build_offset_table<T: BaseLike>(needle: &[T]) -> Vec<usize> {
   let len = needle.len();
-  let mut table = Vec::<usize>::with_capacity(len);
-  unsafe { // SAFETY: the following for-loop fully initializes the table
-    table.set_len(len);
-  }
+  let mut table = vec![0; len];
   let mut last_prefix_pos = len;
   for i in (1 ..= len).rev() {
     let mut is_prefix = true;
@@ -1109,7 +1337,9 @@ fn build_offset_table<T: BaseLike>(needle: &[T]) -> Vec<usize> {
 mod dna_tests {
   use super::*;
   use quickcheck_macros::quickcheck;
-  use base::{Base, SourceBase};
+  use base::{Base, SourceBase, StampedBase};
+  use rope::BTreeRope;
+  use std::fmt;
 
   #[test]
   fn find_simple() {
@@ -1129,8 +1359,8 @@ mod dna_tests {
 
   #[quickcheck]
   fn find_quickcheck(v: Vec<u8>, start: u64, len: u64, i: u64) {
-    if v.len() == 0 { return; } // TODO - test empty?
-    let haystack = v.iter().map(|x| Base::from_u8(*x)).collect_from::<Rope<Base>>();
+    if v.is_empty() { return; } // TODO - test empty?
+    let haystack = v.iter().map(|x| Base::from_u8(*x)).collect::<Rope<Base>>();
     let start = (start % haystack.len() as u64) as usize;
     let i = (i % haystack.len() as u64) as usize;
     let len = if start < haystack.len() - 1 {
@@ -1139,13 +1369,44 @@ mod dna_tests {
       0
     };
     let haystack_str =
-        haystack.iter().map(|x| format!("{}", x)).collect_from::<Vec<_>>().join("");
+        haystack.iter().map(|x| format!("{}", x)).collect::<Vec<_>>().join("");
     let needle_str = &haystack_str[start .. start + len];
     let needle = Base::collect_from::<Vec<_>>(needle_str);
     let expected = haystack_str[i..].find(needle_str).map(|j| i + j);
     assert_eq!(find(&mut haystack.cursor(), &needle, i), expected);
   }
 
+  #[test]
+  fn find_across_leaves() {
+    // Long enough for several leaves, so that packed comparisons cross
+    // from one to the next.
+    let mut x = 1u32;
+    let s = (0 .. 10000).map(|_| {
+      x = x.wrapping_mul(1103515245).wrapping_add(12345);
+      Base::from_u8((x >> 16) as u8).char()
+    }).collect::<String>();
+    let packed = Base::collect_from::<Rope<_>>(&s);
+    let runs = SourceBase::collect_from::<Rope<_>>(&s);
+    let flat = Base::collect_from::<Vec<_>>(&s);
+    for (start, len) in [(0, 1), (10, 31), (100, 33), (4000, 200), (4090, 12), (8170, 65),
+                         (9990, 10)] {
+      let needle = Base::collect_from::<Vec<_>>(&s[start .. start + len]);
+      for from in [0, start - start.min(1), start, start + 1] {
+        let expected = s[from ..].find(&s[start .. start + len]).map(|j| from + j);
+        assert_eq!(find(&mut packed.cursor(), &needle, from), expected);
+        assert_eq!(find(&mut runs.cursor(), &needle, from), expected);
+        assert_eq!(find(&mut flat.cursor(), &needle, from), expected);
+      }
+    }
+    // Checksums agree however the bases are stored, and wherever the
+    // chunks start.
+    for range in [0 .. 10000, 3 .. 9998, 4097 .. 4098] {
+      let expected = crc(&flat[range.clone()].to_vec());
+      assert_eq!(crc(&packed.slice(range.clone())), expected);
+      assert_eq!(crc(&runs.slice(range)), expected);
+    }
+  }
+
   #[test]
   fn parse_pattern_1() {
     let dna = SourceBase::collect_from::<Rope<_>>("CIIC");
@@ -1156,7 +1417,7 @@ mod dna_tests {
                vec![PItem::Bases(vec![
                  SourceBase::from_parts(Base::I, 0, -1)])]);
     assert_eq!(c.pos(), c.full_len());
-    assert_eq!(state.finished, false);
+    assert!(!state.finished);
     assert_eq!(state.rna, Vec::<[SourceBase;7]>::new());
   }
 
@@ -1168,12 +1429,83 @@ mod dna_tests {
     let pat = PItem::parse(&mut c, &mut state);
     assert_eq!(pat,
                "( !2 ) P".split(' ').map(|s| s.parse::<PItem<Base>>().unwrap())
-                   .collect_from::<Vec<_>>());
+                   .collect::<Vec<_>>());
     assert_eq!(c.pos(), c.full_len());
-    assert_eq!(state.finished, false);
+    assert!(!state.finished);
     assert_eq!(state.rna, Vec::<[Base;7]>::new());
   }
 
+  // Each iteration of this DNA emits one RNA and consumes its 16 bases.
+  fn emitter(rna: &str, count: usize) -> String {
+    format!("III{}IICIIC", rna).repeat(count)
+  }
+
+  // Takes one line of RNA and then fails, like a pipe closed by `head -1`.
+  struct OneLine(usize);
+
+  impl Write for OneLine {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      if self.0 >= 8 { return Err(io::ErrorKind::BrokenPipe.into()); }
+      self.0 += buf.len();
+      Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+  }
+
+  #[test]
+  fn write_error() {
+    let mut dna = Base::collect_from::<Rope<_>>(&emitter("PIPIIIC", 3));
+    let mut state = DnaState::<Base>::new();
+    state.out = Some(Box::new(OneLine(0)));
+    while !state.finished() {
+      state.iterate(&mut dna);
+    }
+    // The engine carries on, but stops writing and keeps the error.
+    assert_eq!(state.rna().len(), 3);
+    assert!(state.out.is_none());
+    assert_eq!(state.error.map(|e| e.kind()), Some(io::ErrorKind::BrokenPipe));
+  }
+
+  #[test]
+  fn fork_checkpoint() {
+    let mut dna = Base::collect_from::<Rope<_>>(&emitter("PIPIIIC", 5));
+    let mut state = DnaState::<Base>::new();
+    for _ in 0..2 {
+      state.iterate(&mut dna);
+    }
+    let checkpoint = state.checkpoint(&dna);
+    assert_eq!(checkpoint.iters, 2);
+    assert_eq!(checkpoint.rna.len(), 2);
+
+    // Continue the original run to completion.
+    while !state.finished() {
+      state.iterate(&mut dna);
+    }
+    assert_eq!(state.rna().len(), 5);
+    assert_eq!(checkpoint.dna.len(), 3 * 16);
+
+    // Fork with an extra prefix: one more RNA than the original.
+    let (mut a, mut dna_a) = checkpoint.fork();
+    dna_a.splice(0, 0, Some(Base::collect_from(&emitter("PIPIIIP", 1))));
+    while !a.finished() {
+      a.iterate(&mut dna_a);
+    }
+    let rna_a = a.rna().iter()
+        .map(|r| r.map(|b| b.char()).iter().collect::<String>())
+        .collect::<Vec<_>>();
+    assert_eq!(rna_a, vec!["PIPIIIC", "PIPIIIC", "PIPIIIP",
+                           "PIPIIIC", "PIPIIIC", "PIPIIIC"]);
+
+    // A plain fork replays the original, unaffected by the other fork.
+    let (mut b, mut dna_b) = checkpoint.fork();
+    while !b.finished() {
+      b.iterate(&mut dna_b);
+    }
+    assert_eq!(b.rna(), state.rna());
+    assert_eq!(b.iters, state.iters);
+    assert_eq!(checkpoint.dna.len(), 3 * 16);
+  }
+
   #[test]
   fn full_iteration_1() {
     let mut dna = Base::collect_from::<Rope<_>>("IIPIPICPIICICIIFICCIFPPIICCFPC");
@@ -1197,4 +1529,122 @@ mod dna_tests {
     state.iterate(&mut dna);
     assert_eq!(&str(&dna), "I");
   }
+
+  #[test]
+  fn shared_expansion() {
+    // (!300) -> $0 $0: one copy stays in place and the other is long
+    // enough to be inserted as a slice.
+    let program = "IIPIPIICCICIICPIICIIC".to_string() + "IPPPIPPPIIC";
+    let data = "ICFP".repeat(100);
+    let mut dna = Base::collect_from::<Rope<_>>(&(program + &data));
+    let mut state = DnaState::new();
+    state.iterate(&mut dna);
+    dna.check_invariants();
+    assert_eq!(str(&dna), data[..300].repeat(2) + &data[300..]);
+  }
+
+  #[test]
+  fn synthesized_provenance() {
+    // (!2) -> |0| |1|: the length of group 0, then a P for the missing
+    // group, each attributed to its IIP.
+    let pattern = "IIPIPICPIICIIC";
+    let mut dna = SourceBase::collect_from::<Rope<_>>(
+        &(pattern.to_string() + "IIPPIIPCPIIC" + "CFIC"));
+    let mut state = DnaState::new();
+    state.iterate(&mut dna);
+    let v = dna.iter().collect::<Vec<_>>();
+    let len = pattern.len() as u32;
+    assert_eq!(v, vec![SourceBase::synthesized(Base::I, len),
+                       SourceBase::synthesized(Base::C, len),
+                       SourceBase::synthesized(Base::P, len),
+                       SourceBase::synthesized(Base::P, len + 4),
+                       SourceBase::from_base_pos(Base::I, 28),
+                       SourceBase::from_base_pos(Base::C, 29)]);
+    assert_eq!(source(v[3]), Some((len as usize + 4, SYNTHESIZED_LEVEL)));
+  }
+
+  #[test]
+  fn literal_provenance() {
+    // () -> ICFP: all four bases come from the one literal item at 3.
+    let mut dna = SourceBase::collect_from::<Rope<_>>("IICCFPICIICCFIC");
+    let mut state = DnaState::new();
+    state.iterate(&mut dna);
+    let v = dna.iter().collect::<Vec<_>>();
+    assert_eq!(v, vec![SourceBase::synthesized(Base::I, 3),
+                       SourceBase::synthesized(Base::C, 3),
+                       SourceBase::synthesized(Base::F, 3),
+                       SourceBase::synthesized(Base::P, 3),
+                       SourceBase::from_base_pos(Base::C, 11),
+                       SourceBase::from_base_pos(Base::F, 12),
+                       SourceBase::from_base_pos(Base::I, 13),
+                       SourceBase::from_base_pos(Base::C, 14)]);
+    assert_eq!(source(v[0]), Some((3, SYNTHESIZED_LEVEL)));
+  }
+
+  #[test]
+  fn protected_provenance() {
+    // (!2) -> $\0: the quoted copy of CF is attributed to the reference.
+    let pattern = "IIPIPICPIICIIC";
+    let mut dna = SourceBase::collect_from::<Rope<_>>(
+        &(pattern.to_string() + "IPCPPIIC" + "CFIC"));
+    let mut state = DnaState::new();
+    state.iterate(&mut dna);
+    let v = dna.iter().collect::<Vec<_>>();
+    let len = pattern.len() as u32;
+    assert_eq!(v, vec![SourceBase::synthesized(Base::F, len),
+                       SourceBase::synthesized(Base::P, len),
+                       SourceBase::from_base_pos(Base::I, 24),
+                       SourceBase::from_base_pos(Base::C, 25)]);
+    assert_eq!(source(v[1]), Some((len as usize, SYNTHESIZED_LEVEL)));
+  }
+
+  #[test]
+  fn stamps_created_bases() {
+    // (!2) -> I |0| $0: the literal and the length are new, while the
+    // group and the rest of the DNA are only moved.
+    let program = "IIPIPICPIICIIC".to_string() + "CIIPPIPPPIIC";
+    let mut dna = StampedBase::collect_from::<Rope<_>>(&(program + "CFIC"));
+    let mut state = DnaState::new();
+    state.iterate(&mut dna);
+    assert_eq!(str(&dna), "IICPCFIC");
+    let stamps = dna.iter().map(|b| b.stamp().unwrap()).collect::<Vec<_>>();
+    assert_eq!(stamps, vec![1, 1, 1, 1, 0, 0, 0, 0]);
+    let ages = dna.iter().map(|b| state.age(b).unwrap()).collect::<Vec<_>>();
+    assert_eq!(ages, vec![0, 0, 0, 0, 1, 1, 1, 1]);
+    assert_eq!(DnaState::<Base>::new().age(Base::I), None);
+  }
+
+  // Runs up to `limit` iterations, returning the final DNA and the RNA.
+  fn run_on<T: BaseLike, D: Storage<T>>(dna: &str, limit: u32) -> (Vec<T>, Vec<[T; 7]>, u32) {
+    let mut dna = T::collect_from::<D>(dna);
+    let mut state = DnaState::new();
+    while !state.finished() && state.iters < limit {
+      state.iterate(&mut dna);
+      dna.check_invariants();
+    }
+    (dna.cursor().collect(), state.rna, crc(&dna))
+  }
+
+  fn storage_parity<T: BaseLike + fmt::Debug>(dna: &str, limit: u32) {
+    let expected = run_on::<T, Rope<T>>(dna, limit);
+    assert_eq!(run_on::<T, BTreeRope<T>>(dna, limit), expected);
+    assert_eq!(run_on::<T, Vec<T>>(dna, limit), expected);
+  }
+
+  #[test]
+  fn storage_parity_examples() {
+    let shared = "IIPIPIICCICIICPIICIIC".to_string() + "IPPPIPPPIIC" + &"ICFP".repeat(100);
+    for dna in ["IIPIPICPIICICIIFICCIFPPIICCFPC", "IIPIPIICPIICIICCIICFCFC",
+                &emitter("PIPIIIC", 5), &shared] {
+      storage_parity::<Base>(dna, 100);
+      storage_parity::<SourceBase>(dna, 100);
+    }
+  }
+
+  #[quickcheck]
+  fn storage_parity_quickcheck(v: Vec<u8>) {
+    let dna = v.iter().map(|x| Base::from_u8(*x).char()).collect::<String>();
+    storage_parity::<Base>(&dna, 20);
+    storage_parity::<SourceBase>(&dna, 20);
+  }
 }
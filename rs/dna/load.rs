@@ -0,0 +1,154 @@
+use std::io::{self, BufRead, BufReader, Chain, Read};
+
+use base::{BaseLike, BaseParser, PackedReader, PACKED_MAGIC};
+use flate2::read::GzDecoder;
+use rope::{Rope, RopeBuilder};
+
+// Loads DNA from text or a packed file, either of which may be gzipped,
+// streaming it straight into a balanced rope so that neither the file
+// nor one giant leaf is ever held in memory.  Parse errors come back as
+// InvalidData, with the position.
+pub fn load<T: BaseLike, R: Read>(input: R) -> io::Result<Rope<T>> {
+  let (head, input) = sniff(input, 2)?;
+  if head == [0x1f, 0x8b] {
+    load_unzipped(GzDecoder::new(input))
+  } else {
+    load_unzipped(input)
+  }
+}
+
+fn load_unzipped<T: BaseLike, R: Read>(input: R) -> io::Result<Rope<T>> {
+  // Sniff the magic number; anything else is taken to be text.
+  let (head, input) = sniff(input, PACKED_MAGIC.len())?;
+  let reader = BufReader::new(input);
+  if head == PACKED_MAGIC {
+    let mut builder = RopeBuilder::new();
+    PackedReader::new(reader)?.read_into(&mut builder)?;
+    Ok(builder.finish())
+  } else {
+    load_text(reader)
+  }
+}
+
+type Sniffed<R> = Chain<io::Cursor<Vec<u8>>, R>;
+
+// Reads the first `n` bytes (fewer only at the end of the input), however
+// many reads that takes, and returns them along with a reader that still
+// starts with them.
+fn sniff<R: Read>(mut input: R, n: usize) -> io::Result<(Vec<u8>, Sniffed<R>)> {
+  let mut head = Vec::with_capacity(n);
+  input.by_ref().take(n as u64).read_to_end(&mut head)?;
+  Ok((head.clone(), io::Cursor::new(head).chain(input)))
+}
+
+fn load_text<T: BaseLike, R: BufRead>(mut input: R) -> io::Result<Rope<T>> {
+  let mut parser = BaseParser::new();
+  let mut builder = RopeBuilder::new();
+  loop {
+    let buf = input.fill_buf()?;
+    if buf.is_empty() { break; }
+    let len = buf.len();
+    parser.parse_into(buf, &mut builder)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    input.consume(len);
+  }
+  Ok(builder.finish())
+}
+
+
+#[cfg(test)]
+mod load_tests {
+  use super::*;
+  use base::{Base, PackedWriter, ParseError, SourceBase};
+  use flate2::Compression;
+  use flate2::write::GzEncoder;
+  use std::io::Write;
+
+  const DNA: &str = "IIPIPICPIICICIIFICCIFPPIICCFPC";
+
+  #[test]
+  fn load_plain() {
+    let text = DNA.repeat(1000);
+    let rope: Rope<SourceBase> = load(text.as_bytes()).unwrap();
+    rope.check_invariants();
+    assert_eq!(rope.iter().collect::<Vec<_>>(),
+               SourceBase::collect_from::<Vec<_>>(&text));
+    assert!(rope.chunks().count() > 1);
+  }
+
+  #[test]
+  fn load_gzip() {
+    let text = format!("{}\r\n\t{}\n", DNA, DNA);
+    let mut gz = GzEncoder::new(vec![], Compression::fast());
+    gz.write_all(text.as_bytes()).unwrap();
+    let rope: Rope<Base> = load(&gz.finish().unwrap()[..]).unwrap();
+    assert_eq!(rope.iter().collect::<Vec<_>>(),
+               Base::collect_from::<Vec<_>>(&DNA.repeat(2)));
+  }
+
+  #[test]
+  fn load_packed() {
+    let text = DNA.repeat(1000);
+    let bases = Base::collect_from::<Vec<_>>(&text);
+    let mut w = PackedWriter::new(vec![], bases.len() as u64, true).unwrap();
+    for b in &bases {
+      w.push(*b).unwrap();
+    }
+    let packed = w.finish().unwrap();
+    let rope: Rope<SourceBase> = load(&packed[..]).unwrap();
+    rope.check_invariants();
+    assert_eq!(rope.iter().collect::<Vec<_>>(),
+               SourceBase::collect_from::<Vec<_>>(&text));
+    // The stored checksum is the same as crc's.
+    let sum = u32::from_le_bytes(packed[packed.len() - 4 ..].try_into().unwrap());
+    assert_eq!(sum, crate::crc(&rope));
+    // Gzipped packed files work too.
+    let mut gz = GzEncoder::new(vec![], Compression::fast());
+    gz.write_all(&packed).unwrap();
+    let rope: Rope<Base> = load(&gz.finish().unwrap()[..]).unwrap();
+    assert_eq!(rope.iter().collect::<Vec<_>>(), bases);
+  }
+
+  // Hands out one byte per read, as a decoder may.
+  struct Trickle<'a>(&'a [u8]);
+
+  impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      if buf.is_empty() || self.0.is_empty() { return Ok(0); }
+      buf[0] = self.0[0];
+      self.0 = &self.0[1 ..];
+      Ok(1)
+    }
+  }
+
+  #[test]
+  fn load_short_reads() {
+    let bases = Base::collect_from::<Vec<_>>(&DNA.repeat(10));
+    let mut w = PackedWriter::new(vec![], bases.len() as u64, true).unwrap();
+    for b in &bases {
+      w.push(*b).unwrap();
+    }
+    let packed = w.finish().unwrap();
+    let rope: Rope<Base> = load(Trickle(&packed)).unwrap();
+    assert_eq!(rope.iter().collect::<Vec<_>>(), bases);
+    let mut gz = GzEncoder::new(vec![], Compression::fast());
+    gz.write_all(&packed).unwrap();
+    let rope: Rope<Base> = load(Trickle(&gz.finish().unwrap())).unwrap();
+    assert_eq!(rope.iter().collect::<Vec<_>>(), bases);
+    // Input shorter than the magic is just text.
+    let rope: Rope<Base> = load(Trickle(b"ICF")).unwrap();
+    assert_eq!(rope.iter().collect::<Vec<_>>(), Base::collect_from::<Vec<_>>("ICF"));
+  }
+
+  #[test]
+  fn error_spans_buffers() {
+    // Errors are positioned within the whole input, however it's read.
+    let text = format!("{}\n{}X", DNA.repeat(1000), DNA);
+    let reader = BufReader::with_capacity(7, text.as_bytes());
+    let err = load_text::<Base, _>(reader).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let err = err.into_inner().unwrap().downcast::<ParseError>().unwrap();
+    assert_eq!(*err, ParseError{byte: b'X', offset: text.len() - 1, line: 2,
+                                column: DNA.len() + 1});
+  }
+}
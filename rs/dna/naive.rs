@@ -0,0 +1,436 @@
+use std::fmt;
+
+use base::{Base, BaseLike};
+
+use crate::{crc, DnaState, State, Storage};
+
+// A reference interpreter that follows the task's specification as
+// literally as it can, with the DNA in a plain Vec.  Every iteration
+// copies the whole DNA, so it is only good for checking DnaState on a
+// limited number of iterations.  It shares nothing with the engine
+// except the Base type.
+pub struct Naive {
+  pub dna: Vec<Base>,
+  // As in the specification, an RNA cut short by the end of the DNA is
+  // kept short.
+  pub rna: Vec<Vec<Base>>,
+  pub iters: u32,
+  pub finished: bool,
+}
+
+enum Pat {
+  Base(Base),
+  Skip(usize),
+  Search(Vec<Base>),
+  Open,
+  Close,
+}
+
+enum Tpl {
+  Base(Base),
+  // Group, then escape level.
+  Ref(usize, usize),
+  Len(usize),
+}
+
+// Reads the pattern and template from the front of the DNA.  Instead of
+// dropping each base as the specification does, it keeps an index and
+// the caller drops them all at once.
+struct Reader<'a> {
+  dna: &'a [Base],
+  pos: usize,
+  rna: &'a mut Vec<Vec<Base>>,
+}
+
+impl Reader<'_> {
+  fn get(&self, k: usize) -> Option<Base> {
+    self.dna.get(self.pos + k).copied()
+  }
+
+  fn skip(&mut self, n: usize) {
+    self.pos = (self.pos + n).min(self.dna.len());
+  }
+
+  // None means finish.
+  fn pattern(&mut self) -> Option<Vec<Pat>> {
+    let mut p = vec![];
+    let mut lvl = 0;
+    loop {
+      match (self.get(0), self.get(1), self.get(2)) {
+        (Some(Base::C), _, _) => { self.skip(1); p.push(Pat::Base(Base::I)); }
+        (Some(Base::F), _, _) => { self.skip(1); p.push(Pat::Base(Base::C)); }
+        (Some(Base::P), _, _) => { self.skip(1); p.push(Pat::Base(Base::F)); }
+        (Some(Base::I), Some(Base::C), _) => { self.skip(2); p.push(Pat::Base(Base::P)); }
+        (Some(Base::I), Some(Base::P), _) => {
+          self.skip(2);
+          let n = self.nat()?;
+          p.push(Pat::Skip(n));
+        }
+        (Some(Base::I), Some(Base::F), _) => {
+          // Three, not two: the specification skips an extra base here.
+          self.skip(3);
+          let s = self.consts();
+          p.push(Pat::Search(s));
+        }
+        (Some(Base::I), Some(Base::I), Some(Base::P)) => {
+          self.skip(3);
+          lvl += 1;
+          p.push(Pat::Open);
+        }
+        (Some(Base::I), Some(Base::I), Some(Base::C | Base::F)) => {
+          self.skip(3);
+          if lvl == 0 { return Some(p); }
+          lvl -= 1;
+          p.push(Pat::Close);
+        }
+        (Some(Base::I), Some(Base::I), Some(Base::I)) => self.emit(),
+        _ => return None,
+      }
+    }
+  }
+
+  fn template(&mut self) -> Option<Vec<Tpl>> {
+    let mut t = vec![];
+    loop {
+      match (self.get(0), self.get(1), self.get(2)) {
+        (Some(Base::C), _, _) => { self.skip(1); t.push(Tpl::Base(Base::I)); }
+        (Some(Base::F), _, _) => { self.skip(1); t.push(Tpl::Base(Base::C)); }
+        (Some(Base::P), _, _) => { self.skip(1); t.push(Tpl::Base(Base::F)); }
+        (Some(Base::I), Some(Base::C), _) => { self.skip(2); t.push(Tpl::Base(Base::P)); }
+        (Some(Base::I), Some(Base::F | Base::P), _) => {
+          self.skip(2);
+          let l = self.nat()?;
+          let n = self.nat()?;
+          t.push(Tpl::Ref(n, l));
+        }
+        (Some(Base::I), Some(Base::I), Some(Base::C | Base::F)) => {
+          self.skip(3);
+          return Some(t);
+        }
+        (Some(Base::I), Some(Base::I), Some(Base::P)) => {
+          self.skip(3);
+          let n = self.nat()?;
+          t.push(Tpl::Len(n));
+        }
+        (Some(Base::I), Some(Base::I), Some(Base::I)) => self.emit(),
+        _ => return None,
+      }
+    }
+  }
+
+  // Least significant bit first, ended by P.  Like the engine, this
+  // keeps the low bits of numbers too big for a usize.
+  fn nat(&mut self) -> Option<usize> {
+    let mut bits = vec![];
+    loop {
+      match self.get(0)? {
+        Base::P => { self.skip(1); break; }
+        Base::I | Base::F => bits.push(0),
+        Base::C => bits.push(1),
+      }
+      self.skip(1);
+    }
+    Some(bits.iter().rev().fold(0usize, |n, b| n.wrapping_mul(2).wrapping_add(*b)))
+  }
+
+  fn consts(&mut self) -> Vec<Base> {
+    let mut s = vec![];
+    loop {
+      match (self.get(0), self.get(1)) {
+        (Some(Base::C), _) => { self.skip(1); s.push(Base::I); }
+        (Some(Base::F), _) => { self.skip(1); s.push(Base::C); }
+        (Some(Base::P), _) => { self.skip(1); s.push(Base::F); }
+        (Some(Base::I), Some(Base::C)) => { self.skip(2); s.push(Base::P); }
+        _ => return s,
+      }
+    }
+  }
+
+  fn emit(&mut self) {
+    let start = (self.pos + 3).min(self.dna.len());
+    let end = (self.pos + 10).min(self.dna.len());
+    self.rna.push(self.dna[start .. end].to_vec());
+    self.skip(10);
+  }
+}
+
+fn quote(d: &[Base]) -> Vec<Base> {
+  let mut out = vec![];
+  for b in d {
+    match b {
+      Base::I => out.push(Base::C),
+      Base::C => out.push(Base::F),
+      Base::F => out.push(Base::P),
+      Base::P => out.extend([Base::I, Base::C]),
+    }
+  }
+  out
+}
+
+fn protect(l: usize, d: &[Base]) -> Vec<Base> {
+  let mut d = d.to_vec();
+  // Quoting nothing gives nothing, however high the level.
+  for _ in 0 .. l {
+    if d.is_empty() { break; }
+    d = quote(&d);
+  }
+  d
+}
+
+fn asnat(mut n: usize) -> Vec<Base> {
+  let mut out = vec![];
+  while n > 0 {
+    out.push(if n & 1 == 1 { Base::C } else { Base::I });
+    n >>= 1;
+  }
+  out.push(Base::P);
+  out
+}
+
+// The first index at which `needle` occurs in `haystack`.
+fn find(haystack: &[Base], needle: &[Base]) -> Option<usize> {
+  let last = haystack.len().checked_sub(needle.len())?;
+  (0 ..= last).find(|&k| haystack[k .. k + needle.len()] == *needle)
+}
+
+impl Naive {
+  pub fn new(dna: Vec<Base>) -> Self {
+    Naive{dna, rna: vec![], iters: 0, finished: false}
+  }
+
+  pub fn iterate(&mut self) {
+    if self.finished { return; }
+    self.iters += 1;
+    let mut reader = Reader{dna: &self.dna, pos: 0, rna: &mut self.rna};
+    let parsed = reader.pattern().and_then(|p| Some((p, reader.template()?)));
+    let pos = reader.pos;
+    match parsed {
+      None => self.finished = true,
+      Some((pat, tpl)) => {
+        self.dna.drain(.. pos);
+        self.match_replace(&pat, &tpl);
+      }
+    }
+  }
+
+  fn match_replace(&mut self, pat: &[Pat], tpl: &[Tpl]) {
+    let mut i = 0;
+    let mut e: Vec<Vec<Base>> = vec![];
+    let mut c: Vec<usize> = vec![];
+    for p in pat {
+      match p {
+        Pat::Base(b) => {
+          if self.dna.get(i) != Some(b) { return; }
+          i += 1;
+        }
+        Pat::Skip(n) => {
+          i = match i.checked_add(*n) {
+            Some(j) if j <= self.dna.len() => j,
+            _ => return,
+          };
+        }
+        Pat::Search(s) => match find(&self.dna[i ..], s) {
+          Some(k) => i += k + s.len(),
+          None => return,
+        },
+        Pat::Open => c.push(i),
+        Pat::Close => e.push(self.dna[c.pop().unwrap() .. i].to_vec()),
+      }
+    }
+    self.replace(i, tpl, &e);
+  }
+
+  fn replace(&mut self, i: usize, tpl: &[Tpl], e: &[Vec<Base>]) {
+    let mut r = vec![];
+    for t in tpl {
+      match t {
+        Tpl::Base(b) => r.push(*b),
+        Tpl::Ref(n, l) => r.extend(protect(*l, e.get(*n).map_or(&[], |g| g))),
+        Tpl::Len(n) => r.extend(asnat(e.get(*n).map_or(0, Vec::len))),
+      }
+    }
+    r.extend_from_slice(&self.dna[i ..]);
+    self.dna = r;
+  }
+}
+
+////////////////////////////////////////////////////////////////
+// Differential testing
+
+// The first thing that differs between DnaState and Naive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Divergence {
+  Finished{iter: u32, engine: bool},
+  Rna{iter: u32, index: usize, engine: Option<String>, naive: Option<String>},
+  Dna{iter: u32, engine_len: usize, naive_len: usize, engine_crc: u32, naive_crc: u32},
+}
+
+impl fmt::Display for Divergence {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let show = |r: &Option<String>| r.clone().unwrap_or_else(|| "nothing".to_string());
+    match self {
+      Divergence::Finished{iter, engine} => {
+        let (done, not) = if *engine { ("engine", "naive") } else { ("naive", "engine") };
+        write!(f, "Iteration {}: the {} finished but the {} didn't", iter, done, not)
+      }
+      Divergence::Rna{iter, index, engine, naive} => {
+        write!(f, "Iteration {}: RNA {} is {} from the engine but {} from the naive",
+               iter, index, show(engine), show(naive))
+      }
+      Divergence::Dna{iter, engine_len, naive_len, engine_crc, naive_crc} => {
+        write!(f, "Iteration {}: DNA is {} bases, crc {:08x} from the engine but \
+                   {} bases, crc {:08x} from the naive",
+               iter, engine_len, engine_crc, naive_len, naive_crc)
+      }
+    }
+  }
+}
+
+// Runs one iteration of each and compares the results.  The RNA is
+// always compared, and the DNA only when `check_dna` is set since it
+// means reading all of it.
+pub fn lockstep<T: BaseLike, D: Storage<T>>(naive: &mut Naive, state: &mut DnaState<T>,
+                                            dna: &mut D, check_dna: bool)
+                                            -> Result<(), Divergence> {
+  let (engine_before, naive_before) = (state.rna().len(), naive.rna.len());
+  naive.iterate();
+  state.iterate(dna);
+  let iter = naive.iters;
+  let engine_rna = &state.rna()[engine_before ..];
+  // The engine drops an RNA cut short by the end of the DNA.  No command
+  // is that short, so it makes no difference to the picture.
+  let naive_rna = naive.rna[naive_before ..].iter().filter(|r| r.len() == 7)
+      .collect::<Vec<_>>();
+  for k in 0 .. engine_rna.len().max(naive_rna.len()) {
+    let engine = engine_rna.get(k).map(|r| r.iter().map(|b| b.to_base().char()).collect());
+    let naive = naive_rna.get(k).map(|r| r.iter().map(Base::char).collect());
+    if engine != naive {
+      return Err(Divergence::Rna{iter, index: engine_before + k, engine, naive});
+    }
+  }
+  if state.finished() != naive.finished {
+    return Err(Divergence::Finished{iter, engine: state.finished()});
+  }
+  // Neither looks at the DNA once it's finished.
+  if check_dna && !naive.finished {
+    let (engine_crc, naive_crc) = (crc(dna), crc(&naive.dna));
+    if dna.len() != naive.dna.len() || engine_crc != naive_crc {
+      return Err(Divergence::Dna{iter, engine_len: dna.len(), naive_len: naive.dna.len(),
+                                 engine_crc, naive_crc});
+    }
+  }
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod naive_tests {
+  use super::*;
+  use quickcheck_macros::quickcheck;
+  use rope::{BTreeRope, Rope};
+
+  fn run(dna: &str) -> String {
+    let mut naive = Naive::new(Base::collect_from(dna));
+    naive.iterate();
+    naive.dna.iter().map(Base::char).collect()
+  }
+
+  #[test]
+  fn spec_examples() {
+    assert_eq!(run("IIPIPICPIICICIIFICCIFPPIICCFPC"), "PICFC");
+    assert_eq!(run("IIPIPICPIICICIIFICCIFCCCPPIICCFPC"), "PIICCFCFFPC");
+    assert_eq!(run("IIPIPIICPIICIICCIICFCFC"), "I");
+  }
+
+  #[test]
+  fn short_rna() {
+    let mut naive = Naive::new(Base::collect_from("IIIPIP"));
+    naive.iterate();
+    assert!(naive.finished);
+    assert_eq!(naive.rna, vec![Base::collect_from::<Vec<_>>("PIP")]);
+  }
+
+  fn quoted(b: Base) -> &'static str {
+    match b {
+      Base::I => "C",
+      Base::C => "F",
+      Base::F => "P",
+      Base::P => "IC",
+    }
+  }
+
+  fn nat(n: u8) -> String {
+    asnat(n as usize).iter().map(Base::char).collect()
+  }
+
+  // Builds a pattern and template from `ops`, so that random inputs
+  // mostly make programs that do something.
+  fn program(ops: &[(u8, u8)]) -> String {
+    let (pat, tpl) = ops.split_at(ops.len() / 2);
+    let mut s = String::new();
+    let mut depth = 0;
+    for (op, arg) in pat {
+      match op % 6 {
+        0 | 1 => s += quoted(Base::from_u8(*arg)),
+        2 => s += &("IP".to_string() + &nat(arg % 16)),
+        3 => s += &("IFF".to_string() + quoted(Base::from_u8(*arg))
+                    + quoted(Base::from_u8(arg >> 2))),
+        4 => { s += "IIP"; depth += 1; }
+        _ => if depth > 0 { s += "IIC"; depth -= 1; },
+      }
+    }
+    s += "IIC";
+    for (op, arg) in tpl {
+      match op % 4 {
+        0 | 1 => s += quoted(Base::from_u8(*arg)),
+        2 => s += &("IP".to_string() + &nat(arg % 3) + &nat((arg >> 2) % 4)),
+        _ => s += &("IIP".to_string() + &nat(arg % 4)),
+      }
+    }
+    s + "IIC"
+  }
+
+  fn lockstep_on<D: Storage<Base>>(dna: &str, limit: u32) {
+    let mut naive = Naive::new(Base::collect_from(dna));
+    let mut state = DnaState::<Base>::new();
+    let mut engine = Base::collect_from::<D>(dna);
+    while !naive.finished && naive.iters < limit {
+      if let Err(d) = lockstep(&mut naive, &mut state, &mut engine, true) {
+        panic!("{} running {}", d, dna);
+      }
+    }
+  }
+
+  #[test]
+  fn lockstep_examples() {
+    let shared = "IIPIPIICCICIICPIICIIC".to_string() + "IPPPIPPPIIC" + &"ICFP".repeat(100);
+    for dna in ["IIPIPICPIICICIIFICCIFPPIICCFPC", "IIPIPIICPIICIICCIICFCFC",
+                "IIIPIPIIICIIC", "IIIPIPIIIC", &"IIIPIPIIICIICIIC".repeat(5), &shared] {
+      lockstep_on::<Rope<Base>>(dna, 100);
+    }
+  }
+
+  #[quickcheck]
+  fn lockstep_programs(programs: Vec<Vec<(u8, u8)>>, data: Vec<u8>) {
+    let mut dna = programs.iter().map(|p| program(p)).collect::<String>();
+    dna.extend(data.iter().map(|x| Base::from_u8(*x).char()));
+    lockstep_on::<Rope<Base>>(&dna, 20);
+    lockstep_on::<BTreeRope<Base>>(&dna, 20);
+  }
+
+  #[test]
+  fn divergence() {
+    let dna = "IIPIPICPIICICIIFICCIFPPIICCFPC";
+    let mut naive = Naive::new(Base::collect_from(dna));
+    let mut state = DnaState::<Base>::new();
+    let mut engine = Base::collect_from::<Rope<Base>>(&dna.replace("FPC", "FPP"));
+    assert_eq!(lockstep(&mut naive, &mut state, &mut engine, false), Ok(()));
+
+    let mut naive = Naive::new(Base::collect_from(dna));
+    let mut state = DnaState::<Base>::new();
+    let mut engine = Base::collect_from::<Rope<Base>>(&dna.replace("FPC", "FPP"));
+    let d = lockstep(&mut naive, &mut state, &mut engine, true);
+    assert!(matches!(d, Err(Divergence::Dna{iter: 1, engine_len: 5, naive_len: 5, ..})),
+            "{:?}", d);
+  }
+}
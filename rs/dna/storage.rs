@@ -0,0 +1,265 @@
+use std::ops::Range;
+
+use base::BaseLike;
+use rope::{BTreeCursor, BTreeRope, Chunk, Rope, RopeCursor};
+
+// Sequences of bases that the engine can run on.  Rope is the usual
+// one; BTreeRope trades slower splices for faster seeks, and Vec is
+// simple enough to trust when checking the others.
+pub trait Storage<T: BaseLike>: Clone + Default + FromIterator<T> {
+  type Cursor<'a>: StorageCursor<'a, T, Storage = Self> where Self: 'a;
+
+  fn from_vec(vec: Vec<T>) -> Self;
+  fn len(&self) -> usize;
+  fn is_empty(&self) -> bool { self.len() == 0 }
+  fn cursor(&self) -> Self::Cursor<'_>;
+
+  // A copy of the range, which may share structure with this one.
+  fn slice(&self, range: Range<usize>) -> Self;
+  fn append(&mut self, other: Self);
+  fn splice(&mut self, start: usize, len: usize, insert: Option<Vec<T>>);
+  // Like splice, but inserts another sequence (typically made of slices
+  // of this one) without flattening it.
+  fn splice_from(&mut self, start: usize, len: usize, insert: Self);
+
+  // Checkpoints and the loaders deal in ropes.
+  fn from_rope(rope: Rope<T>) -> Self;
+  fn to_rope(&self) -> Rope<T>;
+
+  fn check_invariants(&self) {}
+}
+
+// A position in a Storage, which also reads around it.  Reads go through
+// the cursor because most backends cache where they last looked.
+pub trait StorageCursor<'a, T: BaseLike>: Iterator<Item = T> {
+  type Storage: Storage<T> + 'a;
+  type Chunk: StorageChunk<T>;
+
+  fn root(&self) -> &'a Self::Storage;
+  fn full_len(&self) -> usize;
+  fn pos(&self) -> usize;
+  fn seek(&mut self, pos: usize);
+  fn at(&mut self, pos: usize) -> T;
+  // The contiguous block holding `pos`, and where it starts.
+  fn leaf_at(&mut self, pos: usize) -> (usize, Self::Chunk);
+
+  #[inline]
+  fn at_end(&self) -> bool {
+    self.pos() >= self.full_len()
+  }
+
+  #[inline]
+  fn skip(&mut self, delta: isize) {
+    self.seek((self.pos() as isize + delta) as usize);
+  }
+
+  #[inline]
+  fn try_at(&mut self, pos: usize) -> Option<T> {
+    if pos < self.full_len() { Some(self.at(pos)) } else { None }
+  }
+
+  // From `pos` to the end of its block, or None past the end.
+  #[inline]
+  fn chunk_at(&mut self, pos: usize) -> Option<Self::Chunk> {
+    if pos >= self.full_len() { return None; }
+    let (start, leaf) = self.leaf_at(pos);
+    Some(leaf.slice(pos - start .. leaf.len()))
+  }
+
+  fn copy_range(&mut self, range: Range<usize>, out: &mut Vec<T>) {
+    out.reserve(range.len());
+    let mut pos = range.start;
+    while pos < range.end {
+      let chunk = self.chunk_at(pos).expect("Out of bounds");
+      let n = chunk.len().min(range.end - pos);
+      chunk.slice(0 .. n).copy_to(out);
+      pos += n;
+    }
+  }
+
+  // Index of the first match of `needle` at or after `start`.
+  fn search<S: BaseLike>(&mut self, needle: &[S], start: usize) -> Option<usize> where Self: Sized {
+    crate::find(self, needle, start)
+  }
+}
+
+pub trait StorageChunk<T>: Copy {
+  fn len(&self) -> usize;
+  fn is_empty(&self) -> bool { self.len() == 0 }
+  fn at(&self, index: usize) -> T;
+  // Like at, but faster for reading forwards when `hint` is kept between
+  // calls.
+  fn at_hint(&self, index: usize, _hint: &mut usize) -> T { self.at(index) }
+  // The end of the run from `index` over which provenance steps evenly.
+  fn run_end(&self, index: usize) -> usize { index + 1 }
+  fn slice(&self, range: Range<usize>) -> Self;
+  fn copy_to(&self, out: &mut Vec<T>);
+  // Packed bytes holding the chunk, and the index of its first base in
+  // them, for comparing and checksumming a word at a time.
+  fn packed(&self) -> Option<(&[u8], usize)> { None }
+}
+
+////////////////////////////////////////////////////////////////
+// Rope
+
+impl<'a, T: BaseLike> StorageChunk<T> for Chunk<'a, T> {
+  #[inline]
+  fn len(&self) -> usize { Chunk::len(self) }
+  #[inline]
+  fn at(&self, index: usize) -> T { Chunk::at(self, index) }
+  #[inline]
+  fn at_hint(&self, index: usize, hint: &mut usize) -> T { Chunk::at_hint(self, index, hint) }
+  #[inline]
+  fn run_end(&self, index: usize) -> usize { Chunk::run_end(self, index) }
+  #[inline]
+  fn slice(&self, range: Range<usize>) -> Self { Chunk::slice(self, range) }
+  fn copy_to(&self, out: &mut Vec<T>) { Chunk::copy_to(self, out) }
+  #[inline]
+  fn packed(&self) -> Option<(&[u8], usize)> { Chunk::packed(self) }
+}
+
+impl<T: BaseLike> Storage<T> for Rope<T> {
+  type Cursor<'a> = RopeCursor<'a, T> where T: 'a;
+
+  fn from_vec(vec: Vec<T>) -> Self { Rope::from_vec(vec) }
+  #[inline]
+  fn len(&self) -> usize { Rope::len(self) }
+  #[inline]
+  fn cursor(&self) -> RopeCursor<'_, T> { Rope::cursor(self) }
+  fn slice(&self, range: Range<usize>) -> Self { Rope::slice(self, range) }
+  fn append(&mut self, other: Self) { self.append_rope(other) }
+  fn splice(&mut self, start: usize, len: usize, insert: Option<Vec<T>>) {
+    Rope::splice(self, start, len, insert)
+  }
+  fn splice_from(&mut self, start: usize, len: usize, insert: Self) {
+    self.splice_rope(start, len, insert)
+  }
+  fn from_rope(rope: Rope<T>) -> Self { rope }
+  fn to_rope(&self) -> Rope<T> { self.clone() }
+  fn check_invariants(&self) { Rope::check_invariants(self) }
+}
+
+impl<'a, T: BaseLike> StorageCursor<'a, T> for RopeCursor<'a, T> {
+  type Storage = Rope<T>;
+  type Chunk = Chunk<'a, T>;
+
+  #[inline]
+  fn root(&self) -> &'a Rope<T> { RopeCursor::root(self) }
+  #[inline]
+  fn full_len(&self) -> usize { RopeCursor::full_len(self) }
+  #[inline]
+  fn pos(&self) -> usize { RopeCursor::pos(self) }
+  #[inline]
+  fn seek(&mut self, pos: usize) { RopeCursor::seek(self, pos) }
+  #[inline]
+  fn at(&mut self, pos: usize) -> T { RopeCursor::at(self, pos) }
+  #[inline]
+  fn leaf_at(&mut self, pos: usize) -> (usize, Chunk<'a, T>) { RopeCursor::leaf_at(self, pos) }
+}
+
+////////////////////////////////////////////////////////////////
+// BTreeRope
+
+impl<T: BaseLike> Storage<T> for BTreeRope<T> {
+  type Cursor<'a> = BTreeCursor<'a, T> where T: 'a;
+
+  fn from_vec(vec: Vec<T>) -> Self { BTreeRope::from_vec(vec) }
+  #[inline]
+  fn len(&self) -> usize { BTreeRope::len(self) }
+  #[inline]
+  fn cursor(&self) -> BTreeCursor<'_, T> { BTreeRope::cursor(self) }
+  fn slice(&self, range: Range<usize>) -> Self { BTreeRope::slice(self, range) }
+  fn append(&mut self, other: Self) { self.append_rope(other) }
+  fn splice(&mut self, start: usize, len: usize, insert: Option<Vec<T>>) {
+    BTreeRope::splice(self, start, len, insert)
+  }
+  fn splice_from(&mut self, start: usize, len: usize, insert: Self) {
+    self.splice_rope(start, len, insert)
+  }
+  fn from_rope(rope: Rope<T>) -> Self { rope.iter().collect() }
+  fn to_rope(&self) -> Rope<T> { self.iter().collect() }
+  fn check_invariants(&self) { BTreeRope::check_invariants(self) }
+}
+
+impl<'a, T: BaseLike> StorageCursor<'a, T> for BTreeCursor<'a, T> {
+  type Storage = BTreeRope<T>;
+  type Chunk = Chunk<'a, T>;
+
+  #[inline]
+  fn root(&self) -> &'a BTreeRope<T> { BTreeCursor::root(self) }
+  #[inline]
+  fn full_len(&self) -> usize { BTreeCursor::full_len(self) }
+  #[inline]
+  fn pos(&self) -> usize { BTreeCursor::pos(self) }
+  #[inline]
+  fn seek(&mut self, pos: usize) { BTreeCursor::seek(self, pos) }
+  #[inline]
+  fn at(&mut self, pos: usize) -> T { BTreeCursor::at(self, pos) }
+  #[inline]
+  fn leaf_at(&mut self, pos: usize) -> (usize, Chunk<'a, T>) { BTreeCursor::leaf_at(self, pos) }
+}
+
+////////////////////////////////////////////////////////////////
+// Vec
+
+impl<T: Copy> StorageChunk<T> for &[T] {
+  #[inline]
+  fn len(&self) -> usize { <[T]>::len(self) }
+  #[inline]
+  fn at(&self, index: usize) -> T { self[index] }
+  #[inline]
+  fn slice(&self, range: Range<usize>) -> Self { &self[range] }
+  fn copy_to(&self, out: &mut Vec<T>) { out.extend_from_slice(self) }
+}
+
+impl<T: BaseLike> Storage<T> for Vec<T> {
+  type Cursor<'a> = VecCursor<'a, T> where T: 'a;
+
+  fn from_vec(vec: Vec<T>) -> Self { vec }
+  #[inline]
+  fn len(&self) -> usize { Vec::len(self) }
+  #[inline]
+  fn cursor(&self) -> VecCursor<'_, T> { VecCursor{root: self, index: 0} }
+  fn slice(&self, range: Range<usize>) -> Self { self[range].to_vec() }
+  fn append(&mut self, other: Self) { self.extend(other) }
+  fn splice(&mut self, start: usize, len: usize, insert: Option<Vec<T>>) {
+    Vec::splice(self, start .. start + len, insert.unwrap_or_default());
+  }
+  fn splice_from(&mut self, start: usize, len: usize, insert: Self) {
+    Vec::splice(self, start .. start + len, insert);
+  }
+  fn from_rope(rope: Rope<T>) -> Self { rope.iter().collect() }
+  fn to_rope(&self) -> Rope<T> { Rope::from_slice(self) }
+}
+
+pub struct VecCursor<'a, T> {
+  root: &'a Vec<T>,
+  index: usize,
+}
+
+impl<'a, T: BaseLike> StorageCursor<'a, T> for VecCursor<'a, T> {
+  type Storage = Vec<T>;
+  type Chunk = &'a [T];
+
+  #[inline]
+  fn root(&self) -> &'a Vec<T> { self.root }
+  #[inline]
+  fn full_len(&self) -> usize { self.root.len() }
+  #[inline]
+  fn pos(&self) -> usize { self.index }
+  #[inline]
+  fn seek(&mut self, pos: usize) { self.index = pos; }
+  #[inline]
+  fn at(&mut self, pos: usize) -> T { self.root[pos] }
+  #[inline]
+  fn leaf_at(&mut self, _pos: usize) -> (usize, &'a [T]) { (0, self.root) }
+}
+
+impl<'a, T: Copy> Iterator for VecCursor<'a, T> {
+  type Item = T;
+  fn next(&mut self) -> Option<T> {
+    let result = self.root.get(self.index).copied();
+    if result.is_some() { self.index += 1; }
+    result
+  }
+}
@@ -0,0 +1,501 @@
+use std::fmt;
+use base::{Base, BaseLike, ParseError};
+
+pub mod png;
+
+// Port of the RNA canvas in ts/rna.ts.  Pixels are packed as RGBA with
+// R in the most significant byte, to match the TypeScript Bitmap.
+
+pub const W: usize = 600;
+pub const H: usize = 600;
+pub const SIZE: usize = W * H;
+
+pub type Pixel = u32;
+type Pos = usize;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dir {
+  E = 0,
+  S = 1,
+  W = 2,
+  N = 3,
+}
+
+impl Dir {
+  #[inline]
+  fn from_u8(i: u8) -> Self {
+    match i & 3 {
+      0 => Dir::E,
+      1 => Dir::S,
+      2 => Dir::W,
+      _ => Dir::N,
+    }
+  }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Color {
+  Black = 0,
+  Red = 1,
+  Green = 2,
+  Yellow = 3,
+  Blue = 4,
+  Magenta = 5,
+  Cyan = 6,
+  White = 7,
+  Transparent = 8,
+  Opaque = 9,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+  AddColor(Color),
+  ClearBucket,
+  Move,
+  TurnCounterClockwise,
+  TurnClockwise,
+  Mark,
+  Line,
+  TryFill,
+  AddBitmap,
+  Compose,
+  Clip,
+}
+
+impl Command {
+  // Returns None for RNA that isn't a recognized command (which the
+  // spec says to ignore).
+  pub fn decode<T: BaseLike>(rna: &[T; 7]) -> Option<Self> {
+    let s = rna.map(|b| b.to_base().char() as u8);
+    Some(match &s {
+      b"PIPIIIC" => Command::AddColor(Color::Black),
+      b"PIPIIIP" => Command::AddColor(Color::Red),
+      b"PIPIICC" => Command::AddColor(Color::Green),
+      b"PIPIICF" => Command::AddColor(Color::Yellow),
+      b"PIPIICP" => Command::AddColor(Color::Blue),
+      b"PIPIIFC" => Command::AddColor(Color::Magenta),
+      b"PIPIIFF" => Command::AddColor(Color::Cyan),
+      b"PIPIIPC" => Command::AddColor(Color::White),
+      b"PIPIIPF" => Command::AddColor(Color::Transparent),
+      b"PIPIIPP" => Command::AddColor(Color::Opaque),
+      b"PIIPICP" => Command::ClearBucket,
+      b"PIIIIIP" => Command::Move,
+      b"PCCCCCP" => Command::TurnCounterClockwise,
+      b"PFFFFFP" => Command::TurnClockwise,
+      b"PCCIFFP" => Command::Mark,
+      b"PFFICCP" => Command::Line,
+      b"PIIPIIP" => Command::TryFill,
+      b"PCCPFFP" => Command::AddBitmap,
+      b"PFFPCCP" => Command::Compose,
+      b"PFFICCF" => Command::Clip,
+      _ => return None,
+    })
+  }
+
+  // None if the bases aren't a command, which is ignored like any other
+  // unknown RNA.  Anything but bases is an error.
+  pub fn from_str_rna(s: &str) -> Result<Option<Self>, ParseError> {
+    let bases = Base::try_collect_from::<Vec<_>, _>(s)?;
+    Ok(<[Base; 7]>::try_from(bases).ok().and_then(|rna| Command::decode(&rna)))
+  }
+}
+
+impl fmt::Display for Command {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Command::AddColor(c) => write!(f, "color {:?}", c),
+      Command::ClearBucket => write!(f, "clear"),
+      Command::Move => write!(f, "move"),
+      Command::TurnCounterClockwise => write!(f, "ccw"),
+      Command::TurnClockwise => write!(f, "cw"),
+      Command::Mark => write!(f, "mark"),
+      Command::Line => write!(f, "line"),
+      Command::TryFill => write!(f, "fill"),
+      Command::AddBitmap => write!(f, "add bitmap"),
+      Command::Compose => write!(f, "compose"),
+      Command::Clip => write!(f, "clip"),
+    }
+  }
+}
+
+////////////////////////////////////////////////////////////////
+// Bitmap
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Bitmap(Box<[Pixel]>);
+
+impl fmt::Debug for Bitmap {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Bitmap({}x{})", W, H)
+  }
+}
+
+impl Default for Bitmap {
+  fn default() -> Self { Bitmap::new() }
+}
+
+impl Bitmap {
+  pub fn new() -> Self {
+    Bitmap(vec![0; SIZE].into_boxed_slice())
+  }
+
+  #[inline]
+  pub fn data(&self) -> &[Pixel] { &self.0 }
+
+  #[inline]
+  pub fn get(&self, x: usize, y: usize) -> Pixel { self.0[y * W + x] }
+
+  #[inline]
+  pub fn set(&mut self, x: usize, y: usize, pixel: Pixel) {
+    self.0[y * W + x] = pixel;
+  }
+
+  // Makes the whole bitmap opaque, as is done to the final image.
+  pub fn finalize(&mut self) {
+    for p in self.0.iter_mut() {
+      *p |= 0xff;
+    }
+  }
+
+  pub fn line(&mut self, p0: Pos, p1: Pos, pixel: Pixel) {
+    let (x0, y0) = ((p0 % W) as i64, (p0 / W) as i64);
+    let (x1, y1) = ((p1 % W) as i64, (p1 / W) as i64);
+    let dx = x1 - x0;
+    let dy = y1 - y0;
+    let d = dx.abs().max(dy.abs());
+    let c = if dx * dy <= 0 { 1 } else { 0 };
+    let mut x = x0 * d + ((d - c) >> 1);
+    let mut y = y0 * d + ((d - c) >> 1);
+    for _ in 0 .. d {
+      let pos = y.div_euclid(d) as usize * W + x.div_euclid(d) as usize;
+      x += dx;
+      y += dy;
+      self.0[pos] = pixel;
+    }
+    self.0[p1] = pixel;
+  }
+
+  pub fn try_fill(&mut self, pos: Pos, pixel: Pixel) {
+    let old = self.0[pos];
+    if pixel == old { return; }
+    let mut stack = vec![pos];
+    while let Some(p) = stack.pop() {
+      if self.0[p] != old { continue; }
+      self.0[p] = pixel;
+      if p >= W { stack.push(p - W); }
+      if p < SIZE - W { stack.push(p + W); }
+      let x = p % W;
+      if x > 0 { stack.push(p - 1); }
+      if x < W - 1 { stack.push(p + 1); }
+    }
+  }
+
+  // Usage: bitmaps[1].compose(&bitmaps[0]): r <- r1*(1-a0) + r0
+  pub fn compose(&self, that: &Bitmap) -> Bitmap {
+    self.combine(that, |p0, p1| {
+      let a0 = p0 & 255;
+      let mut out = 0;
+      for shift in [24, 16, 8, 0] {
+        let c0 = (p0 >> shift) & 255;
+        let c1 = (p1 >> shift) & 255;
+        out |= 255.min(c0 + c1 * (255 - a0) / 255) << shift;
+      }
+      out
+    })
+  }
+
+  // Usage: bitmaps[1].clip(&bitmaps[0]): r <- r1*a0
+  pub fn clip(&self, that: &Bitmap) -> Bitmap {
+    self.combine(that, |p0, p1| {
+      let a0 = p0 & 255;
+      let mut out = 0;
+      for shift in [24, 16, 8, 0] {
+        let c1 = (p1 >> shift) & 255;
+        out |= (c1 * a0 / 255) << shift;
+      }
+      out
+    })
+  }
+
+  #[inline]
+  fn combine<F: Fn(Pixel, Pixel) -> Pixel>(&self, that: &Bitmap, f: F) -> Bitmap {
+    Bitmap(that.0.iter().zip(self.0.iter())
+           .map(|(p0, p1)| f(*p0, *p1))
+           .collect())
+  }
+}
+
+////////////////////////////////////////////////////////////////
+// Canvas
+
+#[derive(Clone, Debug)]
+pub struct Canvas {
+  pub count: usize,
+  bucket: [u32; 10],
+  pixel: Option<Pixel>,
+  pos: Pos,
+  mark: Pos,
+  dir: Dir,
+  bitmaps: Vec<Bitmap>,
+}
+
+impl Default for Canvas {
+  fn default() -> Self { Canvas::new() }
+}
+
+// Computes the current pixel from the bucket.  Note that this follows
+// the TypeScript implementation, which only floors once at the end,
+// rather than flooring the average and the alpha scaling separately.
+fn pixel(bucket: &[u32; 10]) -> Pixel {
+  let mut n: u64 = 0;
+  let mut r: u64 = 0;
+  let mut g: u64 = 0;
+  let mut b: u64 = 0;
+  for (i, v) in bucket[.. 8].iter().enumerate() {
+    let v = *v as u64;
+    n += v;
+    if i & 1 != 0 { r += 255 * v; }
+    if i & 2 != 0 { g += 255 * v; }
+    if i & 4 != 0 { b += 255 * v; }
+  }
+  let o = bucket[Color::Opaque as usize] as u64;
+  let na = o + bucket[Color::Transparent as usize] as u64;
+  let a = (255 * o).checked_div(na).unwrap_or(255);
+  r = (r * a).checked_div(n * 255).unwrap_or(0);
+  g = (g * a).checked_div(n * 255).unwrap_or(0);
+  b = (b * a).checked_div(n * 255).unwrap_or(0);
+  ((r << 24) | (g << 16) | (b << 8) | a) as Pixel
+}
+
+impl Canvas {
+  pub fn new() -> Self {
+    Canvas{count: 0, bucket: [0; 10], pixel: Some(255), pos: 0, mark: 0,
+           dir: Dir::E, bitmaps: vec![Bitmap::new()]}
+  }
+
+  #[inline]
+  pub fn bitmaps(&self) -> &[Bitmap] { &self.bitmaps }
+
+  #[inline]
+  pub fn pos(&self) -> (usize, usize) { (self.pos % W, self.pos / W) }
+
+  #[inline]
+  pub fn dir(&self) -> Dir { self.dir }
+
+  pub fn process<T: BaseLike>(&mut self, rna: &[T; 7]) {
+    self.count += 1;
+    if let Some(cmd) = Command::decode(rna) {
+      self.exec(cmd);
+    }
+  }
+
+  pub fn process_all<T: BaseLike>(&mut self, rna: &[[T; 7]]) {
+    for r in rna {
+      self.process(r);
+    }
+  }
+
+  pub fn exec(&mut self, cmd: Command) {
+    match cmd {
+      Command::AddColor(c) => {
+        self.pixel = None;
+        self.bucket[c as usize] += 1;
+      }
+      Command::ClearBucket => {
+        self.pixel = Some(255);
+        self.bucket = [0; 10];
+      }
+      Command::Move => {
+        let (mut x, mut y) = self.pos();
+        match self.dir {
+          Dir::E => { x += 1; }
+          Dir::S => { y += 1; }
+          Dir::W => { x += W - 1; }
+          Dir::N => { y += H - 1; }
+        }
+        self.pos = (y % H) * W + x % W;
+      }
+      Command::TurnCounterClockwise => {
+        self.dir = Dir::from_u8(self.dir as u8 + 3);
+      }
+      Command::TurnClockwise => {
+        self.dir = Dir::from_u8(self.dir as u8 + 1);
+      }
+      Command::Mark => {
+        self.mark = self.pos;
+      }
+      Command::Line => {
+        let pixel = self.current_pixel();
+        let (pos, mark) = (self.pos, self.mark);
+        self.top().line(pos, mark, pixel);
+      }
+      Command::TryFill => {
+        let pixel = self.current_pixel();
+        let pos = self.pos;
+        self.top().try_fill(pos, pixel);
+      }
+      Command::AddBitmap => {
+        if self.bitmaps.len() < 10 {
+          self.bitmaps.push(Bitmap::new());
+        }
+      }
+      Command::Compose => {
+        if self.bitmaps.len() < 2 { return; }
+        let top = self.bitmaps.pop().unwrap();
+        let bottom = self.bitmaps.pop().unwrap();
+        self.bitmaps.push(bottom.compose(&top));
+      }
+      Command::Clip => {
+        if self.bitmaps.len() < 2 { return; }
+        let top = self.bitmaps.pop().unwrap();
+        let bottom = self.bitmaps.pop().unwrap();
+        self.bitmaps.push(bottom.clip(&top));
+      }
+    }
+  }
+
+  // Returns the final image: the bottom bitmap, made fully opaque.
+  pub fn finish(&self) -> Bitmap {
+    let mut out = self.bitmaps[0].clone();
+    out.finalize();
+    out
+  }
+
+  // All current bitmaps stacked vertically, for debugging.
+  pub fn snapshot(&self) -> png::Image {
+    png::Image::from_bitmaps(&self.bitmaps)
+  }
+
+  #[inline]
+  fn current_pixel(&mut self) -> Pixel {
+    *self.pixel.get_or_insert_with(|| pixel(&self.bucket))
+  }
+
+  #[inline]
+  fn top(&mut self) -> &mut Bitmap {
+    self.bitmaps.last_mut().unwrap()
+  }
+}
+
+
+#[cfg(test)]
+mod rna_tests {
+  use super::*;
+
+  fn run(cmds: &[&str]) -> Canvas {
+    let mut canvas = Canvas::new();
+    for s in cmds {
+      canvas.exec(Command::from_str_rna(s).unwrap().unwrap());
+    }
+    canvas
+  }
+
+  #[test]
+  fn decode() {
+    assert_eq!(Command::from_str_rna("PIPIIIP"),
+               Ok(Some(Command::AddColor(Color::Red))));
+    assert_eq!(Command::from_str_rna("PFFICCF"), Ok(Some(Command::Clip)));
+    assert_eq!(Command::from_str_rna("PIPIIII"), Ok(None));
+    assert_eq!(Command::from_str_rna("PIPII"), Ok(None));
+    assert_eq!(Command::from_str_rna("PIPIXIP").map_err(|e| e.column), Err(5));
+  }
+
+  // Examples from the bucket section of the spec.
+  #[test]
+  fn bucket_pixel() {
+    let mut bucket = [0; 10];
+    bucket[Color::Transparent as usize] = 1;
+    bucket[Color::Opaque as usize] = 2;
+    bucket[Color::Black as usize] = 1;
+    bucket[Color::Yellow as usize] = 1;
+    bucket[Color::Cyan as usize] = 1;
+    assert_eq!(pixel(&bucket), 0x387138aa);
+
+    let mut bucket = [0; 10];
+    bucket[Color::Transparent as usize] = 1;
+    bucket[Color::Opaque as usize] = 3;
+    bucket[Color::White as usize] = 1;
+    assert_eq!(pixel(&bucket), 0xbfbfbfbf);
+
+    let mut bucket = [0; 10];
+    bucket[Color::Transparent as usize] = 1;
+    bucket[Color::Red as usize] = 5;
+    assert_eq!(pixel(&bucket), 0x00000000);
+
+    assert_eq!(pixel(&[0; 10]), 0x000000ff);
+  }
+
+  #[test]
+  fn move_wraps() {
+    let mut canvas = run(&["PCCCCCP", "PIIIIIP"]);
+    assert_eq!(canvas.pos(), (0, H - 1));
+    canvas.exec(Command::TurnCounterClockwise);
+    canvas.exec(Command::Move);
+    assert_eq!(canvas.pos(), (W - 1, H - 1));
+    canvas.exec(Command::TurnClockwise);
+    canvas.exec(Command::TurnClockwise);
+    canvas.exec(Command::Move);
+    canvas.exec(Command::Move);
+    assert_eq!(canvas.pos(), (1, H - 1));
+  }
+
+  #[test]
+  fn line() {
+    let mut bitmap = Bitmap::new();
+    bitmap.line(0, 3 * W + 6, 7);
+    let drawn = (0 .. SIZE).filter(|p| bitmap.data()[*p] == 7)
+        .map(|p| (p % W, p / W)).collect::<Vec<_>>();
+    assert_eq!(drawn, vec![(0, 0), (1, 1), (2, 1), (3, 2),
+                           (4, 2), (5, 3), (6, 3)]);
+  }
+
+  #[test]
+  fn fill() {
+    let mut bitmap = Bitmap::new();
+    for i in 0 .. 10 {
+      bitmap.set(i, 10, 1);
+      bitmap.set(10, i, 1);
+    }
+    bitmap.try_fill(0, 2);
+    assert_eq!(bitmap.get(9, 9), 2);
+    assert_eq!(bitmap.get(10, 10), 0);
+    assert_eq!(bitmap.get(11, 0), 0);
+    assert_eq!(bitmap.data().iter().filter(|p| **p == 2).count(), 100);
+  }
+
+  #[test]
+  fn compose_and_clip() {
+    let mut top = Bitmap::new();
+    let mut bottom = Bitmap::new();
+    top.set(0, 0, 0x40404080);
+    bottom.set(0, 0, 0xff0000ff);
+    assert_eq!(bottom.compose(&top).get(0, 0), 0xbf4040ff);
+    assert_eq!(bottom.clip(&top).get(0, 0), 0x80000080);
+  }
+
+  #[test]
+  fn bitmap_stack() {
+    let mut canvas = run(&["PCCPFFP", "PCCPFFP", "PFFPCCP"]);
+    assert_eq!(canvas.bitmaps().len(), 2);
+    canvas.exec(Command::Clip);
+    canvas.exec(Command::Clip);
+    assert_eq!(canvas.bitmaps().len(), 1);
+    for _ in 0 .. 12 {
+      canvas.exec(Command::AddBitmap);
+    }
+    assert_eq!(canvas.bitmaps().len(), 10);
+  }
+
+  #[test]
+  fn draw_and_finish() {
+    let canvas = run(&["PIPIIIP", "PIPIIPP", "PCCIFFP",
+                       "PIIIIIP", "PIIIIIP", "PFFICCP"]);
+    let image = canvas.finish();
+    assert_eq!(image.get(0, 0), 0xff0000ff);
+    assert_eq!(image.get(1, 0), 0xff0000ff);
+    assert_eq!(image.get(2, 0), 0xff0000ff);
+    assert_eq!(image.get(3, 0), 0x000000ff);
+  }
+}
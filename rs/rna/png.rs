@@ -0,0 +1,372 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+use flate2::Compression;
+use flate2::Crc;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use crate::{Bitmap, Pixel, H, W};
+
+// Minimal PNG support: writes 8-bit RGBA, and reads back any
+// non-interlaced 8-bit image (gray, gray+alpha, RGB or RGBA), which
+// covers everything the TypeScript renderer wrote to pages/.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+// Pixels are packed as RGBA with R in the most significant byte, same
+// as Bitmap.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+  pub width: usize,
+  pub height: usize,
+  pub pixels: Vec<Pixel>,
+}
+
+impl Image {
+  pub fn new(width: usize, height: usize, pixels: Vec<Pixel>) -> Self {
+    assert_eq!(pixels.len(), width * height, "Bad buffer size for {}x{}", width, height);
+    Image{width, height, pixels}
+  }
+
+  // Stacks the bitmaps vertically, bottom bitmap first (this is the
+  // layout of the TypeScript snapshots).
+  pub fn from_bitmaps(bitmaps: &[Bitmap]) -> Self {
+    let mut pixels = Vec::with_capacity(W * H * bitmaps.len());
+    for b in bitmaps {
+      pixels.extend_from_slice(b.data());
+    }
+    Image::new(W, H * bitmaps.len(), pixels)
+  }
+
+  pub fn write<O: Write>(&self, out: O) -> io::Result<()> {
+    encode(out, self.width, self.height, &self.pixels)
+  }
+
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    self.write(&mut out)?;
+    out.flush()
+  }
+
+  pub fn read<I: Read>(input: I) -> io::Result<Self> {
+    decode(input)
+  }
+
+  pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    decode(io::BufReader::new(File::open(path)?))
+  }
+}
+
+impl From<&Bitmap> for Image {
+  fn from(bitmap: &Bitmap) -> Self {
+    Image::new(W, H, bitmap.data().to_vec())
+  }
+}
+
+fn invalid<T>(msg: String) -> io::Result<T> {
+  Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+////////////////////////////////////////////////////////////////
+// Encoder
+
+pub fn encode<O: Write>(mut out: O, width: usize, height: usize,
+                        pixels: &[Pixel]) -> io::Result<()> {
+  if pixels.len() != width * height {
+    return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Got {} pixels for {}x{} image", pixels.len(), width, height)));
+  }
+  out.write_all(&SIGNATURE)?;
+
+  let mut ihdr = Vec::with_capacity(13);
+  ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+  ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+  // bit depth 8, color type 6 (RGBA), deflate, adaptive filter, no interlace
+  ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+  write_chunk(&mut out, b"IHDR", &ihdr)?;
+
+  let stride = width * 4;
+  let mut z = ZlibEncoder::new(Vec::new(), Compression::default());
+  let mut prev = vec![0u8; stride];
+  let mut cur = vec![0u8; stride];
+  let mut filtered = vec![0u8; stride];
+  let mut best = vec![0u8; stride];
+  for y in 0 .. height {
+    for (x, p) in pixels[y * width .. (y + 1) * width].iter().enumerate() {
+      cur[x * 4 .. x * 4 + 4].copy_from_slice(&p.to_be_bytes());
+    }
+    // Standard heuristic: pick the filter with the smallest sum of
+    // absolute (signed) differences.
+    let mut best_filter = 0;
+    let mut best_score = u64::MAX;
+    for filter in 0 .. 5 {
+      apply_filter(filter, &cur, &prev, &mut filtered);
+      let score = filtered.iter().map(|b| (*b as i8).unsigned_abs() as u64).sum();
+      if score < best_score {
+        best_score = score;
+        best_filter = filter;
+        best.copy_from_slice(&filtered);
+      }
+    }
+    z.write_all(&[best_filter])?;
+    z.write_all(&best)?;
+    std::mem::swap(&mut prev, &mut cur);
+  }
+  write_chunk(&mut out, b"IDAT", &z.finish()?)?;
+  write_chunk(&mut out, b"IEND", &[])
+}
+
+fn write_chunk<O: Write>(out: &mut O, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+  out.write_all(&(data.len() as u32).to_be_bytes())?;
+  out.write_all(kind)?;
+  out.write_all(data)?;
+  let mut crc = Crc::new();
+  crc.update(kind);
+  crc.update(data);
+  out.write_all(&crc.sum().to_be_bytes())
+}
+
+fn apply_filter(filter: u8, cur: &[u8], prev: &[u8], out: &mut [u8]) {
+  for i in 0 .. cur.len() {
+    let a = if i >= 4 { cur[i - 4] } else { 0 };
+    let b = prev[i];
+    let c = if i >= 4 { prev[i - 4] } else { 0 };
+    out[i] = cur[i].wrapping_sub(predict(filter, a, b, c));
+  }
+}
+
+#[inline]
+fn predict(filter: u8, a: u8, b: u8, c: u8) -> u8 {
+  match filter {
+    0 => 0,
+    1 => a,
+    2 => b,
+    3 => ((a as u16 + b as u16) / 2) as u8,
+    _ => paeth(a, b, c),
+  }
+}
+
+#[inline]
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+  let p = a as i16 + b as i16 - c as i16;
+  let pa = (p - a as i16).abs();
+  let pb = (p - b as i16).abs();
+  let pc = (p - c as i16).abs();
+  if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+}
+
+////////////////////////////////////////////////////////////////
+// Decoder
+
+pub fn decode<I: Read>(mut input: I) -> io::Result<Image> {
+  let mut sig = [0u8; 8];
+  input.read_exact(&mut sig)?;
+  if sig != SIGNATURE { return invalid("Not a PNG file".to_string()); }
+
+  let mut header: Option<(usize, usize, usize)> = None;
+  let mut idat: Vec<u8> = Vec::new();
+  loop {
+    let mut buf = [0u8; 8];
+    input.read_exact(&mut buf)?;
+    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    let kind = [buf[4], buf[5], buf[6], buf[7]];
+    // The length isn't checked until the CRC is, so only allocate for
+    // what the file actually holds.
+    let mut data = Vec::new();
+    (&mut input).take(len as u64).read_to_end(&mut data)?;
+    if data.len() < len { return Err(io::ErrorKind::UnexpectedEof.into()); }
+    let mut crc_buf = [0u8; 4];
+    input.read_exact(&mut crc_buf)?;
+    let mut crc = Crc::new();
+    crc.update(&kind);
+    crc.update(&data);
+    if crc.sum() != u32::from_be_bytes(crc_buf) {
+      return invalid(format!("Bad CRC in {} chunk",
+                             String::from_utf8_lossy(&kind)));
+    }
+    match &kind {
+      b"IHDR" => {
+        if len != 13 { return invalid("Bad IHDR length".to_string()); }
+        let width = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        let height = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let (depth, color, interlace) = (data[8], data[9], data[12]);
+        if depth != 8 {
+          return invalid(format!("Unsupported bit depth {}", depth));
+        }
+        if data[10] != 0 || data[11] != 0 {
+          return invalid("Unknown compression or filter method".to_string());
+        }
+        if interlace != 0 {
+          return invalid("Interlaced PNGs are not supported".to_string());
+        }
+        let channels = match color {
+          0 => 1,
+          2 => 3,
+          4 => 2,
+          6 => 4,
+          _ => { return invalid(format!("Unsupported color type {}", color)); }
+        };
+        header = Some((width, height, channels));
+      }
+      b"IDAT" => { idat.extend_from_slice(&data); }
+      b"IEND" => { break; }
+      _ => {
+        // Ancillary chunks are safe to ignore, critical ones are not.
+        if kind[0] & 0x20 == 0 {
+          return invalid(format!("Unsupported critical chunk {}",
+                                 String::from_utf8_lossy(&kind)));
+        }
+      }
+    }
+  }
+  let (width, height, channels) = match header {
+    Some(h) => h,
+    None => { return invalid("Missing IHDR".to_string()); }
+  };
+
+  // The header's dimensions are only trusted once the data bears them
+  // out, so nothing is sized from them before that.
+  let size = width.checked_mul(channels)
+      .and_then(|stride| Some((stride, (stride + 1).checked_mul(height)?)));
+  let (stride, size) = match size {
+    Some(size) => size,
+    None => { return invalid(format!("Image too big: {}x{}", width, height)); }
+  };
+  let mut raw = Vec::new();
+  ZlibDecoder::new(&idat[..]).take(size as u64).read_to_end(&mut raw)?;
+  if raw.len() < size {
+    return invalid(format!("Image data too short: {} bytes", raw.len()));
+  }
+
+  let mut pixels = Vec::with_capacity(width * height);
+  let mut prev = vec![0u8; stride];
+  let mut cur = vec![0u8; stride];
+  for y in 0 .. height {
+    let row = &raw[y * (stride + 1) .. (y + 1) * (stride + 1)];
+    let filter = row[0];
+    if filter > 4 { return invalid(format!("Bad filter type {}", filter)); }
+    for i in 0 .. stride {
+      let a = if i >= channels { cur[i - channels] } else { 0 };
+      let b = prev[i];
+      let c = if i >= channels { prev[i - channels] } else { 0 };
+      cur[i] = row[i + 1].wrapping_add(predict(filter, a, b, c));
+    }
+    for px in cur.chunks_exact(channels) {
+      let [r, g, b, a] = match *px {
+        [v] => [v, v, v, 255],
+        [v, a] => [v, v, v, a],
+        [r, g, b] => [r, g, b, 255],
+        [r, g, b, a] => [r, g, b, a],
+        _ => unreachable!(),
+      };
+      pixels.push(Pixel::from_be_bytes([r, g, b, a]));
+    }
+    std::mem::swap(&mut prev, &mut cur);
+  }
+  Ok(Image::new(width, height, pixels))
+}
+
+
+#[cfg(test)]
+mod png_tests {
+  use super::*;
+
+  fn roundtrip(image: &Image) -> Image {
+    let mut buf = Vec::new();
+    image.write(&mut buf).unwrap();
+    Image::read(&buf[..]).unwrap()
+  }
+
+  #[test]
+  fn roundtrip_small() {
+    let image = Image::new(3, 2, vec![0xff0000ff, 0x00ff0080, 0x0000ff00,
+                                      0x12345678, 0x9abcdef0, 0xffffffff]);
+    assert_eq!(roundtrip(&image), image);
+  }
+
+  #[test]
+  fn roundtrip_bitmaps() {
+    let mut bottom = Bitmap::new();
+    let mut top = Bitmap::new();
+    bottom.line(0, 599 * W + 599, 0xff8040ff);
+    top.try_fill(W * 300 + 300, 0x20406080);
+    let image = Image::from_bitmaps(&[bottom, top]);
+    assert_eq!(image.height, 2 * H);
+    assert_eq!(roundtrip(&image), image);
+  }
+
+  #[test]
+  fn encode_bad_size() {
+    let mut buf = Vec::new();
+    assert!(encode(&mut buf, 2, 2, &[0, 0, 0]).is_err());
+  }
+
+  #[test]
+  fn decode_bad_crc() {
+    let mut buf = Vec::new();
+    Image::new(1, 1, vec![0x11223344]).write(&mut buf).unwrap();
+    buf[20] ^= 1; // inside IHDR
+    assert!(Image::read(&buf[..]).is_err());
+  }
+
+  #[test]
+  fn decode_rgb() {
+    // Hand-built 2x1 RGB image using the Sub filter.
+    let mut raw = Vec::new();
+    let mut z = ZlibEncoder::new(&mut raw, Compression::default());
+    z.write_all(&[1, 10, 20, 30, 5, 5, 5]).unwrap();
+    z.finish().unwrap();
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&SIGNATURE);
+    write_chunk(&mut buf, b"IHDR", &[0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0]).unwrap();
+    write_chunk(&mut buf, b"IDAT", &raw).unwrap();
+    write_chunk(&mut buf, b"IEND", &[]).unwrap();
+    assert_eq!(Image::read(&buf[..]).unwrap(),
+               Image::new(2, 1, vec![0x0a141eff, 0x0f1923ff]));
+  }
+
+  // A header for an image too big to allocate, with correct CRCs.
+  fn huge(width: u32, height: u32) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&SIGNATURE);
+    write_chunk(&mut buf, b"IHDR", &header).unwrap();
+    buf
+  }
+
+  #[test]
+  fn decode_bad_sizes() {
+    // Dimensions that overflow, or that the data doesn't fill.
+    for (w, h) in [(u32::MAX, u32::MAX), (1 << 31, 4), (60000, 60000)] {
+      let mut buf = huge(w, h);
+      write_chunk(&mut buf, b"IDAT", &[]).unwrap();
+      write_chunk(&mut buf, b"IEND", &[]).unwrap();
+      assert!(Image::read(&buf[..]).is_err());
+    }
+    // A chunk claiming far more data than the file has.
+    let mut buf = huge(1, 1);
+    buf.extend_from_slice(&[0x7f, 0xff, 0xff, 0xff]);
+    buf.extend_from_slice(b"IDAT");
+    assert_eq!(Image::read(&buf[..]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+  }
+
+  #[test]
+  fn decode_bad_methods() {
+    // Compression, filter and interlace methods other than 0.
+    for k in 10 .. 13 {
+      let mut header = [0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0, 0];
+      header[k] = 1;
+      let mut buf = Vec::new();
+      buf.extend_from_slice(&SIGNATURE);
+      write_chunk(&mut buf, b"IHDR", &header).unwrap();
+      write_chunk(&mut buf, b"IEND", &[]).unwrap();
+      assert_eq!(Image::read(&buf[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+  }
+}
@@ -0,0 +1,85 @@
+// Compares Rope and BTreeRope on workloads shaped like Endo's: a long
+// sequence that is read from the front, sought around in, and rebuilt
+// from slices of itself.  Run with `cargo bench -p rope`.
+
+use rope::{BTreeRope, Rope};
+use std::time::Instant;
+
+const LEN: usize = 8 << 20;
+
+// A small LCG, so that both trees see the same operations.
+struct Rng(u64);
+
+impl Rng {
+  fn below(&mut self, n: usize) -> usize {
+    self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    ((self.0 >> 33) as usize) % n
+  }
+}
+
+fn time<F: FnOnce() -> u64>(f: F) -> (f64, u64) {
+  let start = Instant::now();
+  let check = f();
+  (start.elapsed().as_secs_f64() * 1000.0, check)
+}
+
+// The two trees have the same API but no common trait, so the workloads
+// are written once as a macro.
+macro_rules! workloads {
+  ($rope:ident) => {{
+    let mut results = vec![];
+    let mut rope: $rope<u8> = $rope::new();
+    results.push(("build", time(|| {
+      rope = (0 .. LEN).map(|i| (i % 4) as u8).collect();
+      rope.len() as u64
+    })));
+    results.push(("seek", time(|| {
+      let mut rng = Rng(1);
+      let mut cursor = rope.cursor();
+      (0 .. 2_000_000).map(|_| cursor.at(rng.below(LEN)) as u64).sum()
+    })));
+    results.push(("scan", time(|| {
+      rope.chunks().map(|c| c.iter().map(|b| b as u64).sum::<u64>()).sum()
+    })));
+    results.push(("templates", time(|| {
+      // Replace a prefix with slices of the rest, keeping the length.
+      let mut rng = Rng(2);
+      for _ in 0 .. 20_000 {
+        let mut insert = $rope::new();
+        for _ in 0 .. 4 {
+          let start = rng.below(rope.len() - 2000);
+          insert.append_rope(rope.slice(start .. start + rng.below(2000)));
+        }
+        let len = insert.len() + rng.below(50);
+        rope.splice_rope(0, len, insert);
+        let mut cursor = rope.cursor();
+        for i in 0 .. 20 {
+          cursor.at(i);
+        }
+      }
+      rope.len() as u64
+    })));
+    results.push(("small splices", time(|| {
+      let mut rng = Rng(3);
+      for _ in 0 .. 200_000 {
+        let pos = rng.below(rope.len() - 2);
+        rope.splice(pos, 2, Some(vec![1, 2, 3]));
+      }
+      rope.len() as u64
+    })));
+    results.push(("scan after", time(|| {
+      rope.chunks().map(|c| c.iter().map(|b| b as u64).sum::<u64>()).sum()
+    })));
+    results
+  }};
+}
+
+fn main() {
+  let rope = workloads!(Rope);
+  let btree = workloads!(BTreeRope);
+  println!("{:16} {:>10} {:>10}", "ms", "Rope", "BTreeRope");
+  for ((name, (a, x)), (_, (b, y))) in rope.iter().zip(&btree) {
+    assert_eq!(x, y, "{} gave different results", name);
+    println!("{:16} {:>10.1} {:>10.1}", name, a, b);
+  }
+}
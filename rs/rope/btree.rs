@@ -0,0 +1,708 @@
+use std::cmp;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::{Chunk, Element, Leaf};
+
+// An alternative to Rope with wide branches, so that seeking makes a
+// few hops through short arrays of lengths rather than dozens through
+// App nodes.  All the leaves are at the same depth.  Branches other
+// than the root have MIN_CHILDREN to MAX_CHILDREN children, and leaves
+// other than the root follow the element's leaf size policy.  Nodes are
+// shared and copied on write, as in Rope.
+#[derive(Clone, Debug)]
+pub struct BTreeRope<T: Element>(Option<Arc<BNode<T>>>);
+
+pub const MAX_CHILDREN: usize = 16;
+pub const MIN_CHILDREN: usize = MAX_CHILDREN / 2;
+
+#[derive(Clone, Debug)]
+enum BNode<T: Element> {
+  Branch(Branch<T>),
+  Leaf(T::Leaf),
+}
+
+#[derive(Clone, Debug)]
+struct Branch<T: Element> {
+  // ends[i] is the total length of children[..= i], for binary search.
+  ends: Vec<usize>,
+  children: Vec<BTreeRope<T>>,
+  height: u8,
+}
+
+impl<T: Element> Branch<T> {
+  fn new(children: Vec<BTreeRope<T>>) -> Self {
+    let height = children[0].height() + 1;
+    let mut branch = Branch{ends: vec![], children, height};
+    branch.fix_ends();
+    branch
+  }
+
+  fn fix_ends(&mut self) {
+    self.ends.clear();
+    let mut end = 0;
+    for child in &self.children {
+      end += child.len();
+      self.ends.push(end);
+    }
+  }
+
+  // Index of the child containing `pos`, and where that child starts.
+  #[inline]
+  fn find(&self, pos: usize) -> (usize, usize) {
+    let i = cmp::min(self.ends.partition_point(|e| *e <= pos), self.ends.len() - 1);
+    (i, if i == 0 { 0 } else { self.ends[i - 1] })
+  }
+
+  // Splits off the upper half of the children if there are too many.
+  fn split_if_full(&mut self) -> Option<BTreeRope<T>> {
+    if self.children.len() <= MAX_CHILDREN { return None; }
+    let rest = self.children.split_off(self.children.len() / 2);
+    self.fix_ends();
+    Some(BTreeRope::branch(rest))
+  }
+}
+
+impl<T: Element> FromIterator<T> for BTreeRope<T> {
+  fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+    let mut leaves = vec![];
+    let mut buf = Vec::with_capacity(T::MAX_LEAF);
+    for elem in iter {
+      buf.push(elem);
+      if buf.len() == T::MAX_LEAF {
+        let full = std::mem::replace(&mut buf, Vec::with_capacity(T::MAX_LEAF));
+        leaves.push(BTreeRope::leaf(T::Leaf::from_vec(full)));
+      }
+    }
+    // The full leaves make a tree directly, and the short one at the end
+    // is joined on.
+    BTreeRope::concat(BTreeRope::build(leaves), BTreeRope::from_vec(buf))
+  }
+}
+
+impl<T: Element> Default for BTreeRope<T> {
+  fn default() -> Self { BTreeRope(None) }
+}
+
+impl<T: Element> BTreeRope<T> {
+
+  pub fn new() -> Self { BTreeRope(None) }
+
+  pub fn from_slice(slice: &[T]) -> Self {
+    if slice.len() > T::MAX_LEAF {
+      return slice.iter().copied().collect();
+    }
+    BTreeRope::leaf(T::Leaf::from_slice(slice))
+  }
+
+  pub fn from_vec(vec: Vec<T>) -> Self {
+    if vec.len() > T::MAX_LEAF {
+      return BTreeRope::from_slice(&vec);
+    }
+    BTreeRope::leaf(T::Leaf::from_vec(vec))
+  }
+
+  fn leaf(leaf: T::Leaf) -> Self {
+    BTreeRope(if leaf.is_empty() { None } else { Some(Arc::new(BNode::Leaf(leaf))) })
+  }
+
+  fn branch(children: Vec<BTreeRope<T>>) -> Self {
+    BTreeRope(Some(Arc::new(BNode::Branch(Branch::new(children)))))
+  }
+
+  // A root for the given siblings, which may be too few for a branch.
+  fn from_children(mut children: Vec<BTreeRope<T>>) -> Self {
+    match children.len() {
+      0 => BTreeRope::new(),
+      1 => children.pop().unwrap(),
+      _ => BTreeRope::branch(children),
+    }
+  }
+
+  // Builds a tree bottom-up from full siblings.
+  fn build(mut level: Vec<BTreeRope<T>>) -> Self {
+    while level.len() > 1 {
+      let mut groups = level.chunks(MAX_CHILDREN).map(|c| c.to_vec()).collect::<Vec<_>>();
+      let n = groups.len();
+      if n > 1 && groups[n - 1].len() < MIN_CHILDREN {
+        // Share the last two groups out evenly.
+        let mut last = groups.pop().unwrap();
+        let prev = groups.last_mut().unwrap();
+        prev.append(&mut last);
+        let rest = prev.split_off(prev.len() / 2);
+        groups.push(rest);
+      }
+      level = groups.into_iter().map(BTreeRope::branch).collect();
+    }
+    level.pop().unwrap_or_default()
+  }
+
+  ////////////////////////////////////////////////////////////////
+  // Accessors
+
+  #[inline]
+  pub fn len(&self) -> usize {
+    match self.0.as_deref() {
+      None => 0,
+      Some(BNode::Leaf(v)) => v.len(),
+      Some(BNode::Branch(b)) => *b.ends.last().unwrap(),
+    }
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.0.is_none()
+  }
+
+  // Leaves have height 0, as in Rope::dep.
+  #[inline]
+  pub fn height(&self) -> u8 {
+    match self.0.as_deref() {
+      None|Some(BNode::Leaf(_)) => 0,
+      Some(BNode::Branch(b)) => b.height,
+    }
+  }
+
+  #[inline]
+  pub fn ptr_eq(&self, other: &BTreeRope<T>) -> bool {
+    match (&self.0, &other.0) {
+      (None, None) => true,
+      (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+      _ => false,
+    }
+  }
+
+  #[inline]
+  pub fn cursor<'a>(&'a self) -> BTreeCursor<'a, T> {
+    BTreeCursor::new(self)
+  }
+
+  #[inline]
+  pub fn iter<'a>(&'a self) -> BTreeCursor<'a, T> {
+    BTreeCursor::new(self)
+  }
+
+  #[inline]
+  pub fn chunks<'a>(&'a self) -> BTreeChunks<'a, T> {
+    self.cursor().chunks(0 .. self.len())
+  }
+
+  #[inline]
+  fn node_mut(&mut self) -> Option<&mut BNode<T>> {
+    self.0.as_mut().map(Arc::make_mut)
+  }
+
+  fn branch_mut(&mut self) -> &mut Branch<T> {
+    match self.node_mut() {
+      Some(BNode::Branch(b)) => b,
+      _ => panic!("branch_mut on a leaf"),
+    }
+  }
+
+  ////////////////////////////////////////////////////////////////
+  // Joiners
+
+  pub fn append_rope(&mut self, right: BTreeRope<T>) {
+    let left = std::mem::take(self);
+    *self = BTreeRope::concat(left, right);
+  }
+
+  // Joins two trees in O(B * |height(left) - height(right)|) by adding
+  // the shorter one to the taller one's inner spine.
+  pub fn concat(mut left: BTreeRope<T>, mut right: BTreeRope<T>) -> BTreeRope<T> {
+    if right.is_empty() { return left; }
+    if left.is_empty() { return right; }
+    match left.height().cmp(&right.height()) {
+      cmp::Ordering::Equal => BTreeRope::from_children(BTreeRope::merge(left, right)),
+      cmp::Ordering::Greater => match left.push_back(right) {
+        None => left,
+        Some(extra) => BTreeRope::branch(vec![left, extra]),
+      },
+      cmp::Ordering::Less => match right.push_front(left) {
+        None => right,
+        Some(extra) => BTreeRope::branch(vec![extra, right]),
+      },
+    }
+  }
+
+  // Joins two trees of the same height into one or two valid siblings.
+  fn merge(mut left: BTreeRope<T>, mut right: BTreeRope<T>) -> Vec<BTreeRope<T>> {
+    let (l, r) = (left.len(), right.len());
+    match (left.node_mut(), right.node_mut()) {
+      (Some(BNode::Leaf(a)), Some(BNode::Leaf(b))) => {
+        if l + r <= T::MAX_LEAF {
+          a.append(b);
+          return vec![left];
+        } else if l < T::MIN_LEAF {
+          let rest = b.split_off(T::MIN_LEAF - l);
+          a.append(b);
+          *b = rest;
+        } else if r < T::MIN_LEAF {
+          let mut tail = a.split_off(l - (T::MIN_LEAF - r));
+          tail.append(b);
+          *b = tail;
+        }
+      }
+      (Some(BNode::Branch(a)), Some(BNode::Branch(b))) => {
+        let total = a.children.len() + b.children.len();
+        if total <= MAX_CHILDREN {
+          a.children.append(&mut b.children);
+          a.fix_ends();
+          return vec![left];
+        } else if a.children.len() < MIN_CHILDREN || b.children.len() < MIN_CHILDREN {
+          a.children.append(&mut b.children);
+          b.children = a.children.split_off(total / 2);
+          a.fix_ends();
+          b.fix_ends();
+        }
+      }
+      _ => panic!("merge needs two nodes of the same height"),
+    }
+    vec![left, right]
+  }
+
+  // Adds a shorter tree after the last leaf.  Returns a new right
+  // sibling if this branch overflowed.
+  fn push_back(&mut self, right: BTreeRope<T>) -> Option<BTreeRope<T>> {
+    let branch = self.branch_mut();
+    let mut last = branch.children.pop().unwrap();
+    if last.height() == right.height() {
+      branch.children.extend(BTreeRope::merge(last, right));
+    } else {
+      let extra = last.push_back(right);
+      branch.children.push(last);
+      branch.children.extend(extra);
+    }
+    branch.fix_ends();
+    branch.split_if_full()
+  }
+
+  // Adds a shorter tree before the first leaf.  Returns a new left
+  // sibling if this branch overflowed.
+  fn push_front(&mut self, left: BTreeRope<T>) -> Option<BTreeRope<T>> {
+    let branch = self.branch_mut();
+    let mut first = branch.children.remove(0);
+    let mut front = if first.height() == left.height() {
+      BTreeRope::merge(left, first)
+    } else {
+      match first.push_front(left) {
+        None => vec![first],
+        Some(extra) => vec![extra, first],
+      }
+    };
+    front.append(&mut branch.children);
+    branch.children = front;
+    if branch.children.len() <= MAX_CHILDREN {
+      branch.fix_ends();
+      return None;
+    }
+    let rest = branch.children.split_off(branch.children.len() / 2);
+    let extra = std::mem::replace(&mut branch.children, rest);
+    branch.fix_ends();
+    Some(BTreeRope::branch(extra))
+  }
+
+  ////////////////////////////////////////////////////////////////
+  // Splitters
+
+  // Truncates this tree to `at` and returns the rest, in O(B log n).
+  pub fn split_off(&mut self, at: usize) -> BTreeRope<T> {
+    if at == 0 { return std::mem::take(self); }
+    if at >= self.len() { return BTreeRope::new(); }
+    match self.node_mut() {
+      None => BTreeRope::new(),
+      Some(BNode::Leaf(arr)) => BTreeRope::leaf(arr.split_off(at)),
+      Some(BNode::Branch(branch)) => {
+        let (i, start) = branch.find(at);
+        let after = branch.children.split_off(i + 1);
+        let mut mid = branch.children.pop().unwrap();
+        let before = std::mem::take(&mut branch.children);
+        let rest = mid.split_off(at - start);
+        *self = BTreeRope::concat(BTreeRope::from_children(before), mid);
+        BTreeRope::concat(rest, BTreeRope::from_children(after))
+      }
+    }
+  }
+
+  pub fn slice(&self, range: Range<usize>) -> BTreeRope<T> {
+    let mut out = self.clone();
+    let mut rest = out.split_off(range.start);
+    rest.split_off(range.end - range.start);
+    rest
+  }
+
+  ////////////////////////////////////////////////////////////////
+  // Splice
+
+  pub fn splice(&mut self, start: usize, length: usize,
+                insert: Option<Vec<T>>) {
+    let insert_len = insert.as_ref().map_or(0, |v| v.len());
+    let leaf_len = self.leaf_span(start, start + length);
+    let new_len = (leaf_len + insert_len).wrapping_sub(length);
+    let fits = if self.height() == 0 { 1 ..= T::MAX_LEAF } else { T::MIN_LEAF ..= T::MAX_LEAF };
+    if leaf_len > 0 && fits.contains(&new_len) {
+      // Common case: the change stays inside one leaf.
+      self.splice_leaf(start, start + length, insert_len as isize - length as isize, insert);
+    } else {
+      let insert = insert.map_or(BTreeRope::new(), BTreeRope::from_vec);
+      self.splice_rope(start, length, insert);
+    }
+  }
+
+  // Like splice, but inserts a whole tree without flattening it.
+  pub fn splice_rope(&mut self, start: usize, length: usize, insert: BTreeRope<T>) {
+    let mut rest = self.split_off(start);
+    let tail = rest.split_off(length);
+    let head = std::mem::take(self);
+    *self = BTreeRope::concat(BTreeRope::concat(head, insert), tail);
+  }
+
+  // Length of the single leaf holding start .. end, or 0 if there isn't
+  // one.
+  fn leaf_span(&self, start: usize, end: usize) -> usize {
+    match self.0.as_deref() {
+      None => 0,
+      Some(BNode::Leaf(arr)) => arr.len(),
+      Some(BNode::Branch(branch)) => {
+        let (i, child_start) = branch.find(start);
+        if end > branch.ends[i] { return 0; }
+        branch.children[i].leaf_span(start - child_start, end - child_start)
+      }
+    }
+  }
+
+  fn splice_leaf(&mut self, start: usize, end: usize, delta: isize, insert: Option<Vec<T>>) {
+    match self.node_mut() {
+      None => panic!("splice_leaf on an empty tree"),
+      Some(BNode::Branch(branch)) => {
+        let (i, child_start) = branch.find(start);
+        branch.children[i].splice_leaf(start - child_start, end - child_start, delta, insert);
+        for e in &mut branch.ends[i ..] {
+          *e = (*e as isize + delta) as usize;
+        }
+      }
+      Some(BNode::Leaf(arr)) => {
+        let right = arr.split_off(end);
+        arr.truncate(start);
+        if let Some(v) = insert {
+          arr.append(&T::Leaf::from_vec(v));
+        }
+        arr.append(&right);
+      }
+    }
+  }
+
+  ////////////////////////////////////////////////////////////////
+  // Invariants
+
+  // Checks the whole tree, unlike Rope::check_invariants.
+  pub fn check_invariants(&self) {
+    self.check_node(true);
+  }
+
+  fn check_node(&self, root: bool) {
+    match self.0.as_deref() {
+      None => {}
+      Some(BNode::Leaf(arr)) if arr.len() > T::MAX_LEAF || (!root && arr.len() < T::MIN_LEAF) => {
+        panic!("Bad leaf length {}", arr.len());
+      }
+      Some(BNode::Leaf(_)) => {}
+      Some(BNode::Branch(branch)) => {
+        let n = branch.children.len();
+        if n > MAX_CHILDREN || n < if root { 2 } else { MIN_CHILDREN } {
+          panic!("Bad branch with {} children", n);
+        }
+        let mut end = 0;
+        for (child, e) in branch.children.iter().zip(&branch.ends) {
+          end += child.len();
+          if *e != end || child.is_empty() {
+            panic!("Bad end {} for child ending at {}", e, end);
+          }
+          if child.height() + 1 != branch.height {
+            panic!("Bad height {} under branch of height {}", child.height(), branch.height);
+          }
+          child.check_node(false);
+        }
+      }
+    }
+  }
+}
+
+
+////////////////////////////////////////////////////////////////
+// Cursor/Iterator
+
+// Same interface as RopeCursor.  The current leaf is cached, and other
+// positions are found by searching down from the root, which is only
+// a few levels deep.
+pub struct BTreeCursor<'a, T: Element> {
+  root: &'a BTreeRope<T>,
+  start: usize,
+  index: usize,
+  leaf: Option<&'a T::Leaf>,
+  // For Leaf::at_hint.
+  hint: usize,
+}
+
+impl<'a, T: Element> BTreeCursor<'a, T> {
+  #[inline]
+  fn new(root: &'a BTreeRope<T>) -> Self {
+    BTreeCursor{root, start: 0, index: 0, leaf: None, hint: 0}
+  }
+
+  #[inline]
+  pub fn root(&self) -> &'a BTreeRope<T> {
+    self.root
+  }
+
+  #[inline]
+  pub fn full_len(&self) -> usize {
+    self.root.len()
+  }
+
+  #[inline]
+  pub fn at_end(&self) -> bool {
+    self.index >= self.full_len()
+  }
+
+  #[inline]
+  pub fn pos(&self) -> usize {
+    self.index
+  }
+
+  #[inline]
+  pub fn seek(&mut self, pos: usize) {
+    self.index = pos;
+  }
+
+  #[inline]
+  pub fn skip(&mut self, delta: isize) {
+    self.index = (self.index as isize + delta) as usize;
+  }
+
+  #[inline]
+  pub fn peek(&mut self) -> Option<T> {
+    self.try_at(self.index)
+  }
+
+  #[inline]
+  pub fn at(&mut self, pos: usize) -> T {
+    self.seek_internal(pos);
+    self.leaf.unwrap().at_hint(pos - self.start, &mut self.hint)
+  }
+
+  #[inline]
+  pub fn try_at(&mut self, pos: usize) -> Option<T> {
+    if pos < self.full_len() { Some(self.at(pos)) } else { None }
+  }
+
+  #[inline]
+  pub fn leaf_at(&mut self, pos: usize) -> (usize, Chunk<'a, T>) {
+    self.seek_internal(pos);
+    let leaf = self.leaf.unwrap();
+    (self.start, Chunk{leaf, start: 0, end: leaf.len()})
+  }
+
+  #[inline]
+  pub fn chunk_at(&mut self, pos: usize) -> Option<Chunk<'a, T>> {
+    if pos >= self.full_len() { return None; }
+    let (start, leaf) = self.leaf_at(pos);
+    Some(leaf.slice(pos - start .. leaf.len()))
+  }
+
+  pub fn chunks(&self, range: Range<usize>) -> BTreeChunks<'a, T> {
+    BTreeChunks{cursor: BTreeCursor::new(self.root), pos: range.start, end: range.end}
+  }
+
+  pub fn copy_range(&mut self, range: Range<usize>, out: &mut Vec<T>) {
+    out.reserve(range.len());
+    let mut pos = range.start;
+    while pos < range.end {
+      let chunk = self.chunk_at(pos).expect("Out of bounds");
+      let n = cmp::min(chunk.len(), range.end - pos);
+      chunk.slice(0 .. n).copy_to(out);
+      pos += n;
+    }
+  }
+
+  #[inline]
+  fn seek_internal(&mut self, pos: usize) {
+    if let Some(arr) = self.leaf {
+      if pos >= self.start && pos < self.start + arr.len() { return; }
+    }
+    let mut node = self.root;
+    let mut start = 0;
+    loop {
+      match node.0.as_deref() {
+        None => panic!("Out of bounds?"),
+        Some(BNode::Branch(branch)) => {
+          let (i, child_start) = branch.find(pos - start);
+          start += child_start;
+          node = &branch.children[i];
+        }
+        Some(BNode::Leaf(arr)) => {
+          assert!(pos - start < arr.len(), "Out of bounds?");
+          self.start = start;
+          self.leaf = Some(arr);
+          return;
+        }
+      }
+    }
+  }
+}
+
+pub struct BTreeChunks<'a, T: Element> {
+  cursor: BTreeCursor<'a, T>,
+  pos: usize,
+  end: usize,
+}
+
+impl<'a, T: Element> Iterator for BTreeChunks<'a, T> {
+  type Item = Chunk<'a, T>;
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.pos >= self.end { return None; }
+    let chunk = self.cursor.chunk_at(self.pos)?;
+    let n = cmp::min(chunk.len(), self.end - self.pos);
+    self.pos += n;
+    Some(chunk.slice(0 .. n))
+  }
+}
+
+impl<'a, T: Element> Iterator for BTreeCursor<'a, T> {
+  type Item = T;
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.index >= self.root.len() {
+      None
+    } else {
+      let result = self.at(self.index);
+      self.index += 1;
+      Some(result)
+    }
+  }
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    let rest = self.root.len() - self.index;
+    (rest, Some(rest))
+  }
+}
+
+
+#[cfg(test)]
+mod btree_tests {
+  use super::*;
+  use itertools::assert_equal;
+  use quickcheck_macros::quickcheck;
+
+  // Small leaves, so that small tests still build tall trees.
+  #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+  struct Small(u32);
+  impl Element for Small {
+    type Leaf = Vec<Small>;
+    const MIN_LEAF: usize = 2;
+    const MAX_LEAF: usize = 5;
+  }
+
+  fn small(range: Range<u32>) -> Vec<Small> {
+    range.map(Small).collect()
+  }
+
+  // Applies (start, length, insert length) splices scaled to fit,
+  // checking the tree after each one and that older copies are intact.
+  fn build(ops: &[(u16, u16, Option<u8>)]) -> (Vec<Small>, BTreeRope<Small>) {
+    let mut v = vec![];
+    let mut r = BTreeRope::new();
+    let mut next = 0;
+    let mut snapshots = vec![];
+    for (start, len, insert) in ops {
+      snapshots.push((v.clone(), r.clone()));
+      let start = *start as usize % (v.len() + 1);
+      let len = *len as usize % (v.len() - start + 1);
+      let insert = insert.map(|n| {
+        next += n as u32;
+        small(next - n as u32 .. next)
+      });
+      v.splice(start .. start + len, insert.clone().unwrap_or_default());
+      r.splice(start, len, insert);
+      r.check_invariants();
+      assert_eq!(r.len(), v.len());
+    }
+    for (v, r) in snapshots {
+      assert_equal(r.iter(), v.iter().copied());
+    }
+    (v, r)
+  }
+
+  #[quickcheck]
+  fn splice_parity(ops: Vec<(u16, u16, Option<u8>)>) {
+    let (v, r) = build(&ops);
+    assert_equal(r.iter(), v.iter().copied());
+  }
+
+  #[quickcheck]
+  fn from_iter_parity(n: u16) {
+    let v = small(0 .. n as u32);
+    let r = v.iter().copied().collect::<BTreeRope<_>>();
+    r.check_invariants();
+    assert_equal(r.iter(), v.iter().copied());
+    assert_equal(r.chunks().flat_map(|c| c.iter()), v.iter().copied());
+  }
+
+  #[quickcheck]
+  fn split_off_concat_parity(n: u16, at: u16, m: u16) {
+    let mut v = small(0 .. n as u32);
+    let mut r = BTreeRope::from_vec(v.clone());
+    let at = at as usize % (v.len() + 1);
+    let rest = r.split_off(at);
+    let vrest = v.split_off(at);
+    r.check_invariants();
+    rest.check_invariants();
+    assert_equal(r.iter(), v.iter().copied());
+    assert_equal(rest.iter(), vrest.iter().copied());
+    // Join onto something of a different height.
+    let w = small(1000 .. 1000 + m as u32);
+    let joined = BTreeRope::concat(rest, BTreeRope::from_vec(w.clone()));
+    joined.check_invariants();
+    assert_equal(joined.iter(), vrest.iter().chain(&w).copied());
+    let joined = BTreeRope::concat(BTreeRope::from_vec(w.clone()), r);
+    joined.check_invariants();
+    assert_equal(joined.iter(), w.iter().chain(&v).copied());
+  }
+
+  #[quickcheck]
+  fn splice_rope_parity(ops: Vec<(u16, u16, Option<u8>)>, start: u16, end: u16, src: u16) {
+    let (mut v, mut r) = build(&ops);
+    let (mut start, mut end) = (start as usize % (v.len() + 1), end as usize % (v.len() + 1));
+    if start > end { std::mem::swap(&mut start, &mut end); }
+    // Insert a suffix of the tree itself, as template expansion does.
+    let src = src as usize % (v.len() + 1);
+    let insert = r.slice(src .. v.len());
+    let ins = v[src ..].to_vec();
+    v.splice(start .. end, ins);
+    r.splice_rope(start, end - start, insert);
+    r.check_invariants();
+    assert_equal(r.iter(), v.iter().copied());
+  }
+
+  #[quickcheck]
+  fn cursor_parity(n: u16, positions: Vec<u16>) {
+    let v = small(0 .. n as u32);
+    let r = BTreeRope::from_vec(v.clone());
+    let mut cursor = r.cursor();
+    for p in positions {
+      let p = p as usize % (v.len() + 1);
+      assert_eq!(cursor.try_at(p), v.get(p).copied());
+    }
+    let mut out = vec![];
+    cursor.copy_range(v.len() / 3 .. v.len() / 2, &mut out);
+    assert_eq!(out, &v[v.len() / 3 .. v.len() / 2]);
+  }
+
+  #[test]
+  fn wide_and_shallow() {
+    let r = (0 .. 1 << 20).collect::<BTreeRope<u32>>();
+    r.check_invariants();
+    // 256 leaves fit under two levels of branches.
+    assert_eq!(r.height(), 2);
+    let mut cursor = r.cursor();
+    assert_eq!(cursor.at(123456), 123456);
+  }
+}
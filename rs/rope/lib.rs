@@ -1,19 +1,30 @@
 use std::cmp;
-
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+mod btree;
+mod stats;
+pub use btree::{BTreeChunks, BTreeCursor, BTreeRope};
+pub use stats::RopeStats;
+
+// Nodes are shared, so cloning a Rope is O(1).  Mutations go through
+// Rope::node_mut, which copies any shared node on the path down (but
+// not its children), so older clones are unaffected.
 #[derive(Clone, Debug)]
-pub struct Rope<T: Copy>(Option<Box<Node<T>>>);
+pub struct Rope<T: Element>(Option<Arc<Node<T>>>);
 
-// TODO: Consider a custom Debug impl for Rope?
-// See https://gist.github.com/shicks/22265fbb1dc1c8c38d5424c3dcd0a7f2
+// The derived Debug shows every element, which is rarely useful for
+// big ropes: see Rope::stats and Rope::write_dot for the shape instead.
 
 #[derive(Clone, Debug, PartialEq, Eq)]
-enum Node<T: Copy> {
+enum Node<T: Element> {
   App(App<T>),
-  Leaf(Vec<T>),
+  Leaf(T::Leaf),
 }
 
 #[derive(Clone, Debug)]
-struct App<T: Copy> {
+struct App<T: Element> {
   left: Rope<T>,
   right: Rope<T>,
   length: usize,
@@ -22,44 +33,154 @@ struct App<T: Copy> {
 
 // NOTE: Not using the derived equals because we want to make a custom
 // structure-agnostic equality for Rope<T>.
-impl<T: Copy + PartialEq> PartialEq<App<T>> for App<T> {
+impl<T: Element + PartialEq> PartialEq<App<T>> for App<T> {
   fn eq(&self, other: &App<T>) -> bool {
     self.length == other.length
       && self.left.0 == other.left.0
       && self.right.0 == other.right.0
   }
 }
-impl<T: Copy + Eq> Eq for App<T> {}
+impl<T: Element + Eq> Eq for App<T> {}
+
+////////////////////////////////////////////////////////////////
+// Leaf storage
+
+// Element types choose how their leaves are stored.  Most just use a
+// Vec, but small ones (such as DNA bases) can pack several per byte.
+// They also set the leaf size policy: joins and splices merge leaves
+// shorter than MIN_LEAF into a neighbour where the result fits in
+// MAX_LEAF, and don't make leaves longer than MAX_LEAF.
+pub trait Element: Copy {
+  type Leaf: Leaf<Self>;
+  const MIN_LEAF: usize = 64;
+  const MAX_LEAF: usize = 4096;
+}
+
+pub trait Leaf<T>: Clone + fmt::Debug + Eq {
+  fn from_vec(vec: Vec<T>) -> Self;
+  fn from_slice(slice: &[T]) -> Self;
+  fn len(&self) -> usize;
+  fn at(&self, index: usize) -> T;
+  // Like at, for leaves that have to search for an index: `hint` is
+  // kept between calls, so reading forwards needn't search each time.
+  #[inline]
+  fn at_hint(&self, index: usize, _hint: &mut usize) -> T {
+    self.at(index)
+  }
+  // The end of the stretch from `index` in which each element follows
+  // from the last in the same way, for leaves that store such runs.
+  #[inline]
+  fn run_end(&self, index: usize) -> usize {
+    index + 1
+  }
+  // Same as the Vec methods.
+  fn split_off(&mut self, at: usize) -> Self;
+  fn truncate(&mut self, len: usize);
+  fn copy_range(&self, range: Range<usize>, out: &mut Vec<T>);
+
+  fn append(&mut self, other: &Self) {
+    let mut v = Vec::with_capacity(self.len() + other.len());
+    self.copy_range(0 .. self.len(), &mut v);
+    other.copy_range(0 .. other.len(), &mut v);
+    *self = Self::from_vec(v);
+  }
+
+  // Bytes of heap storage, for statistics.  The default guesses from
+  // the length.
+  fn heap_bytes(&self) -> usize {
+    self.len() * std::mem::size_of::<T>()
+  }
+
+  #[inline]
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  // The elements as a slice, if they're stored unpacked.
+  #[inline]
+  fn as_slice(&self) -> Option<&[T]> {
+    None
+  }
+
+  // The elements' 2-bit codes packed four to a byte, first in the low
+  // bits, if they're stored that way.
+  #[inline]
+  fn packed(&self) -> Option<&[u8]> {
+    None
+  }
+}
+
+impl<T: Copy + fmt::Debug + Eq> Leaf<T> for Vec<T> {
+  fn from_vec(vec: Vec<T>) -> Self { vec }
+  fn from_slice(slice: &[T]) -> Self { Vec::from(slice) }
+  #[inline]
+  fn len(&self) -> usize { Vec::len(self) }
+  #[inline]
+  fn at(&self, index: usize) -> T { self[index] }
+  fn split_off(&mut self, at: usize) -> Self { Vec::split_off(self, at) }
+  fn truncate(&mut self, len: usize) { Vec::truncate(self, len) }
+  fn copy_range(&self, range: Range<usize>, out: &mut Vec<T>) {
+    out.extend_from_slice(&self[range]);
+  }
+  fn append(&mut self, other: &Self) { self.extend_from_slice(other) }
+  fn heap_bytes(&self) -> usize { self.capacity() * std::mem::size_of::<T>() }
+  #[inline]
+  fn as_slice(&self) -> Option<&[T]> { Some(self) }
+}
+
+macro_rules! vec_elements {
+  ( $( $t:ty ),* ) => {
+    $( impl Element for $t { type Leaf = Vec<$t>; } )*
+  }
+}
+vec_elements!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, char, bool);
 
 ////////////////////////////////////////////////////////////////
 // Rope Methods
 
-impl<T: Copy> FromIterator<T> for Rope<T> {
+impl<T: Element> FromIterator<T> for Rope<T> {
   fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
-    Rope::from_vec(Vec::from_iter(iter))
+    let mut builder = RopeBuilder::new();
+    builder.extend(iter);
+    builder.finish()
   }
 }
 
-impl<T: Copy> Rope<T> {
+impl<T: Element> Default for Rope<T> {
+  fn default() -> Self { Rope::new() }
+}
+
+impl<T: Element> Rope<T> {
 
   ////////////////////////////////////////////////////////////////
   // Constructors
 
   pub fn new() -> Self { Rope(None) }
 
+  // Anything longer than MAX_LEAF is split into a balanced tree.
   pub fn from_slice(slice: &[T]) -> Self {
-    Rope(if slice.len() == 0 {
+    if slice.len() > T::MAX_LEAF {
+      return slice.iter().copied().collect();
+    }
+    Rope(if slice.is_empty() {
       None
     } else {
-      Some(Box::new(Node::Leaf(Vec::from(slice))))
+      Some(Arc::new(Node::Leaf(T::Leaf::from_slice(slice))))
     })
   }
 
   pub fn from_vec(vec: Vec<T>) -> Self {
-    Rope(if vec.len() == 0 {
+    if vec.len() > T::MAX_LEAF {
+      return Rope::from_slice(&vec);
+    }
+    Rope::leaf(vec)
+  }
+
+  fn leaf(vec: Vec<T>) -> Self {
+    Rope(if vec.is_empty() {
       None
     } else {
-      Some(Box::new(Node::Leaf(vec)))
+      Some(Arc::new(Node::Leaf(T::Leaf::from_vec(vec))))
     })
   }
 
@@ -75,14 +196,30 @@ impl<T: Copy> Rope<T> {
     }
   }
 
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
   #[inline]
   pub fn dep(&self) -> i8 {
     match self.0.as_deref() {
-      None|Some(Node::Leaf(_)) => 0 as i8,
+      None|Some(Node::Leaf(_)) => 0,
       Some(Node::App(App{depth, ..})) => *depth,
     }
   }
 
+  // Whether the two ropes share the same root node (and therefore are
+  // known to be equal without looking at their contents).
+  #[inline]
+  pub fn ptr_eq(&self, other: &Rope<T>) -> bool {
+    match (&self.0, &other.0) {
+      (None, None) => true,
+      (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+      _ => false,
+    }
+  }
+
   #[inline]
   pub fn cursor<'a>(&'a self) -> RopeCursor<'a, T> {
     RopeCursor::new(self)
@@ -94,22 +231,61 @@ impl<T: Copy> Rope<T> {
     RopeCursor::new(self)
   }
 
+  #[inline]
+  pub fn chunks<'a>(&'a self) -> Chunks<'a, T> {
+    self.cursor().chunks(0 .. self.len())
+  }
+
   ////////////////////////////////////////////////////////////////
   // Joiners
 
-  pub fn append_rope(&mut self, mut right: Rope<T>) {
-    // Assume both ropes are balanced
-    if right.len() == 0 { return; }
-    if self.len() == 0 {
-      std::mem::swap(self, &mut right);
-      return;
+  pub fn append_rope(&mut self, right: Rope<T>) {
+    let left = std::mem::take(self);
+    *self = Rope::concat(left, right);
+  }
+
+  // Joins two ropes, merging the leaves either side of the join if one
+  // of them is too small.
+  pub fn concat(mut left: Rope<T>, mut right: Rope<T>) -> Rope<T> {
+    if right.is_empty() { return left; }
+    if left.is_empty() { return right; }
+    let (l, r) = (left.last_leaf_len(), right.first_leaf_len());
+    if (l < T::MIN_LEAF || r < T::MIN_LEAF) && l + r <= T::MAX_LEAF {
+      let mut leaf = left.split_off(left.len() - l);
+      let rest = right.split_off(r);
+      if let (Some(Node::Leaf(a)), Some(Node::Leaf(b))) = (leaf.node_mut(), right.0.as_deref()) {
+        a.append(b);
+      } else {
+        unreachable!("edge leaves should split off as leaves");
+      }
+      right = Rope::join(leaf, rest);
+    }
+    Rope::join(left, right)
+  }
+
+  // Joins two balanced ropes of any heights in O(|dep(left) - dep(right)|)
+  // by walking down the taller one's inner spine to a subtree of about
+  // the same height as the shorter one, joining there, and rebalancing
+  // on the way back up.
+  fn join(mut left: Rope<T>, mut right: Rope<T>) -> Rope<T> {
+    if right.is_empty() { return left; }
+    if left.is_empty() { return right; }
+    let (ld, rd) = (left.dep(), right.dep());
+    if ld > rd + 1 {
+      let (ll, lr) = left.take_children();
+      left.set_children(ll, Rope::join(lr, right));
+      left.rebalance();
+      left
+    } else if rd > ld + 1 {
+      let (rl, rr) = right.take_children();
+      right.set_children(Rope::join(left, rl), rr);
+      right.rebalance();
+      right
+    } else {
+      let length = left.len() + right.len();
+      let depth = cmp::max(ld, rd) + 1;
+      Rope(Some(Arc::new(Node::App(App{left, right, length, depth}))))
     }
-    let length = self.len() + right.len();
-    let depth = cmp::max(self.dep(), right.dep()) + 1; // unneeded?
-    let left = self.0.take();
-    let out = Some(Box::new(Node::App(App{left: Rope(left), right, length, depth})));
-    self.0 = out;
-    self.rebalance();
   }
 
   pub fn append_slice(&mut self, right: &[T]) {
@@ -122,6 +298,42 @@ impl<T: Copy> Rope<T> {
     self.append_rope(other);
   }
 
+  ////////////////////////////////////////////////////////////////
+  // Splitters
+
+  // Truncates this rope to `at` and returns the rest, in O(log n).
+  // Subtrees that aren't cut are shared rather than copied.
+  pub fn split_off(&mut self, at: usize) -> Rope<T> {
+    if at == 0 { return std::mem::take(self); }
+    if at >= self.len() { return Rope::new(); }
+    match self.node_mut() {
+      None => Rope::new(),
+      Some(Node::Leaf(arr)) => Rope(Some(Arc::new(Node::Leaf(arr.split_off(at))))),
+      Some(Node::App(_)) => {
+        let (mut left, mut right) = self.take_children();
+        let left_len = left.len();
+        let rest = if at < left_len {
+          let mid = left.split_off(at);
+          Rope::join(mid, right)
+        } else {
+          let rest = right.split_off(at - left_len);
+          left = Rope::join(left, right);
+          rest
+        };
+        *self = left;
+        rest
+      }
+    }
+  }
+
+  // Returns the given range as a new rope, sharing structure with this one.
+  pub fn slice(&self, range: Range<usize>) -> Rope<T> {
+    let mut out = self.clone();
+    let mut rest = out.split_off(range.start);
+    rest.split_off(range.end - range.start);
+    rest
+  }
+
   ////////////////////////////////////////////////////////////////
   // Splice
 
@@ -133,11 +345,21 @@ impl<T: Copy> Rope<T> {
                          insert);
   }
 
+  // Like splice, but inserts a whole rope (typically slices of this
+  // one) without flattening it.
+  pub fn splice_rope(&mut self, start: usize, length: usize, insert: Rope<T>) {
+    let mut rest = self.split_off(start);
+    let tail = rest.split_off(length);
+    let head = std::mem::take(self);
+    *self = Rope::concat(Rope::concat(head, insert), tail);
+  }
+
   fn splice_internal(&mut self, start: usize, end: usize,
                      delta: isize, insert: Option<Vec<T>>) {
-    match self.0.as_deref_mut() {
+    let mut rejoin = false;
+    match self.node_mut() {
       None => {
-        self.0 = insert.map(|v| Box::new(Node::Leaf(v)));
+        *self = insert.map_or(Rope::new(), Rope::from_vec);
       }
       Some(Node::App(App{ref mut left, ref mut right,
                          length: ref mut app_len,
@@ -150,70 +372,103 @@ impl<T: Copy> Rope<T> {
           // Only need to touch left child
           left.splice_internal(start, end, delta, insert);
         } else {
-          // Need to remove parts of both
+          // Need to remove parts of both, which may leave small leaves
+          // either side of the gap.
           let left_delta = start as isize - left_len as isize;
           left.splice_internal(start, left_len, left_delta, None);
           right.splice_internal(0, end - left_len, delta - left_delta, insert);
+          rejoin = true;
+        }
+        // Don't leave an empty child behind either.  This has to happen
+        // before rebalancing, which could bury it.
+        rejoin |= left.is_empty() || right.is_empty();
+        if !rejoin {
+          *app_len = (*app_len as isize + delta) as usize;
+          *dep = cmp::max(left.dep(), right.dep()) + 1;
+          self.rebalance();
         }
-        *app_len = (*app_len as isize + delta) as usize;
-        *dep = cmp::max(left.dep(), right.dep()) + 1;
-        self.rebalance();
       }
       Some(Node::Leaf(ref mut arr)) => {
         let arr_len = arr.len();
         let new_len = ((arr_len as isize) + delta) as usize;
-        let mut left: Rope<T> = Rope(None);
-        let mut right: Rope<T> = Rope(None);
-        let mut middle: Rope<T> = Rope(None);
-//   let insert_len = insert.as_ref().map(|x| x.len());
-        if end < arr_len {
-          right = Rope(Some(Box::new(Node::Leaf(arr.split_off(end)))));
-          // assert_eq!(arr.len(), end);
-          // assert_eq!(right.len(), arr_len - end);
-        }
-        if start > 0 {
+        if new_len == 0 {
+          self.0 = None;
+        } else if insert.is_none() && end == arr_len {
           arr.truncate(start);
-          left = Rope(self.0.take());
-        }
-        if let Some(v) = insert {
-          middle = Rope(Some(Box::new(Node::Leaf(v))));
-        }
-        if right.len() == 0 {
-          right = middle;
-        } else if left.len() == 0 {
-          left = middle;
-        } else if middle.len() > 0 {
-          let left_len = left.len();
-          let right_len = right.len();
-// eprintln!("3-way join: {}, {}, {}", left_len, middle.len(), right_len);
-          if left_len < right_len {
-            left = Rope(Some(Box::new(Node::App(App{
-              length: left_len + middle.len(), depth: 1,
-              left, right: middle}))));
-          } else {
-            right = Rope(Some(Box::new(Node::App(App{
-              length: right_len + middle.len(), depth: 1,
-              left: middle, right}))));
+        } else if insert.is_none() && start == 0 {
+          *arr = arr.split_off(end);
+        } else if new_len <= T::MAX_LEAF {
+          // Small enough to stay as one leaf.
+          let right = arr.split_off(end);
+          arr.truncate(start);
+          if let Some(v) = insert {
+            arr.append(&T::Leaf::from_vec(v));
           }
-        }
-        // At this point, middle is empty (and probably moved)
-        // All that's left to do is combine left and right, if
-        // both are present.
-        let left_len = left.len();
-        if left_len == 0 || right.len() == 0 {
-          self.0 = if left_len > 0 { left.0 } else { right.0 };
+          arr.append(&right);
         } else {
-          let depth = cmp::max(left.dep(), right.dep()) + 1;
-          // if new_len != left.len() + right.len() {
-          //   eprintln!("arr_len = {}, delta = {}, new_len = {}, insert = {:?}, start = {}, end = {}",
-          //            arr_len, delta, new_len, insert_len, start, end);
-          // }
-          // assert_eq!(new_len, left.len() + right.len());
-          self.0 = Some(Box::new(Node::App(App{
-              left, right, length: new_len, depth})));
+          let right = if end < arr_len {
+            Rope(Some(Arc::new(Node::Leaf(arr.split_off(end)))))
+          } else {
+            Rope::new()
+          };
+          let left = if start > 0 {
+            arr.truncate(start);
+            Rope(self.0.take())
+          } else {
+            Rope::new()
+          };
+          let middle = insert.map_or(Rope::new(), Rope::from_vec);
+          *self = Rope::concat(Rope::concat(left, middle), right);
         }
       }
     }
+    if rejoin {
+      let (left, right) = self.take_children();
+      *self = Rope::concat(left, right);
+    }
+  }
+
+  // Rebuilds the rope so that its leaves follow the size policy, merging
+  // runs of small leaves and splitting long ones.  Leaves that are
+  // already a good size are kept, and stay shared with other ropes.
+  pub fn compact(&mut self) {
+    let mut builder = RopeBuilder::new();
+    self.for_each_leaf(&mut |leaf| builder.push_leaf(leaf));
+    *self = builder.finish();
+    // Only the last leaf can still be short.
+    let tail = self.split_off(self.len() - self.last_leaf_len());
+    self.append_rope(tail);
+  }
+
+  fn for_each_leaf<F: FnMut(&Rope<T>)>(&self, f: &mut F) {
+    match self.0.as_deref() {
+      None => {}
+      Some(Node::Leaf(_)) => f(self),
+      Some(Node::App(App{left, right, ..})) => {
+        left.for_each_leaf(f);
+        right.for_each_leaf(f);
+      }
+    }
+  }
+
+  fn first_leaf_len(&self) -> usize {
+    match self.0.as_deref() {
+      None => 0,
+      Some(Node::Leaf(arr)) => arr.len(),
+      Some(Node::App(App{left, right, ..})) => {
+        if left.is_empty() { right.first_leaf_len() } else { left.first_leaf_len() }
+      }
+    }
+  }
+
+  fn last_leaf_len(&self) -> usize {
+    match self.0.as_deref() {
+      None => 0,
+      Some(Node::Leaf(arr)) => arr.len(),
+      Some(Node::App(App{left, right, ..})) => {
+        if right.is_empty() { left.last_leaf_len() } else { right.last_leaf_len() }
+      }
+    }
   }
 
   ////////////////////////////////////////////////////////////////
@@ -231,7 +486,9 @@ impl<T: Copy> Rope<T> {
     match self.0.as_deref() {
       None|Some(Node::Leaf(_)) => {},
       Some(Node::App(App{left, right, length, depth})) => {
-        if *length != left.len() + right.len() {
+        if left.is_empty() || right.is_empty() {
+          panic!("Empty child of length {} app", length);
+        } else if *length != left.len() + right.len() {
           panic!("Bad length {} from left {} and right {}",
                  length, left.len(), right.len());
         } else if *depth != cmp::max(left.dep(), right.dep()) + 1 {
@@ -293,9 +550,15 @@ impl<T: Copy> Rope<T> {
     }
   }
 
+  // Path copying: clones the node only if some other rope shares it.
+  #[inline]
+  fn node_mut(&mut self) -> Option<&mut Node<T>> {
+    self.0.as_mut().map(Arc::make_mut)
+  }
+
   #[inline]
   fn set_children(&mut self, left: Self, right: Self) {
-    if let Some(Node::App(a)) = self.0.as_deref_mut() {
+    if let Some(Node::App(a)) = self.node_mut() {
       a.length = left.len() + right.len();
       a.depth = cmp::max(left.dep(), right.dep()) + 1;
       a.left = left;
@@ -307,7 +570,7 @@ impl<T: Copy> Rope<T> {
 
   #[inline]
   fn take_children(&mut self) -> (Self, Self) {
-    if let Some(Node::App(App{left, right, ..})) = self.0.as_deref_mut() {
+    if let Some(Node::App(App{left, right, ..})) = self.node_mut() {
       (Rope(left.0.take()), Rope(right.0.take()))
     } else {
       panic!("take_children on a leaf")
@@ -316,6 +579,99 @@ impl<T: Copy> Rope<T> {
 }
 
 
+////////////////////////////////////////////////////////////////
+// Builder
+
+// Builds a balanced rope from a stream of elements, cutting it into
+// leaves of a fixed length (MAX_LEAF by default) as it goes rather than
+// collecting one big leaf.  Finished leaves are merged like a binary
+// counter, so the stack holds ropes of strictly decreasing depth.
+pub struct RopeBuilder<T: Element> {
+  leaf_len: usize,
+  buf: Vec<T>,
+  stack: Vec<Rope<T>>,
+}
+
+impl<T: Element> RopeBuilder<T> {
+  pub fn new() -> Self {
+    RopeBuilder::with_leaf_len(T::MAX_LEAF)
+  }
+
+  pub fn with_leaf_len(leaf_len: usize) -> Self {
+    assert!(leaf_len > 0, "leaf length must be positive");
+    RopeBuilder{leaf_len, buf: Vec::with_capacity(leaf_len), stack: vec![]}
+  }
+
+  // Number of elements pushed so far.
+  pub fn len(&self) -> usize {
+    self.stack.iter().map(|r| r.len()).sum::<usize>() + self.buf.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  #[inline]
+  pub fn push(&mut self, elem: T) {
+    self.buf.push(elem);
+    if self.buf.len() == self.leaf_len {
+      self.flush();
+    }
+  }
+
+  pub fn finish(mut self) -> Rope<T> {
+    if !self.buf.is_empty() {
+      self.flush();
+    }
+    let mut rope = Rope::new();
+    while let Some(left) = self.stack.pop() {
+      rope = Rope::join(left, rope);
+    }
+    rope
+  }
+
+  // Adds a whole leaf, sharing it if it's a good size and doesn't have
+  // to be merged with what's buffered.
+  fn push_leaf(&mut self, leaf: &Rope<T>) {
+    let len = leaf.len();
+    if (T::MIN_LEAF ..= T::MAX_LEAF).contains(&len)
+        && (self.buf.is_empty() || self.buf.len() >= T::MIN_LEAF) {
+      if !self.buf.is_empty() {
+        self.flush();
+      }
+      self.push_rope(leaf.clone());
+    } else {
+      for chunk in leaf.chunks() {
+        self.extend(chunk.iter());
+      }
+    }
+  }
+
+  fn flush(&mut self) {
+    let buf = std::mem::replace(&mut self.buf, Vec::with_capacity(self.leaf_len));
+    self.push_rope(Rope::leaf(buf));
+  }
+
+  fn push_rope(&mut self, mut rope: Rope<T>) {
+    while self.stack.last().is_some_and(|top| top.dep() == rope.dep()) {
+      rope = Rope::join(self.stack.pop().unwrap(), rope);
+    }
+    self.stack.push(rope);
+  }
+}
+
+impl<T: Element> Default for RopeBuilder<T> {
+  fn default() -> Self { RopeBuilder::new() }
+}
+
+impl<T: Element> Extend<T> for RopeBuilder<T> {
+  fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+    for elem in iter {
+      self.push(elem);
+    }
+  }
+}
+
 // fn splice_suffix<T>(arr: &mut Vec<T>, mid: usize) -> Vec<T> {
 //   unsafe {
 //     // let mut copy: Vec<T> = &mut *arr;
@@ -333,18 +689,20 @@ impl<T: Copy> Rope<T> {
 ////////////////////////////////////////////////////////////////
 // Cursor/Iterator
 
-pub struct RopeCursor<'a, T: Copy> {
+pub struct RopeCursor<'a, T: Element> {
   root: &'a Rope<T>,
   stack: Vec<&'a Rope<T>>,
   start: usize,
   index: usize,
-  leaf: Option<&'a Vec<T>>,
+  leaf: Option<&'a T::Leaf>,
+  // For Leaf::at_hint.
+  hint: usize,
 }
 
-impl<'a, T: Copy> RopeCursor<'a, T> {
+impl<'a, T: Element> RopeCursor<'a, T> {
   #[inline]
   fn new(root: &'a Rope<T>) -> Self {
-    RopeCursor{root, stack: vec![root], start: 0, index: 0, leaf: None}
+    RopeCursor{root, stack: vec![root], start: 0, index: 0, leaf: None, hint: 0}
   }
 
   #[inline]
@@ -389,12 +747,45 @@ impl<'a, T: Copy> RopeCursor<'a, T> {
   #[inline]
   pub fn at(&mut self, pos: usize) -> T {
     self.seek_internal(pos);
-    self.leaf.unwrap()[pos - self.start]
+    self.leaf.unwrap().at_hint(pos - self.start, &mut self.hint)
   }
 
   #[inline]
   pub fn try_at(&mut self, pos: usize) -> Option<T> {
-    if !self.at_end() { Some(self.at(pos)) } else { None }
+    if pos < self.full_len() { Some(self.at(pos)) } else { None }
+  }
+
+  // Returns the start and contents of the leaf containing `pos`.
+  #[inline]
+  pub fn leaf_at(&mut self, pos: usize) -> (usize, Chunk<'a, T>) {
+    self.seek_internal(pos);
+    let leaf = self.leaf.unwrap();
+    (self.start, Chunk{leaf, start: 0, end: leaf.len()})
+  }
+
+  // Returns the contiguous run of elements from `pos` to the end of its
+  // leaf, or None past the end.
+  #[inline]
+  pub fn chunk_at(&mut self, pos: usize) -> Option<Chunk<'a, T>> {
+    if pos >= self.full_len() { return None; }
+    let (start, leaf) = self.leaf_at(pos);
+    Some(leaf.slice(pos - start .. leaf.len()))
+  }
+
+  // Iterates over the given range as leaf slices.
+  pub fn chunks(&self, range: Range<usize>) -> Chunks<'a, T> {
+    Chunks{cursor: RopeCursor::new(self.root), pos: range.start, end: range.end}
+  }
+
+  pub fn copy_range(&mut self, range: Range<usize>, out: &mut Vec<T>) {
+    out.reserve(range.len());
+    let mut pos = range.start;
+    while pos < range.end {
+      let chunk = self.chunk_at(pos).expect("Out of bounds");
+      let n = cmp::min(chunk.len(), range.end - pos);
+      chunk.slice(0 .. n).copy_to(out);
+      pos += n;
+    }
   }
 
   fn seek_internal(&mut self, mut pos: usize) {
@@ -434,7 +825,103 @@ impl<'a, T: Copy> RopeCursor<'a, T> {
   }
 }
 
-impl<'a, T: Copy> Iterator for RopeCursor<'a, T> {
+// A run of consecutive elements within a single leaf.
+pub struct Chunk<'a, T: Element> {
+  leaf: &'a T::Leaf,
+  start: usize,
+  end: usize,
+}
+
+// Derived impls would want the leaf to be Copy too.
+impl<'a, T: Element> Clone for Chunk<'a, T> {
+  fn clone(&self) -> Self { *self }
+}
+impl<'a, T: Element> Copy for Chunk<'a, T> {}
+
+impl<'a, T: Element> Chunk<'a, T> {
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.end - self.start
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.start == self.end
+  }
+
+  #[inline]
+  pub fn at(&self, index: usize) -> T {
+    debug_assert!(index < self.len());
+    self.leaf.at(self.start + index)
+  }
+
+  // Leaf::run_end, within the chunk.
+  #[inline]
+  pub fn run_end(&self, index: usize) -> usize {
+    cmp::min(self.leaf.run_end(self.start + index), self.end) - self.start
+  }
+
+  // Leaf::at_hint, for reading through the chunk.
+  #[inline]
+  pub fn at_hint(&self, index: usize, hint: &mut usize) -> T {
+    debug_assert!(index < self.len());
+    self.leaf.at_hint(self.start + index, hint)
+  }
+
+  #[inline]
+  pub fn slice(&self, range: Range<usize>) -> Chunk<'a, T> {
+    assert!(range.start <= range.end && range.end <= self.len());
+    Chunk{leaf: self.leaf, start: self.start + range.start, end: self.start + range.end}
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = T> + 'a {
+    let leaf = self.leaf;
+    let mut hint = 0;
+    (self.start .. self.end).map(move |i| leaf.at_hint(i, &mut hint))
+  }
+
+  pub fn copy_to(&self, out: &mut Vec<T>) {
+    self.leaf.copy_range(self.start .. self.end, out);
+  }
+
+  pub fn to_vec(&self) -> Vec<T> {
+    let mut out = Vec::with_capacity(self.len());
+    self.copy_to(&mut out);
+    out
+  }
+
+  // The elements as a slice, if the leaf stores them unpacked.
+  #[inline]
+  pub fn as_slice(&self) -> Option<&'a [T]> {
+    self.leaf.as_slice().map(|s| &s[self.start .. self.end])
+  }
+
+  // The leaf's packed bytes, if it has them, and the index in them of
+  // this chunk's first element.
+  #[inline]
+  pub fn packed(&self) -> Option<(&'a [u8], usize)> {
+    self.leaf.packed().map(|bytes| (bytes, self.start))
+  }
+}
+
+pub struct Chunks<'a, T: Element> {
+  cursor: RopeCursor<'a, T>,
+  pos: usize,
+  end: usize,
+}
+
+impl<'a, T: Element> Iterator for Chunks<'a, T> {
+  type Item = Chunk<'a, T>;
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.pos >= self.end { return None; }
+    let chunk = self.cursor.chunk_at(self.pos)?;
+    let n = cmp::min(chunk.len(), self.end - self.pos);
+    self.pos += n;
+    Some(chunk.slice(0 .. n))
+  }
+}
+
+impl<'a, T: Element> Iterator for RopeCursor<'a, T> {
   type Item = T;
   fn next(&mut self) -> Option<Self::Item> {
     if self.index >= self.root.len() {
@@ -461,7 +948,7 @@ mod rope_tests {
   macro_rules! app {
     { $( $key:ident : $val:expr ),* }
       => {
-        Rope(Some(Box::new(Node::App(App{$( $key: $val ),*}))))
+        Rope(Some(Arc::new(Node::App(App{$( $key: $val ),*}))))
       }
   }
 
@@ -475,12 +962,12 @@ mod rope_tests {
   // macro_rules! leaf {
   //   [ $( $val:expr ),* ]
   //     => {
-  //       Rope(Some(Box::new(Node::Leaf(vec![$( $val ),*]))))
+  //       Rope(Some(Arc::new(Node::Leaf(vec![$( $val ),*]))))
   //     }
   // }
 
-  fn leaf<T: Copy>(data: &[T]) -> Rope<T> {
-    Rope(Some(Box::new(Node::Leaf(Vec::from(data)))))
+  fn leaf<T: Element>(data: &[T]) -> Rope<T> {
+    Rope(Some(Arc::new(Node::Leaf(T::Leaf::from_slice(data)))))
   }
 
   #[test]
@@ -505,7 +992,7 @@ mod rope_tests {
     let mut left = Rope::from_slice(s1);
     let right = Rope::from_slice(s2);
     left.append_rope(right);
-    let mut out = s1.iter().map(|x| *x).collect::<Vec<_>>();
+    let mut out = s1.to_vec();
     out.extend_from_slice(s2);
     assert_rope_eq!(left, leaf(&out));
   }
@@ -513,8 +1000,8 @@ mod rope_tests {
   #[test]
   fn append_rope() {
     // NOTE: We need large leafs to avoid the consolidation threshold
-    let s1 = &[2, 5, 4, 1, 6].iter().cycle().take(300).collect::<Vec<_>>();
-    let s2 = &[3, 7, 9, 8, 0].iter().cycle().take(300).collect::<Vec<_>>();
+    let s1 = &[2, 5, 4, 1, 6].iter().copied().cycle().take(300).collect::<Vec<_>>();
+    let s2 = &[3, 7, 9, 8, 0].iter().copied().cycle().take(300).collect::<Vec<_>>();
     let mut left = Rope::from_slice(s1);
     let right = Rope::from_slice(s2);
     left.append_rope(right);
@@ -529,15 +1016,15 @@ mod rope_tests {
     let s2 = &[3, 7, 9, 8, 0];
     let mut rope = Rope::from_slice(s1);
     rope.append_slice(s2);
-    let mut out = s1.iter().map(|x| *x).collect::<Vec<_>>();
+    let mut out = s1.to_vec();
     out.extend_from_slice(s2);
     assert_rope_eq!(rope, leaf(&out));
   }
 
   #[test]
   fn append_slice() {
-    let s1 = &[2, 5, 4, 1, 6].iter().cycle().take(300).collect::<Vec<_>>();
-    let s2 = &[3, 7, 9, 8, 0].iter().cycle().take(300).collect::<Vec<_>>();
+    let s1 = &[2, 5, 4, 1, 6].iter().copied().cycle().take(300).collect::<Vec<_>>();
+    let s2 = &[3, 7, 9, 8, 0].iter().copied().cycle().take(300).collect::<Vec<_>>();
     let mut rope = Rope::from_slice(s1);
     rope.append_slice(s2);
     assert_rope_eq!(rope,
@@ -551,15 +1038,15 @@ mod rope_tests {
     let s2 = &[3, 7, 9, 8, 0];
     let mut rope = Rope::from_slice(s1);
     rope.prepend_slice(s2);
-    let mut out = s2.iter().map(|x| *x).collect::<Vec<_>>();
+    let mut out = s2.to_vec();
     out.extend_from_slice(s1);
     assert_rope_eq!(rope, leaf(&out));
   }
 
   #[test]
   fn prepend_slice() {
-    let s1 = &[2, 5, 4, 1, 6].iter().cycle().take(300).collect::<Vec<_>>();
-    let s2 = &[3, 7, 9, 8, 0].iter().cycle().take(300).collect::<Vec<_>>();
+    let s1 = &[2, 5, 4, 1, 6].iter().copied().cycle().take(300).collect::<Vec<_>>();
+    let s2 = &[3, 7, 9, 8, 0].iter().copied().cycle().take(300).collect::<Vec<_>>();
     let mut rope = Rope::from_slice(s1);
     rope.prepend_slice(s2);
     assert_rope_eq!(rope,
@@ -596,7 +1083,42 @@ mod rope_tests {
   fn iterator_parity(xs: Vec<u32>) {
     let rope = xs.iter().cloned().collect::<Rope<_>>();
     rope.check_invariants();
-    assert_equal(rope.iter(), xs.iter().map(|x| *x))
+    assert_equal(rope.iter(), xs.iter().copied())
+  }
+
+  #[quickcheck]
+  fn chunks_parity(ops: Vec<SpliceOp>, start: f32, end: f32) {
+    let (v, r) = build(&ops, &mut 0);
+    let mut start = f32::round(start.abs().fract() * v.len() as f32) as usize;
+    let mut end = f32::round(end.abs().fract() * v.len() as f32) as usize;
+    if start > end { std::mem::swap(&mut start, &mut end); }
+    let mut cursor = r.cursor();
+    assert_equal(cursor.chunks(start .. end).flat_map(|c| c.iter()),
+                 v[start .. end].iter().copied());
+    assert!(cursor.chunks(start .. end).all(|c| !c.is_empty()));
+    let mut out = vec![];
+    cursor.copy_range(start .. end, &mut out);
+    assert_eq!(out, v[start .. end]);
+    if start < v.len() {
+      let chunk = cursor.chunk_at(start).unwrap();
+      assert_eq!(chunk.to_vec(), &v[start .. start + chunk.len()]);
+    }
+    assert!(cursor.chunk_at(v.len()).is_none());
+    assert_eq!(cursor.try_at(v.len()), None);
+  }
+
+  #[test]
+  fn try_at_ignores_cursor_pos() {
+    // try_at once asked whether the cursor was at its end, rather than
+    // whether `pos` was, so it failed either way round.
+    let rope = (0 .. 100).collect::<Rope<u32>>();
+    let mut cursor = rope.cursor();
+    assert_eq!(cursor.try_at(100), None);
+    assert_eq!(cursor.try_at(99), Some(99));
+    cursor.seek(100);
+    assert!(cursor.at_end());
+    assert_eq!(cursor.try_at(0), Some(0));
+    assert_eq!(cursor.try_at(100), None);
   }
 
   #[quickcheck]
@@ -606,9 +1128,235 @@ mod rope_tests {
     let mut i: u32 = 0;
     for op in ops {
       op.apply(&mut i, &mut v, &mut r);
-      assert_equal(r.iter(), v.iter().map(|x| *x));
+      assert_equal(r.iter(), v.iter().copied());
+      r.check_invariants();
+    }
+  }
+
+  #[test]
+  fn clone_shares_structure() {
+    let s1 = &[2, 5, 4, 1, 6].iter().cycle().take(300).copied().collect::<Vec<_>>();
+    let s2 = &[3, 7, 9, 8, 0].iter().cycle().take(300).copied().collect::<Vec<_>>();
+    let mut rope = Rope::from_slice(s1);
+    rope.append_slice(s2);
+    let snapshot = rope.clone();
+    assert!(snapshot.ptr_eq(&rope));
+    rope.splice(590, 10, None);
+    assert!(!snapshot.ptr_eq(&rope));
+    // The untouched left leaf is still shared after the splice.
+    if let (Some(Node::App(a)), Some(Node::App(b)))
+        = (rope.0.as_deref(), snapshot.0.as_deref()) {
+      assert!(a.left.ptr_eq(&b.left));
+    } else {
+      panic!("expected App nodes");
+    }
+    let mut expected = s1.clone();
+    expected.extend_from_slice(s2);
+    assert_equal(snapshot.iter(), expected.iter().copied());
+    expected.truncate(590);
+    assert_equal(rope.iter(), expected.iter().copied());
+  }
+
+  #[quickcheck]
+  fn persistent_splice_parity(ops: Vec<SpliceOp>) {
+    let mut v: Vec<u32> = vec![];
+    let mut r: Rope<u32> = Rope::new();
+    let mut i: u32 = 0;
+    let mut snapshots: Vec<(Vec<u32>, Rope<u32>)> = vec![];
+    for op in ops {
+      snapshots.push((v.clone(), r.clone()));
+      op.apply(&mut i, &mut v, &mut r);
       r.check_invariants();
     }
+    for (v, r) in snapshots {
+      assert_equal(r.iter(), v.iter().copied());
+    }
+  }
+
+  fn check_all<T: Element>(rope: &Rope<T>) {
+    rope.check_invariants();
+    if let Some(Node::App(App{left, right, ..})) = rope.0.as_deref() {
+      check_all(left);
+      check_all(right);
+    }
+  }
+
+  fn build(ops: &[SpliceOp], i: &mut u32) -> (Vec<u32>, Rope<u32>) {
+    let mut v: Vec<u32> = vec![];
+    let mut r: Rope<u32> = Rope::new();
+    for op in ops {
+      op.apply(i, &mut v, &mut r);
+    }
+    (v, r)
+  }
+
+  #[quickcheck]
+  fn concat_parity(a: Vec<SpliceOp>, b: Vec<SpliceOp>) {
+    let mut i = 0;
+    let (mut va, ra) = build(&a, &mut i);
+    let (vb, rb) = build(&b, &mut i);
+    let r = Rope::concat(ra, rb);
+    check_all(&r);
+    va.extend(vb);
+    assert_equal(r.iter(), va.iter().copied());
+  }
+
+  #[test]
+  fn concat_different_heights() {
+    let mut tall: Rope<u32> = Rope::new();
+    for i in 0 .. 200 {
+      tall.append_slice(&[i]);
+    }
+    let short = Rope::from_slice(&[1000, 1001]);
+    let r = Rope::concat(tall.clone(), short.clone());
+    check_all(&r);
+    assert_eq!(r.dep(), tall.dep());
+    let r = Rope::concat(short, tall);
+    check_all(&r);
+    assert_equal(r.iter().take(3), [1000, 1001, 0]);
+  }
+
+  #[quickcheck]
+  fn split_off_parity(ops: Vec<SpliceOp>, at: f32) {
+    let (mut v, mut r) = build(&ops, &mut 0);
+    let at = f32::round(at.abs().fract() * v.len() as f32) as usize;
+    let rest = r.split_off(at);
+    check_all(&r);
+    check_all(&rest);
+    let v_rest = v.split_off(at);
+    assert_equal(r.iter(), v.iter().copied());
+    assert_equal(rest.iter(), v_rest.iter().copied());
+  }
+
+  #[quickcheck]
+  fn slice_parity(ops: Vec<SpliceOp>, start: f32, end: f32) {
+    let (v, r) = build(&ops, &mut 0);
+    let mut start = f32::round(start.abs().fract() * v.len() as f32) as usize;
+    let mut end = f32::round(end.abs().fract() * v.len() as f32) as usize;
+    if start > end { std::mem::swap(&mut start, &mut end); }
+    let s = r.slice(start .. end);
+    check_all(&s);
+    assert_equal(s.iter(), v[start .. end].iter().copied());
+    // The original is untouched.
+    assert_equal(r.iter(), v.iter().copied());
+  }
+
+  #[test]
+  fn slice_shares_leaves() {
+    let s1 = [1; 300];
+    let s2 = [2; 300];
+    let s3 = [3; 300];
+    let mut rope = Rope::from_slice(&s1);
+    rope.append_slice(&s2);
+    rope.append_slice(&s3);
+    let s = rope.slice(0 .. 600);
+    assert_equal(s.iter(), s1.iter().chain(s2.iter()).copied());
+    if let Some(Node::App(a)) = rope.0.as_deref() {
+      assert!(s.ptr_eq(&a.left));
+    } else {
+      panic!("expected App node");
+    }
+  }
+
+  #[quickcheck]
+  fn splice_rope_parity(ops: Vec<SpliceOp>, start: f32, end: f32, src: f32) {
+    let (mut v, mut r) = build(&ops, &mut 0);
+    let mut start = f32::round(start.abs().fract() * v.len() as f32) as usize;
+    let mut end = f32::round(end.abs().fract() * v.len() as f32) as usize;
+    if start > end { std::mem::swap(&mut start, &mut end); }
+    // Insert a copy of a suffix of the rope itself, as template expansion does.
+    let src = f32::round(src.abs().fract() * v.len() as f32) as usize;
+    let insert = r.slice(src .. v.len());
+    let ins = v[src ..].to_vec();
+    v.splice(start .. end, ins);
+    r.splice_rope(start, end - start, insert);
+    check_all(&r);
+    assert_equal(r.iter(), v.iter().copied());
+  }
+
+  #[quickcheck]
+  fn builder_parity(v: Vec<u8>, leaf_len: u8) {
+    let leaf_len = leaf_len as usize % 7 + 1;
+    let mut b = RopeBuilder::with_leaf_len(leaf_len);
+    b.extend(v.iter().copied());
+    assert_eq!(b.len(), v.len());
+    let r = b.finish();
+    check_all(&r);
+    assert_equal(r.iter(), v.iter().copied());
+    // Every leaf is full except perhaps the last.
+    let lens = r.chunks().map(|c| c.len()).collect::<Vec<_>>();
+    assert!(lens.iter().rev().skip(1).all(|n| *n == leaf_len));
+  }
+
+  fn leaf_lens<T: Element>(r: &Rope<T>) -> Vec<usize> {
+    let mut lens = vec![];
+    r.for_each_leaf(&mut |leaf| lens.push(leaf.len()));
+    lens
+  }
+
+  #[quickcheck]
+  fn compact_parity(ops: Vec<SpliceOp>) {
+    let (v, mut r) = build(&ops, &mut 0);
+    assert!(leaf_lens(&r).iter().all(|n| *n <= u32::MAX_LEAF));
+    let before = r.clone();
+    r.compact();
+    check_all(&r);
+    assert_equal(r.iter(), v.iter().copied());
+    // No leaf is too long, and no short leaf could have been merged.
+    let lens = leaf_lens(&r);
+    assert!(lens.iter().all(|n| *n <= u32::MAX_LEAF));
+    for w in lens.windows(2) {
+      assert!((w[0] >= u32::MIN_LEAF && w[1] >= u32::MIN_LEAF)
+              || w[0] + w[1] > u32::MAX_LEAF, "{:?}", lens);
+    }
+    // Compacting doesn't disturb the original.
+    assert_equal(before.iter(), v.iter().copied());
+  }
+
+  #[test]
+  fn small_splices_stay_consolidated() {
+    // Many one-element splices don't fragment the rope.
+    let mut r = Rope::from_vec((0 .. 10000).collect::<Vec<u32>>());
+    let mut v = (0 .. 10000).collect::<Vec<u32>>();
+    for i in 0 .. 2000 {
+      let pos = (i * 7919) % v.len();
+      r.splice(pos, 1, Some(vec![i as u32, i as u32]));
+      v.splice(pos .. pos + 1, [i as u32, i as u32]);
+    }
+    check_all(&r);
+    assert_equal(r.iter(), v.iter().copied());
+    assert!(leaf_lens(&r).len() <= v.len() / u32::MIN_LEAF, "{:?}", leaf_lens(&r));
+  }
+
+  // Tiny leaves, so that small tests still build deep trees.
+  #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+  struct Tiny(u32);
+  impl Element for Tiny {
+    type Leaf = Vec<Tiny>;
+    const MIN_LEAF: usize = 2;
+    const MAX_LEAF: usize = 8;
+  }
+  impl From<u32> for Tiny {
+    fn from(x: u32) -> Self { Tiny(x) }
+  }
+
+  #[quickcheck]
+  fn tiny_leaf_parity(ops: Vec<SpliceOp>) {
+    let mut v: Vec<Tiny> = vec![];
+    let mut r: Rope<Tiny> = Rope::new();
+    let mut i = 0;
+    for op in ops {
+      let (old_v, old_r) = (v.clone(), r.clone());
+      op.apply(&mut i, &mut v, &mut r);
+      check_all(&r);
+      assert!(leaf_lens(&r).iter().all(|n| *n <= Tiny::MAX_LEAF));
+      assert_equal(r.iter(), v.iter().copied());
+      // The old version is untouched.
+      assert_equal(old_r.iter(), old_v.iter().copied());
+    }
+    r.compact();
+    check_all(&r);
+    assert_equal(r.iter(), v.iter().copied());
   }
 
   #[derive(Clone, Debug)]
@@ -637,12 +1385,12 @@ mod rope_tests {
     }
   }
   impl SpliceOp {
-    fn apply(&self, i: &mut u32, v: &mut Vec<u32>, r: &mut Rope<u32>) {
-      let insert: Option<Vec<u32>> = self.insert.map(|len| {
+    fn apply<T: Element + From<u32>>(&self, i: &mut u32, v: &mut Vec<T>, r: &mut Rope<T>) {
+      let insert: Option<Vec<T>> = self.insert.map(|len| {
         *i += len as u32;
-        (*i-len as u32 .. *i).collect()
+        (*i-len as u32 .. *i).map(T::from).collect()
       });
-      let replace_with = insert.clone().unwrap_or_else(|| vec![]).into_iter();
+      let replace_with = insert.clone().unwrap_or_default().into_iter();
       let start = f32::round(self.start * (v.len()) as f32) as usize;
       let length = f32::round(self.len * (v.len() - start) as f32) as usize;
       v.splice(start .. start + length, replace_with);
@@ -0,0 +1,186 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+
+use crate::{App, Element, Leaf, Node, Rope};
+
+// A summary of a rope's shape, for working out why splices got slow.
+// Subtrees can appear more than once (splice_rope shares slices of the
+// rope with itself), so the counts are of nodes as seen walking the
+// tree, while the heap size counts each allocation once.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RopeStats {
+  pub len: usize,
+  pub nodes: usize,
+  pub leaves: usize,
+  // How many of the nodes were already seen elsewhere in the tree.
+  pub shared: usize,
+  // leaf_sizes[k] is the number of leaves with 2^k to 2^(k+1)-1 elements.
+  pub leaf_sizes: Vec<usize>,
+  pub depth: usize,
+  pub min_leaf_depth: usize,
+  // Total depth of all the leaves, for the mean.
+  pub leaf_depth_sum: usize,
+  // Largest difference between the depths of two siblings.
+  pub max_imbalance: usize,
+  pub heap_bytes: usize,
+}
+
+impl RopeStats {
+  pub fn mean_leaf_depth(&self) -> f64 {
+    if self.leaves == 0 { return 0.0; }
+    self.leaf_depth_sum as f64 / self.leaves as f64
+  }
+
+  fn visit<T: Element>(&mut self, rope: &Rope<T>, depth: usize,
+                       seen: &mut HashSet<*const Node<T>>) {
+    let node = match rope.0.as_deref() {
+      None => return,
+      Some(node) => node,
+    };
+    self.nodes += 1;
+    if seen.insert(node) {
+      self.heap_bytes += mem::size_of::<Node<T>>() + 2 * mem::size_of::<usize>();
+      if let Node::Leaf(arr) = node {
+        self.heap_bytes += arr.heap_bytes();
+      }
+    } else {
+      self.shared += 1;
+    }
+    match node {
+      Node::Leaf(arr) => {
+        let bucket = arr.len().max(1).ilog2() as usize;
+        if self.leaf_sizes.len() <= bucket {
+          self.leaf_sizes.resize(bucket + 1, 0);
+        }
+        self.leaf_sizes[bucket] += 1;
+        if self.leaves == 0 || depth < self.min_leaf_depth {
+          self.min_leaf_depth = depth;
+        }
+        self.leaves += 1;
+        self.leaf_depth_sum += depth;
+        self.depth = self.depth.max(depth);
+      }
+      Node::App(App{left, right, ..}) => {
+        let imbalance = left.dep().abs_diff(right.dep()) as usize;
+        self.max_imbalance = self.max_imbalance.max(imbalance);
+        self.visit(left, depth + 1, seen);
+        self.visit(right, depth + 1, seen);
+      }
+    }
+  }
+}
+
+impl fmt::Display for RopeStats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "{} elements in {} leaves, {} nodes ({} shared), {} heap bytes",
+             self.len, self.leaves, self.nodes, self.shared, self.heap_bytes)?;
+    writeln!(f, "depth {}, leaves at {}..{} (mean {:.1}, ideal {:.1}), max imbalance {}",
+             self.depth, self.min_leaf_depth, self.depth, self.mean_leaf_depth(),
+             (self.leaves.max(1) as f64).log2(), self.max_imbalance)?;
+    let buckets = self.leaf_sizes.iter().enumerate().filter(|(_, count)| **count > 0)
+        .map(|(k, count)| format!("{}..{}: {}", 1usize << k, (2usize << k) - 1, count))
+        .collect::<Vec<_>>();
+    write!(f, "leaf lengths: {}", buckets.join(", "))
+  }
+}
+
+impl<T: Element> Rope<T> {
+  pub fn stats(&self) -> RopeStats {
+    let mut stats = RopeStats{len: self.len(), ..RopeStats::default()};
+    stats.visit(self, 0, &mut HashSet::new());
+    stats
+  }
+
+  // Writes the tree as a Graphviz digraph, labelling branches with
+  // their length and depth and leaves with their length.  A shared
+  // subtree is only drawn once, with an edge from each of its parents.
+  pub fn write_dot<W: Write>(&self, out: &mut W) -> io::Result<()> {
+    writeln!(out, "digraph rope {{")?;
+    writeln!(out, "  ordering=out;")?;
+    writeln!(out, "  node [fontname=monospace];")?;
+    self.write_dot_node(out, &mut HashSet::new())?;
+    writeln!(out, "}}")
+  }
+
+  fn write_dot_node<W: Write>(&self, out: &mut W,
+                              seen: &mut HashSet<*const Node<T>>) -> io::Result<()> {
+    let node = match self.0.as_deref() {
+      None => return Ok(()),
+      Some(node) => node,
+    };
+    if !seen.insert(node) { return Ok(()); }
+    match node {
+      Node::Leaf(arr) => {
+        writeln!(out, "  n{:p} [shape=box, label=\"{}\"];", node, arr.len())?;
+      }
+      Node::App(App{left, right, length, depth}) => {
+        writeln!(out, "  n{:p} [label=\"{}\\nd{}\"];", node, length, depth)?;
+        for child in [left, right] {
+          if let Some(c) = child.0.as_deref() {
+            writeln!(out, "  n{:p} -> n{:p};", node, c)?;
+          }
+          child.write_dot_node(out, seen)?;
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+
+#[cfg(test)]
+mod stats_tests {
+  use super::*;
+
+  fn dot(r: &Rope<u32>) -> String {
+    let mut out = vec![];
+    r.write_dot(&mut out).unwrap();
+    String::from_utf8(out).unwrap()
+  }
+
+  #[test]
+  fn empty() {
+    let r: Rope<u32> = Rope::new();
+    assert_eq!(r.stats(), RopeStats::default());
+    assert_eq!(dot(&r), "digraph rope {\n  ordering=out;\n  node [fontname=monospace];\n}\n");
+  }
+
+  #[test]
+  fn counts() {
+    let r = (0 .. 10000).collect::<Rope<u32>>();
+    let stats = r.stats();
+    assert_eq!(stats.len, 10000);
+    // Two full leaves and the rest.
+    assert_eq!(stats.leaves, 3);
+    assert_eq!(stats.nodes, 5);
+    assert_eq!(stats.shared, 0);
+    assert_eq!(stats.leaf_sizes[10], 1);
+    assert_eq!(stats.leaf_sizes[12], 2);
+    assert_eq!(stats.leaf_sizes.iter().sum::<usize>(), 3);
+    assert_eq!(stats.depth, r.dep() as usize);
+    assert!(stats.heap_bytes >= 10000 * 4);
+    let text = dot(&r);
+    assert_eq!(text.matches(" -> ").count(), 4);
+    assert_eq!(text.matches("shape=box").count(), 3);
+  }
+
+  #[test]
+  fn shared_subtrees() {
+    let mut r = (0 .. 10000).collect::<Rope<u32>>();
+    let before = r.stats();
+    let copy = r.slice(0 .. 8192);
+    r.splice_rope(10000, 0, copy);
+    let after = r.stats();
+    // The copied leaves are counted again, but take no more memory
+    // than the new branches need.
+    assert_eq!(after.leaves, 5);
+    // The two full leaves and the branch holding them.
+    assert_eq!(after.shared, 3);
+    assert!(after.heap_bytes < before.heap_bytes + 1000);
+    // Each shared leaf is drawn once but has two parents.
+    let text = dot(&r);
+    assert_eq!(text.matches("shape=box").count(), 3);
+  }
+}